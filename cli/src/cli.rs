@@ -1,37 +1,436 @@
-use chrono::Utc;
-use clap::Parser;
-use libwsclone::{init_download, DownloadRule, Update};
+use crate::config::{read_url_list, Config, ConfigError, OutputFormat};
+use crate::progress::ProgressView;
+use chrono::{NaiveDate, Utc};
+use clap::{Parser, Subcommand};
+use libwsclone::{
+    AuthScheme, Credentials, Downloader, Event, FilterAction, FilterPattern, FormLogin, HostScope,
+    Normalization, SessionReport, TrailingSlash, UrlFilter, WscError,
+};
 use owo_colors::{OwoColorize, Stream};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use tokio::sync::mpsc::channel;
 use url::Url;
 
-const PROGRESS_UPDATE_INTERVAL: u64 = 1000;
 const MAX_BUFFER_SIZE: usize = 100;
+/// Name of the report file written in the output directory, unless a report file is given.
+const REPORT_FILE_NAME: &str = "wsclone-report.json";
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  every url was downloaded, cached or skipped
+  1  partial failure, some urls failed to download
+  2  invalid input, E.g bad arguments or configuration
+  3  total failure, the session couldn't complete";
+
+/// Outcome of a run, mapped to the process exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Success = 0,
+    PartialFailure = 1,
+    /// Same code clap uses for usage errors
+    InvalidInput = 2,
+    Failure = 3,
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
+
+/// Last line written in jsonl mode.
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "Summary")]
+struct Summary<'a> {
+    status: Status,
+    exit_code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report_file: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    report: Option<&'a SessionReport>,
+}
 
 #[derive(Parser, Debug)]
 #[command(
     author,
     version,
     about = "An offline browser utility",
-    long_about = "An offline browser utility for downloading website(s) for offline viewing."
+    long_about = "An offline browser utility for downloading website(s) for offline viewing.",
+    after_help = EXIT_CODES_HELP
 )]
 pub struct Cli {
-    url: Url,
-    output_directory: String,
-    #[arg(default_value = "10000000", help = "Max file size in bytes.", long)]
-    max_file_size: u64,
-    #[arg(default_value = "0", long)]
-    max_level: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(
-        help = "Abort download if any resource other than the first page encounters an error.",
+        help = "Url of the initial page. Required unless set in the config file, or given \
+        with --seed or --urls-from."
+    )]
+    url: Option<Url>,
+    #[arg(help = "Required unless set in the config file.")]
+    output_directory: Option<String>,
+    #[arg(
+        help = "Same as the positional output directory, for when the url is given with \
+        --seed or --urls-from.",
+        short = 'o',
+        long = "output-directory",
+        global = true
+    )]
+    output_directory_flag: Option<String>,
+    #[arg(
+        help = "Another page to start from, crawled in the same session. Resources shared by \
+        the pages are downloaded once and an index.html linking to each page is written. \
+        Can be repeated.",
+        long = "seed",
+        global = true
+    )]
+    seeds: Vec<Url>,
+    #[arg(
+        help = "A file listing pages to start from, one url per line, - for stdin. Same as \
+        --seed for each url.",
+        long,
+        global = true
+    )]
+    urls_from: Option<PathBuf>,
+    #[arg(
+        help = "Where to write the JSON session report. Defaults to wsclone-report.json in the \
+        output directory.",
+        long,
+        global = true
+    )]
+    report_file: Option<PathBuf>,
+    #[arg(
+        help = "Print plain lines instead of progress bars. Always the case when stdout \
+        isn't a terminal.",
         long
     )]
+    no_progress: bool,
+    #[arg(
+        help = "Output format. jsonl writes every event as a JSON object on it's own line, \
+        followed by a summary object. [default: text]",
+        long,
+        value_enum
+    )]
+    output_format: Option<OutputFormat>,
+    #[arg(
+        help = "Load options from a TOML configuration file. Flags given with it replace its \
+        values, except flags that can be repeated, E.g --header or --filter, which add to its \
+        lists.",
+        long,
+        global = true
+    )]
+    config: Option<PathBuf>,
+    #[arg(
+        help = "Name of a profile from the configuration file, merged over its top level values.",
+        long,
+        global = true,
+        requires = "config"
+    )]
+    profile: Option<String>,
+    #[arg(
+        help = "Max file size in bytes. [default: 10000000]",
+        long,
+        global = true
+    )]
+    max_file_size: Option<u64>,
+    #[arg(help = "[default: 0]", long, global = true)]
+    max_level: Option<u8>,
+    #[arg(
+        help = "Abort download if any resource other than the first page encounters an error.",
+        long,
+        global = true
+    )]
     abort_on_download_error: Option<bool>,
     #[arg(
         help = "Download files even if their size can't be determined. Defaults to true.",
-        long
+        long,
+        global = true
     )]
     download_files_with_unknown_size: Option<bool>,
+    #[arg(
+        help = "A text/url to blacklist, can be repeated. Same as the positional blacklist urls.",
+        long = "blacklist-url",
+        global = true
+    )]
+    blacklist_url_flags: Vec<String>,
+    #[arg(
+        help = "Hosts of the pages to follow, one of same-host, same-domain or any-host. \
+        [default: same-host]",
+        long,
+        global = true
+    )]
+    page_scope: Option<HostScope>,
+    #[arg(
+        help = "Hosts of the static resources to download, one of same-host, same-domain or \
+        any-host. [default: any-host]",
+        long,
+        global = true
+    )]
+    resource_scope: Option<HostScope>,
+    #[arg(
+        help = "A host whose pages are followed, in addition to the page scope. Static \
+        resources are still limited by the resource scope, see --allow-resource-host. A \
+        leading . also allows subdomains, E.g .example.org. Can be repeated.",
+        long = "allow-host",
+        global = true
+    )]
+    allowed_hosts: Vec<String>,
+    #[arg(
+        help = "A host whose static resources are downloaded, in addition to the resource \
+        scope. A leading . also allows subdomains, E.g .example.org. Can be repeated.",
+        long = "allow-resource-host",
+        global = true
+    )]
+    allowed_resource_hosts: Vec<String>,
+    #[arg(
+        help = "Only follow pages whose path starts with this prefix, E.g /docs/v2/",
+        long,
+        global = true
+    )]
+    path_prefix: Option<String>,
+    #[arg(
+        help = "An include or exclude rule, as <include|exclude>:<regex|glob|mime|ext>:<pattern>. \
+        E.g exclude:mime:video/* or include:glob:*/docs/*. Can be repeated, the first matching \
+        rule decides and urls matching none are downloaded. Checked after the filters of the \
+        config file.",
+        long = "filter",
+        global = true,
+        value_parser = parse_filter
+    )]
+    filters: Vec<UrlFilter>,
+    #[arg(
+        help = "Trailing slash handling when normalizing urls, one of keep, add or remove. \
+        [default: keep]",
+        long,
+        global = true
+    )]
+    trailing_slash: Option<TrailingSlash>,
+    #[arg(
+        help = "Treat pages declaring an already downloaded page as their canonical url as \
+        duplicates of it. Defaults to false.",
+        long,
+        global = true
+    )]
+    follow_canonical: Option<bool>,
+    #[arg(
+        help = "Remove the query parameters of analytics and ad platforms, E.g utm_source, and \
+        sort the others, so the variants of a url are downloaded once. Defaults to false.",
+        long,
+        global = true
+    )]
+    clean_queries: Option<bool>,
+    #[arg(
+        help = "Also download the pages listed in the sitemaps of the site, from robots.txt or \
+        /sitemap.xml. Defaults to false.",
+        long,
+        global = true
+    )]
+    sitemap: Option<bool>,
+    #[arg(
+        help = "A sitemap to read instead of looking for them, implies --sitemap true. \
+        Can be repeated.",
+        long = "sitemap-url",
+        global = true
+    )]
+    sitemap_urls: Vec<Url>,
+    #[arg(
+        help = "Only add sitemap pages modified on or after this date, E.g 2023-01-31.",
+        long,
+        global = true
+    )]
+    modified_since: Option<NaiveDate>,
+    #[arg(
+        help = "Stop the crawl after requesting this many pages, seeds included.",
+        long,
+        global = true
+    )]
+    max_pages: Option<u64>,
+    #[arg(
+        help = "Stop the crawl after requesting this many static resources.",
+        long,
+        global = true
+    )]
+    max_resources: Option<u64>,
+    #[arg(
+        help = "Stop the crawl once this many bytes are written to disk.",
+        long,
+        global = true
+    )]
+    max_bytes: Option<u64>,
+    #[arg(
+        help = "Stop the crawl after this many seconds. Links in what was downloaded are \
+        still rewritten.",
+        long,
+        global = true
+    )]
+    max_duration: Option<u64>,
+    #[arg(
+        help = "Write pages as UTF-8 instead of the encoding they were served in. \
+        Defaults to false.",
+        long,
+        global = true
+    )]
+    convert_to_utf8: Option<bool>,
+    #[arg(help = "User agent of the requests.", long, global = true)]
+    user_agent: Option<String>,
+    #[arg(
+        help = "A header sent with every request, as \"Name: value\". Can be repeated.",
+        long = "header",
+        global = true,
+        value_parser = parse_header
+    )]
+    headers: Vec<(String, String)>,
+    #[arg(
+        help = "A header sent only to a host, as \"host=Name: value\". A leading . also \
+        matches subdomains, E.g .example.org. Can be repeated.",
+        long = "host-header",
+        global = true,
+        value_parser = parse_host_header
+    )]
+    host_headers: Vec<(String, (String, String))>,
+    #[arg(
+        help = "File the cookies are loaded from, if it exists, and saved to after the download.",
+        long,
+        global = true
+    )]
+    cookie_jar: Option<String>,
+    #[arg(
+        help = "A Netscape cookies.txt file, E.g exported from a browser, to send the cookies \
+        of. Can be repeated.",
+        long = "import-cookies",
+        global = true
+    )]
+    import_cookies: Vec<String>,
+    #[arg(
+        help = "A netrc file with the login and password of hosts, sent with basic auth.",
+        long,
+        global = true
+    )]
+    netrc: Option<String>,
+    #[arg(
+        help = "Credentials of a host, read from environment variables, as \
+        \"host=basic:USERNAME_VAR:PASSWORD_VAR\" or \"host=bearer:TOKEN_VAR\". A leading . \
+        also matches subdomains. The host can be given with a scheme and port, E.g \
+        https://host:8443, they default to the ones of the seed on the host. Credentials are \
+        never sent to another scheme, host or port, even when redirected. Can be repeated.",
+        long = "auth",
+        global = true,
+        value_parser = parse_credentials
+    )]
+    credentials: Vec<Credentials>,
+    #[arg(
+        help = "Proxy of every request, E.g http://proxy:3128 or socks5h://proxy:1080. The \
+        proxy environment variables are ignored when a proxy is set.",
+        long = "proxy",
+        global = true
+    )]
+    all_proxy: Option<String>,
+    #[arg(help = "Proxy of the http requests.", long, global = true)]
+    http_proxy: Option<String>,
+    #[arg(help = "Proxy of the https requests.", long, global = true)]
+    https_proxy: Option<String>,
+    #[arg(
+        help = "User name of the proxies, when it isn't in their url.",
+        long,
+        global = true
+    )]
+    proxy_user: Option<String>,
+    #[arg(
+        help = "Environment variable holding the password of the proxies.",
+        long,
+        global = true
+    )]
+    proxy_password_env: Option<String>,
+    #[arg(
+        help = "Comma separated hosts connected to directly. A leading . also matches \
+        subdomains, * matches every host. Can be repeated.",
+        long,
+        global = true,
+        value_delimiter = ','
+    )]
+    no_proxy: Vec<String>,
+    #[arg(
+        help = "A PEM file of root certificates to trust, E.g the CA of internal sites. \
+        Can be repeated.",
+        long = "ca-cert",
+        global = true
+    )]
+    ca_certificates: Vec<String>,
+    #[arg(
+        help = "PEM file of the client certificate for mutual TLS, with --client-key.",
+        long = "client-cert",
+        global = true
+    )]
+    client_certificate: Option<String>,
+    #[arg(
+        help = "PEM file of the PKCS#8 private key of the client certificate.",
+        long,
+        global = true
+    )]
+    client_key: Option<String>,
+    #[arg(
+        help = "PKCS#12 file of the client certificate and key, instead of --client-cert.",
+        long,
+        global = true
+    )]
+    client_pkcs12: Option<String>,
+    #[arg(
+        help = "Environment variable holding the password of the PKCS#12 file.",
+        long,
+        global = true
+    )]
+    client_pkcs12_password_env: Option<String>,
+    #[arg(
+        help = "INSECURE. A host whose certificate isn't verified, E.g a staging site with a \
+        self signed certificate. A leading . also matches subdomains, * matches every host. \
+        Can be repeated.",
+        long = "insecure-host",
+        global = true
+    )]
+    insecure_hosts: Vec<String>,
+    #[arg(
+        help = "Url a login form is posted to before the crawl, the cookies it gets are kept \
+        for the session.",
+        long,
+        global = true
+    )]
+    login_url: Option<String>,
+    #[arg(
+        help = "A field of the login form, as name=value. Can be repeated.",
+        long = "login-field",
+        global = true,
+        value_parser = parse_key_value
+    )]
+    login_fields: Vec<(String, String)>,
+    #[arg(
+        help = "A field of the login form read from an environment variable, as name=VAR. \
+        E.g password=SITE_PASSWORD. Can be repeated.",
+        long = "login-field-env",
+        global = true,
+        value_parser = parse_key_value
+    )]
+    login_field_envs: Vec<(String, String)>,
+    #[arg(
+        help = "The login succeeded if the page it ends on contains this text.",
+        long,
+        global = true
+    )]
+    login_success_text: Option<String>,
+    #[arg(
+        help = "The login succeeded if the url it ends on contains this text.",
+        long,
+        global = true
+    )]
+    login_success_url: Option<String>,
+    #[arg(
+        help = "Links whose url or text contain this are never followed, to stay logged in. \
+        Added to the default patterns, E.g logout and sign out. Can be repeated.",
+        long = "logout-pattern",
+        global = true
+    )]
+    logout_patterns: Vec<String>,
     #[arg(
         help = "A space separated list of texts/urls. All links will be checked if they contain \
         the text or the url, links that match the check won't be downloaded."
@@ -39,77 +438,578 @@ pub struct Cli {
     blacklist_urls: Vec<String>,
 }
 
-pub async fn download(cli: Cli) {
-    println!("Initializing download....");
-    let (tx, mut rx) = channel::<Update>(MAX_BUFFER_SIZE);
-    tokio::spawn(async move {
-        match init_download(
-            &format!("Session-{}", Utc::now().timestamp()),
-            cli.url.as_ref(),
-            &cli.output_directory,
-            DownloadRule {
-                max_level: cli.max_level,
-                abort_on_download_error: cli.abort_on_download_error.unwrap_or(false),
-                download_static_resource_with_unknown_size: cli
-                    .download_files_with_unknown_size
-                    .unwrap_or(true),
-                progress_update_interval: PROGRESS_UPDATE_INTERVAL,
-                max_static_file_size: cli.max_file_size,
-                black_list_urls: cli.blacklist_urls.clone(),
-            },
-            tx,
-        )
-        .await
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inspect the configuration.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the effective configuration, after merging the config file,
+    /// the selected profile and command line flags. Proxy passwords are
+    /// replaced by `***`.
+    Show,
+}
+
+impl Cli {
+    /// Loads the config file, if any, and applies the command line flags over it.
+    fn effective_config(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path, self.profile.as_deref())?,
+            None => Config::default(),
+        };
+        if let Some(url) = &self.url {
+            config.url = Some(url.clone());
+        }
+        config.seeds.extend(self.seeds.iter().cloned());
+        if let Some(path) = &self.urls_from {
+            config.seeds.extend(read_url_list(path)?);
+        }
+        if let Some(output_directory) = self
+            .output_directory
+            .as_ref()
+            .or(self.output_directory_flag.as_ref())
         {
-            Ok(_) => {
-                println!(
-                    "{} {}",
-                    "Webpage(s) downloaded successfully : "
-                        .if_supports_color(Stream::Stdout, |text| text.bright_green()),
-                    cli.output_directory
-                );
+            config.output_directory = Some(output_directory.clone());
+        }
+        if let Some(report_file) = &self.report_file {
+            config.report_file = Some(report_file.clone());
+        }
+        if let Some(format) = self.output_format {
+            config.output_format = format;
+        }
+        if self.no_progress {
+            config.no_progress = true;
+        }
+        let rule = &mut config.rule;
+        if let Some(max_file_size) = self.max_file_size {
+            rule.max_static_file_size = max_file_size;
+        }
+        if let Some(max_level) = self.max_level {
+            rule.max_level = max_level;
+        }
+        if let Some(abort) = self.abort_on_download_error {
+            rule.abort_on_download_error = abort;
+        }
+        if let Some(download) = self.download_files_with_unknown_size {
+            rule.download_static_resource_with_unknown_size = download;
+        }
+        rule.black_list_urls.extend(
+            self.blacklist_url_flags
+                .iter()
+                .chain(self.blacklist_urls.iter())
+                .cloned(),
+        );
+        if let Some(hosts) = self.page_scope {
+            rule.page_scope.hosts = hosts;
+        }
+        if let Some(hosts) = self.resource_scope {
+            rule.resource_scope.hosts = hosts;
+        }
+        rule.page_scope
+            .allowed_hosts
+            .extend(self.allowed_hosts.iter().cloned());
+        rule.resource_scope
+            .allowed_hosts
+            .extend(self.allowed_resource_hosts.iter().cloned());
+        if let Some(prefix) = &self.path_prefix {
+            rule.page_scope.path_prefix = Some(prefix.clone());
+        }
+        rule.filters.extend(self.filters.iter().cloned());
+        if let Some(trailing_slash) = self.trailing_slash {
+            rule.normalization.trailing_slash = trailing_slash;
+        }
+        if let Some(follow) = self.follow_canonical {
+            rule.normalization.follow_canonical = follow;
+        }
+        if let Some(clean) = self.clean_queries {
+            let preset = if clean {
+                Normalization::clean_queries()
+            } else {
+                Normalization::none()
+            };
+            rule.normalization.strip_params = preset.strip_params;
+            rule.normalization.sort_query = preset.sort_query;
+        }
+        if let Some(sitemap) = self.sitemap {
+            rule.sitemap.enabled = sitemap;
+        }
+        if !self.sitemap_urls.is_empty() {
+            rule.sitemap.enabled = true;
+            rule.sitemap
+                .sitemap_urls
+                .extend(self.sitemap_urls.iter().map(Url::to_string));
+        }
+        if let Some(since) = self.modified_since {
+            rule.sitemap.modified_since = Some(since);
+        }
+        if let Some(max_pages) = self.max_pages {
+            rule.budget.max_pages = Some(max_pages);
+        }
+        if let Some(max_resources) = self.max_resources {
+            rule.budget.max_resources = Some(max_resources);
+        }
+        if let Some(max_bytes) = self.max_bytes {
+            rule.budget.max_bytes = Some(max_bytes);
+        }
+        if let Some(seconds) = self.max_duration {
+            rule.budget.max_duration = Some(seconds);
+        }
+        if let Some(convert) = self.convert_to_utf8 {
+            rule.convert_to_utf8 = convert;
+        }
+        if let Some(user_agent) = &self.user_agent {
+            rule.http.user_agent = user_agent.clone();
+        }
+        rule.http.headers.extend(self.headers.iter().cloned());
+        for (host, header) in &self.host_headers {
+            rule.http
+                .host_headers
+                .entry(host.clone())
+                .or_default()
+                .extend([header.clone()]);
+        }
+        if let Some(path) = &self.cookie_jar {
+            rule.http.cookie_jar = Some(path.clone());
+        }
+        rule.http
+            .import_cookies
+            .extend(self.import_cookies.iter().cloned());
+        if let Some(path) = &self.netrc {
+            rule.http.netrc = Some(path.clone());
+        }
+        rule.http
+            .credentials
+            .extend(self.credentials.iter().cloned());
+        let proxy = &mut rule.http.proxy;
+        for (flag, option) in [
+            (&self.all_proxy, &mut proxy.all),
+            (&self.http_proxy, &mut proxy.http),
+            (&self.https_proxy, &mut proxy.https),
+            (&self.proxy_user, &mut proxy.username),
+            (&self.proxy_password_env, &mut proxy.password_env),
+        ] {
+            if let Some(value) = flag {
+                *option = Some(value.clone());
             }
-            Err(e) => {
-                println!(
-                    "{}",
-                    "Download wasn't able to complete"
-                        .if_supports_color(Stream::Stdout, |text| text.bright_red())
-                );
-                println!("{e}") 
+        }
+        proxy.no_proxy.extend(self.no_proxy.iter().cloned());
+        let tls = &mut rule.http.tls;
+        tls.ca_certificates
+            .extend(self.ca_certificates.iter().cloned());
+        for (flag, option) in [
+            (&self.client_certificate, &mut tls.client_certificate),
+            (&self.client_key, &mut tls.client_key),
+            (&self.client_pkcs12, &mut tls.client_pkcs12),
+            (
+                &self.client_pkcs12_password_env,
+                &mut tls.client_pkcs12_password_env,
+            ),
+        ] {
+            if let Some(value) = flag {
+                *option = Some(value.clone());
+            }
+        }
+        tls.insecure_hosts
+            .extend(self.insecure_hosts.iter().cloned());
+        if let Some(url) = &self.login_url {
+            rule.http.login = Some(FormLogin {
+                url: url.clone(),
+                success_text: None,
+                success_url: None,
+                fields: Default::default(),
+                field_envs: Default::default(),
+            });
+        }
+        let login_flags = !self.login_fields.is_empty()
+            || !self.login_field_envs.is_empty()
+            || self.login_success_text.is_some()
+            || self.login_success_url.is_some();
+        match &mut rule.http.login {
+            Some(login) => {
+                login.fields.extend(self.login_fields.iter().cloned());
+                login
+                    .field_envs
+                    .extend(self.login_field_envs.iter().cloned());
+                if let Some(text) = &self.login_success_text {
+                    login.success_text = Some(text.clone());
+                }
+                if let Some(url) = &self.login_success_url {
+                    login.success_url = Some(url.clone());
+                }
             }
+            None if login_flags => return Err(ConfigError::LoginWithoutUrl),
+            None => {}
+        }
+        rule.http
+            .logout_patterns
+            .extend(self.logout_patterns.iter().cloned());
+        Ok(config)
+    }
+}
+
+fn parse_filter(value: &str) -> Result<UrlFilter, String> {
+    let mut parts = value.splitn(3, ':');
+    let (action, kind, pattern) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(action), Some(kind), Some(pattern)) => (action, kind, pattern.to_string()),
+        _ => return Err("expected <include|exclude>:<regex|glob|mime|ext>:<pattern>".into()),
+    };
+    let action = match action {
+        "include" => FilterAction::Include,
+        "exclude" => FilterAction::Exclude,
+        _ => {
+            return Err(format!(
+                "unknown action \"{action}\", expected include or exclude"
+            ))
         }
+    };
+    let pattern = match kind {
+        "regex" => FilterPattern::Regex(pattern),
+        "glob" => FilterPattern::Glob(pattern),
+        "mime" => FilterPattern::MimeType(pattern),
+        "ext" => FilterPattern::Extension(pattern),
+        _ => {
+            return Err(format!(
+                "unknown pattern kind \"{kind}\", expected regex, glob, mime or ext"
+            ))
+        }
+    };
+    Ok(UrlFilter { action, pattern })
+}
+
+/// E.g `Accept-Language: fr`
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    match value.split_once(':') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        }
+        _ => Err("expected Name: value".into()),
+    }
+}
+
+/// E.g `username=alice`
+fn parse_key_value(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err("expected name=value".into()),
+    }
+}
+
+/// E.g `api.example.org=X-Api-Key: secret`
+fn parse_host_header(value: &str) -> Result<(String, (String, String)), String> {
+    match value.split_once('=') {
+        Some((host, header)) if !host.trim().is_empty() => {
+            Ok((host.trim().to_string(), parse_header(header)?))
+        }
+        _ => Err("expected host=Name: value".into()),
+    }
+}
+
+/// E.g `docs.example.org=bearer:DOCS_TOKEN` or `https://docs.example.org:8443=bearer:DOCS_TOKEN`
+fn parse_credentials(value: &str) -> Result<Credentials, String> {
+    let (host, auth) = value
+        .split_once('=')
+        .ok_or("expected host=basic:USERNAME_VAR:PASSWORD_VAR or host=bearer:TOKEN_VAR")?;
+    let parts: Vec<&str> = auth.split(':').collect();
+    let (scheme, username_env, secret_env) = match parts.as_slice() {
+        ["basic", username, password] => (AuthScheme::Basic, Some(username), password),
+        ["bearer", token] => (AuthScheme::Bearer, None, token),
+        _ => {
+            return Err(format!(
+            "unknown auth \"{auth}\", expected basic:USERNAME_VAR:PASSWORD_VAR or bearer:TOKEN_VAR"
+        ))
+        }
+    };
+    let (host, url_scheme, port) = match host.trim() {
+        origin if origin.contains("://") => {
+            let url = Url::parse(origin).map_err(|e| format!("invalid url \"{origin}\". {e}"))?;
+            let host = url.host_str().ok_or(format!("no host in \"{origin}\""))?;
+            (
+                host.to_string(),
+                Some(url.scheme().to_string()),
+                url.port_or_known_default(),
+            )
+        }
+        host => (host.to_string(), None, None),
+    };
+    Ok(Credentials {
+        host,
+        url_scheme,
+        port,
+        scheme,
+        username_env: username_env.map(|var| var.to_string()),
+        secret_env: secret_env.to_string(),
+    })
+}
+
+fn print_error(context: &str, error: impl std::fmt::Display) {
+    println!(
+        "{} {error}",
+        context.if_supports_color(Stream::Stdout, |text| text.bright_red())
+    );
+}
+
+fn print_json(value: &impl Serialize) {
+    match serde_json::to_string(value) {
+        Ok(json) => println!("{json}"),
+        Err(e) => eprintln!("error serializing output : {e}"),
+    }
+}
+
+pub async fn run(cli: Cli) -> ExitCode {
+    let status = match cli.command {
+        Some(Command::Config {
+            action: ConfigAction::Show,
+        }) => show_config(&cli),
+        None => download(cli).await,
+    };
+    status.into()
+}
+
+fn show_config(cli: &Cli) -> Status {
+    let toml = cli.effective_config().and_then(|mut config| {
+        let proxy = &mut config.rule.http.proxy;
+        *proxy = proxy.redacted();
+        config.to_toml()
     });
-    while let Some(update) = rx.recv().await {
-        match update {
-            Update::MessageUpdate(msg) => {
-                println!(
-                    "{} {} | {}",
-                    if msg.is_error {
-                        format!(
-                            "{}",
-                            "[ERROR]".if_supports_color(Stream::Stdout, |text| text.bright_red())
-                        )
-                    } else {
-                        format!(
-                            "{}",
-                            "[INFO]".if_supports_color(Stream::Stdout, |text| text.green())
-                        )
-                    },
-                    msg.content,
-                    msg.resource_name
-                );
+    match toml {
+        Ok(toml) => {
+            print!("{toml}");
+            Status::Success
+        }
+        Err(e) => {
+            print_error("Invalid configuration :", e);
+            Status::InvalidInput
+        }
+    }
+}
+
+/// Reports an error that prevented the download from starting.
+fn invalid_input(format: OutputFormat, context: &str, error: impl std::fmt::Display) -> Status {
+    match format {
+        OutputFormat::Text => print_error(context, error),
+        OutputFormat::Jsonl => print_json(&Summary {
+            status: Status::InvalidInput,
+            exit_code: Status::InvalidInput as u8,
+            error: Some(format!("{context} {error}")),
+            report_file: None,
+            report: None,
+        }),
+    }
+    Status::InvalidInput
+}
+
+/// Status of a session that failed with `error`. Errors in the rule, the urls
+/// or the login credentials are invalid input.
+fn error_status(error: &WscError) -> Status {
+    match error {
+        WscError::InvalidDownloadRule(_) | WscError::InvalidUrl(_) | WscError::LoginFailed(_) => {
+            Status::InvalidInput
+        }
+        _ => Status::Failure,
+    }
+}
+
+pub async fn download(cli: Cli) -> Status {
+    let config = match cli.effective_config() {
+        Ok(config) => config,
+        Err(e) => {
+            let format = cli.output_format.unwrap_or_default();
+            return invalid_input(format, "Invalid configuration :", e);
+        }
+    };
+    let format = config.output_format;
+    let seed_urls = config.seed_urls();
+    let (url, output_directory) =
+        match (seed_urls.first(), config.output_directory) {
+            (Some(url), Some(output_directory)) => (url.clone(), output_directory),
+            _ => return invalid_input(
+                format,
+                "Invalid options :",
+                "an url and an output directory are required, as arguments or in the config file",
+            ),
+        };
+    let report_file = config
+        .report_file
+        .unwrap_or_else(|| PathBuf::from(&output_directory).join(REPORT_FILE_NAME));
+    let rule = config.rule;
+    if let Err(e) = rule.validate() {
+        return invalid_input(format, "Invalid options :", e);
+    }
+    if format == OutputFormat::Text {
+        println!("Initializing download....");
+    }
+    let (tx, mut rx) = channel::<Event>(MAX_BUFFER_SIZE);
+    let mut downloader = Downloader::new(
+        &format!("Session-{}", Utc::now().timestamp()),
+        url.as_ref(),
+        &output_directory,
+        rule,
+    );
+    for seed in seed_urls.iter().skip(1) {
+        downloader = downloader.seed(seed.as_ref());
+    }
+    let session = tokio::spawn(async move { downloader.run(tx).await });
+    match format {
+        OutputFormat::Text => {
+            let mut view = ProgressView::new(config.no_progress);
+            while let Some(event) = rx.recv().await {
+                view.handle(&event);
+            }
+            view.finish();
+        }
+        OutputFormat::Jsonl => {
+            while let Some(event) = rx.recv().await {
+                print_json(&event);
             }
-            Update::ProgressUpdate(progress) => {
-                if progress.bytes_written >= progress.file_size {
+        }
+    }
+
+    let result = match session.await {
+        Ok(Ok(report)) => Ok(report),
+        Ok(Err(e)) => match error_status(&e) {
+            Status::InvalidInput => Err((Status::InvalidInput, "Invalid options :", e.to_string())),
+            status => Err((status, "Download wasn't able to complete :", e.to_string())),
+        },
+        Err(e) => Err((Status::Failure, "Download task panicked :", e.to_string())),
+    };
+    let (status, report_written) = match &result {
+        Ok(report) => {
+            let status = if report.stats.failed > 0 {
+                Status::PartialFailure
+            } else {
+                Status::Success
+            };
+            (status, Some(write_report(report, &report_file)))
+        }
+        Err((status, _, _)) => (*status, None),
+    };
+
+    match format {
+        OutputFormat::Text => {
+            match &result {
+                Ok(report) => {
                     println!(
-                        "{} {} {} bytes",
-                        "[DOWNLOADED]"
+                        "{} {}",
+                        "Webpage(s) downloaded successfully : "
                             .if_supports_color(Stream::Stdout, |text| text.bright_green()),
-                        progress.resource_name,
-                        progress.file_size
-                    )
+                        report.destination_dir
+                    );
+                    print!("{report}");
                 }
+                Err((_, context, e)) => print_error(context, e),
             }
-        };
+            match report_written {
+                Some(Ok(_)) => println!("Report written to {}", report_file.display()),
+                Some(Err(e)) => print_error("Error writing report :", e),
+                None => {}
+            }
+        }
+        OutputFormat::Jsonl => {
+            let error = match (&result, &report_written) {
+                (Err((_, context, e)), _) => Some(format!("{context} {e}")),
+                (_, Some(Err(e))) => Some(format!("Error writing report : {e}")),
+                _ => None,
+            };
+            print_json(&Summary {
+                status,
+                exit_code: status as u8,
+                error,
+                report_file: matches!(report_written, Some(Ok(_))).then_some(report_file.as_path()),
+                report: result.as_ref().ok(),
+            });
+        }
+    }
+    status
+}
+
+fn write_report(report: &SessionReport, path: &Path) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(report).map_err(|e| e.to_string())?;
+    std::fs::write(path, json).map_err(|e| format!("{e} : {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_subcommand_is_parsed_after_global_flags() {
+        for args in [
+            ["wsclone", "--config", "site.toml", "config", "show"],
+            ["wsclone", "config", "show", "--config", "site.toml"],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(
+                cli.command,
+                Some(Command::Config {
+                    action: ConfigAction::Show
+                })
+            ));
+            assert_eq!(cli.config, Some(PathBuf::from("site.toml")));
+            assert_eq!(cli.url, None);
+        }
+        let cli = Cli::try_parse_from(["wsclone", "https://example.com/", "site"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.output_directory.as_deref(), Some("site"));
+    }
+
+    #[test]
+    fn list_flags_extend_the_config_lists() {
+        let path = std::env::temp_dir().join(format!("wsclone-lists-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[rule]\nblack_list_urls = [\"/admin\"]\n\n[[rule.filters]]\naction = \"exclude\"\n\
+            extension = \"zip\"\n\n[rule.http]\nlogout_patterns = [\"quit\"]\n\n\
+            [rule.http.headers]\nAccept = \"text/html\"\n",
+        )
+        .unwrap();
+        let cli = Cli::try_parse_from([
+            "wsclone",
+            "--config",
+            &path.to_string_lossy(),
+            "--blacklist-url",
+            "/private",
+            "--filter",
+            "exclude:ext:pdf",
+            "--logout-pattern",
+            "exit",
+            "--header",
+            "X-Token: 1",
+        ])
+        .unwrap();
+        let rule = cli.effective_config().unwrap().rule;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rule.black_list_urls, ["/admin", "/private"]);
+        assert_eq!(
+            rule.filters,
+            [
+                UrlFilter::exclude(FilterPattern::Extension("zip".to_string())),
+                UrlFilter::exclude(FilterPattern::Extension("pdf".to_string())),
+            ]
+        );
+        assert_eq!(rule.http.logout_patterns, ["quit", "exit"]);
+        assert_eq!(rule.http.headers.len(), 2);
+    }
+
+    #[test]
+    fn library_input_errors_are_invalid_input() {
+        use libwsclone::RuleError;
+
+        for error in [
+            WscError::InvalidDownloadRule(RuleError::ZeroMaxStaticFileSize),
+            WscError::InvalidUrl("not a url".to_string()),
+            WscError::LoginFailed("wrong password".to_string()),
+        ] {
+            assert_eq!(error_status(&error), Status::InvalidInput, "{error}");
+        }
+        let error = WscError::NetworkError("timed out".to_string());
+        assert_eq!(error_status(&error), Status::Failure);
     }
 }
@@ -0,0 +1,248 @@
+use clap::ValueEnum;
+use libwsclone::DownloadRule;
+use serde::{Deserialize, Serialize};
+use std::fmt::Formatter;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+/// Name of the table holding named profiles in a configuration file.
+const PROFILES_KEY: &str = "profiles";
+
+/// Crawl configuration, as read from a TOML file.
+///
+/// Top level values apply to every run. A profile from the `[profiles.<name>]`
+/// tables is merged over them when selected, tables are merged key by key.
+/// ```toml
+/// url = "https://example.com"
+/// seeds = ["https://example.com/pricing"]
+/// output_directory = "site"
+/// output_format = "jsonl"
+///
+/// [rule]
+/// max_level = 1
+///
+/// [profiles.nightly]
+/// output_directory = "nightly"
+/// rule = { max_level = 3, abort_on_download_error = true }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    /// Pages crawled in the same session as `url`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub seeds: Vec<Url>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output_directory: Option<String>,
+    /// Where to write the JSON session report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub report_file: Option<PathBuf>,
+    pub output_format: OutputFormat,
+    /// Print plain lines instead of progress bars, in the text format
+    pub no_progress: bool,
+    pub rule: DownloadRule,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+    /// Human readable output, with progress bars on a terminal
+    #[default]
+    Text,
+    /// One JSON object per event, followed by a summary object
+    Jsonl,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        path: PathBuf,
+        message: String,
+    },
+    Parse {
+        path: PathBuf,
+        message: String,
+    },
+    UnknownProfile(String),
+    Serialize(String),
+    /// Login fields or checks were given without the url of the form
+    LoginWithoutUrl,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ConfigError::Read { path, message } => {
+                format!("error reading config file {} : {message}", path.display())
+            }
+            ConfigError::Parse { path, message } => {
+                format!("invalid config file {} : {message}", path.display())
+            }
+            ConfigError::UnknownProfile(name) => format!("no profile named \"{name}\""),
+            ConfigError::Serialize(err) => format!("error serializing configuration. {err}"),
+            ConfigError::LoginWithoutUrl => {
+                "login options need --login-url, or a login url in the config file".to_string()
+            }
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl Config {
+    /// The url followed by the seeds, without duplicates.
+    pub fn seed_urls(&self) -> Vec<Url> {
+        let mut urls: Vec<Url> = Vec::new();
+        for url in self.url.iter().chain(self.seeds.iter()) {
+            if !urls.contains(url) {
+                urls.push(url.clone());
+            }
+        }
+        urls
+    }
+
+    /// Reads the config file at `path`, with the named profile merged over the top level values.
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|e| ConfigError::Read {
+            path: path.to_path_buf(),
+            message: e.to_string(),
+        })?;
+        let parse_error = |message: String| ConfigError::Parse {
+            path: path.to_path_buf(),
+            message,
+        };
+
+        let mut value: toml::Value = content.parse().map_err(|e| parse_error(format!("{e}")))?;
+        let table = value
+            .as_table_mut()
+            .ok_or_else(|| parse_error("expected a table".into()))?;
+        let mut profiles = match table.remove(PROFILES_KEY) {
+            None => toml::value::Table::new(),
+            Some(toml::Value::Table(t)) => t,
+            Some(_) => return Err(parse_error(format!("\"{PROFILES_KEY}\" must be a table"))),
+        };
+
+        if let Some(name) = profile {
+            let overlay = profiles
+                .remove(name)
+                .ok_or_else(|| ConfigError::UnknownProfile(name.to_string()))?;
+            merge(&mut value, overlay);
+        }
+
+        value.try_into().map_err(|e| parse_error(format!("{e}")))
+    }
+
+    /// Plain values are written before tables, as TOML requires, whatever the field order.
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        let value =
+            toml::Value::try_from(self).map_err(|e| ConfigError::Serialize(e.to_string()))?;
+        toml::to_string_pretty(&value).map_err(|e| ConfigError::Serialize(e.to_string()))
+    }
+}
+
+/// Reads the urls listed in `path`, one per line, or from stdin when `path`
+/// is `-`. Blank lines and lines starting with `#` are ignored.
+pub fn read_url_list(path: &Path) -> Result<Vec<Url>, ConfigError> {
+    let read_error = |e: std::io::Error| ConfigError::Read {
+        path: path.to_path_buf(),
+        message: e.to_string(),
+    };
+    let content = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin()).map_err(read_error)?
+    } else {
+        std::fs::read_to_string(path).map_err(read_error)?
+    };
+    content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(idx, line)| {
+            Url::parse(line).map_err(|e| ConfigError::Parse {
+                path: path.to_path_buf(),
+                message: format!("line {} : {e} : {line}", idx + 1),
+            })
+        })
+        .collect()
+}
+
+/// Merges `overlay` into `base`. Tables are merged recursively, any other value is replaced.
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn load(content: &str) -> Result<Config, ConfigError> {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "wsclone-config-{}-{}.toml",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, content).unwrap();
+        let config = Config::load(&path, None);
+        std::fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn output_options_are_read() {
+        let config = load("output_format = \"jsonl\"\nno_progress = true\n").unwrap();
+        assert_eq!(config.output_format, OutputFormat::Jsonl);
+        assert!(config.no_progress);
+        let config = load("url = \"https://example.com/\"\n").unwrap();
+        assert_eq!(config.output_format, OutputFormat::Text);
+        assert!(!config.no_progress);
+    }
+
+    #[test]
+    fn unknown_rule_keys_are_rejected() {
+        let config = load("[rule]\nmax_level = 3\n\n[rule.budget]\nmax_pages = 5\n").unwrap();
+        assert_eq!(config.rule.max_level, 3);
+        assert_eq!(config.rule.budget.max_pages, Some(5));
+
+        for (content, key) in [
+            ("[rule]\nmax_levle = 3\n", "max_levle"),
+            ("[rule.budget]\nmax_page = 5\n", "max_page"),
+            (
+                "[rule.http.proxy]\nhttps_proxy = \"http://p:3128\"\n",
+                "https_proxy",
+            ),
+            (
+                "[rule.page_scope]\nhosts = \"same_host\"\nprefix = \"/docs\"\n",
+                "prefix",
+            ),
+            (
+                "[[rule.filters]]\naction = \"exclude\"\nglob = \"*.zip\"\nregex = \"x\"\n",
+                "one of",
+            ),
+            (
+                "[[rule.filters]]\naction = \"exclude\"\nglb = \"*.zip\"\n",
+                "glb",
+            ),
+        ] {
+            match load(content) {
+                Err(ConfigError::Parse { message, .. }) => {
+                    assert!(message.contains(key), "{content}: {message}")
+                }
+                other => panic!("{content} loaded: {other:?}"),
+            }
+        }
+    }
+}
@@ -1,11 +1,14 @@
-use crate::cli::download;
+use crate::cli::run;
 use clap::Parser;
 use std::path::MAIN_SEPARATOR;
+use std::process::ExitCode;
 
 mod cli;
+mod config;
+mod progress;
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let f_appender = tracing_appender::rolling::hourly(format!(".{MAIN_SEPARATOR}"), "wsclone.log");
     let (non_blk, _guard) = tracing_appender::non_blocking(f_appender);
     tracing_subscriber::fmt()
@@ -14,5 +17,5 @@ async fn main() {
         .with_writer(non_blk)
         .init();
     let cli = cli::Cli::parse();
-    download(cli).await;
+    run(cli).await
 }
@@ -0,0 +1,241 @@
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use libwsclone::{Event, EventKind, Update};
+use owo_colors::{OwoColorize, Stream};
+use std::collections::{BTreeMap, HashMap};
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+const TICK_INTERVAL: Duration = Duration::from_millis(120);
+const SIZED_TEMPLATE: &str =
+    "{prefix:32!} [{bar:30.cyan/blue}] {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}";
+const UNSIZED_TEMPLATE: &str = "{prefix:32!} {spinner} {bytes} {bytes_per_sec}";
+const OVERALL_TEMPLATE: &str = "{spinner:.green} [{elapsed_precise}] {msg}";
+
+/// Shows the events of a session on the terminal. Progress bars are used when
+/// stdout is a terminal, plain lines otherwise.
+pub enum ProgressView {
+    Bars(Box<Bars>),
+    Plain,
+}
+
+impl ProgressView {
+    pub fn new(force_plain: bool) -> Self {
+        if force_plain || !std::io::stdout().is_terminal() {
+            ProgressView::Plain
+        } else {
+            ProgressView::Bars(Box::new(Bars::new()))
+        }
+    }
+
+    pub fn handle(&mut self, event: &Event) {
+        match self {
+            ProgressView::Bars(bars) => bars.handle(event),
+            ProgressView::Plain => print_plain(event),
+        }
+    }
+
+    /// Removes the bars, leaving the terminal ready for the final messages.
+    pub fn finish(&mut self) {
+        if let ProgressView::Bars(bars) = self {
+            bars.finish();
+        }
+    }
+}
+
+/// Prints the event the way the cli did before progress bars, one line per message or file.
+fn print_plain(event: &Event) {
+    match event.to_update() {
+        Some(Update::MessageUpdate(msg)) => {
+            println!(
+                "{} {} | {}",
+                if msg.is_error {
+                    format!(
+                        "{}",
+                        "[ERROR]".if_supports_color(Stream::Stdout, |text| text.bright_red())
+                    )
+                } else {
+                    format!(
+                        "{}",
+                        "[INFO]".if_supports_color(Stream::Stdout, |text| text.green())
+                    )
+                },
+                msg.content,
+                msg.resource_name
+            );
+        }
+        Some(Update::ProgressUpdate(progress)) => {
+            if let EventKind::ResourceCompleted { .. } = event.kind {
+                println!(
+                    "{} {} {} bytes",
+                    "[DOWNLOADED]".if_supports_color(Stream::Stdout, |text| text.bright_green()),
+                    progress.resource_name,
+                    progress.file_size
+                )
+            }
+        }
+        None => {}
+    }
+}
+
+pub struct Bars {
+    multi: MultiProgress,
+    overall: ProgressBar,
+    /// Bars of the downloads in progress, by url
+    active: HashMap<String, ProgressBar>,
+    pages_queued: BTreeMap<u8, u64>,
+    pages_done: BTreeMap<u8, u64>,
+    resources: u64,
+    skipped: u64,
+    errors: u64,
+    bytes: u64,
+    started: Instant,
+}
+
+impl Bars {
+    fn new() -> Self {
+        let multi = MultiProgress::new();
+        let overall = multi.add(ProgressBar::new_spinner());
+        overall.set_style(ProgressStyle::with_template(OVERALL_TEMPLATE).unwrap());
+        overall.enable_steady_tick(TICK_INTERVAL);
+        Bars {
+            multi,
+            overall,
+            active: HashMap::new(),
+            pages_queued: BTreeMap::new(),
+            pages_done: BTreeMap::new(),
+            resources: 0,
+            skipped: 0,
+            errors: 0,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+
+    fn handle(&mut self, event: &Event) {
+        let url = event.url.clone().unwrap_or_default();
+        match &event.kind {
+            EventKind::SessionStarted => *self.pages_queued.entry(0).or_default() += 1,
+            EventKind::PageQueued => *self.pages_queued.entry(event.depth).or_default() += 1,
+            EventKind::ResourceStarted {
+                resource_name,
+                file_size,
+                ..
+            } => {
+                let bar = match file_size {
+                    Some(size) => {
+                        let bar = ProgressBar::new(*size);
+                        bar.set_style(ProgressStyle::with_template(SIZED_TEMPLATE).unwrap());
+                        bar
+                    }
+                    None => {
+                        let bar = ProgressBar::new_spinner();
+                        bar.set_style(ProgressStyle::with_template(UNSIZED_TEMPLATE).unwrap());
+                        bar.enable_steady_tick(TICK_INTERVAL);
+                        bar
+                    }
+                };
+                bar.set_prefix(resource_name.clone());
+                if let Some(old) = self.active.insert(url, self.multi.add(bar)) {
+                    old.finish_and_clear();
+                }
+            }
+            EventKind::ResourceProgress { bytes_written, .. } => {
+                if let Some(bar) = self.active.get(&url) {
+                    bar.set_position(*bytes_written);
+                }
+            }
+            EventKind::ResourceCompleted {
+                is_page,
+                bytes_written,
+                cached,
+                ..
+            } => {
+                self.remove_bar(&url);
+                if *is_page {
+                    *self.pages_done.entry(event.depth).or_default() += 1;
+                } else {
+                    self.resources += 1;
+                }
+                if !cached {
+                    self.bytes += bytes_written;
+                }
+            }
+            EventKind::ResourceSkipped { .. } => {
+                // A body larger than the limit is skipped once it started
+                self.remove_bar(&url);
+                self.skipped += 1;
+            }
+            EventKind::ResourceFailed { error } => {
+                self.remove_bar(&url);
+                self.errors += 1;
+                self.println(format!(
+                    "{} {error}",
+                    "[ERROR]".if_supports_color(Stream::Stdout, |text| text.bright_red())
+                ));
+            }
+            EventKind::RetryScheduled {
+                attempt, delay_ms, ..
+            } => self.println(format!(
+                "{} retrying {url} in {delay_ms}ms (attempt {attempt})",
+                "[INFO]".if_supports_color(Stream::Stdout, |text| text.green())
+            )),
+            EventKind::BudgetExhausted { budget } => self.println(format!(
+                "{} {budget} budget exhausted, stopping the crawl",
+                "[INFO]".if_supports_color(Stream::Stdout, |text| text.green())
+            )),
+            EventKind::TlsVerificationDisabled { hosts } => self.println(format!(
+                "{} certificates of {} aren't verified, the connections to them are insecure",
+                "[WARNING]".if_supports_color(Stream::Stdout, |text| text.bright_red()),
+                hosts.join(", ")
+            )),
+            EventKind::Message { content, is_error } if *is_error => self.println(format!(
+                "{} {content} | {url}",
+                "[ERROR]".if_supports_color(Stream::Stdout, |text| text.bright_red())
+            )),
+            _ => {}
+        }
+        self.overall.set_message(self.summary());
+    }
+
+    fn remove_bar(&mut self, url: &str) {
+        if let Some(bar) = self.active.remove(url) {
+            bar.finish_and_clear();
+            self.multi.remove(&bar);
+        }
+    }
+
+    fn println(&self, line: String) {
+        if self.multi.println(&line).is_err() {
+            println!("{line}");
+        }
+    }
+
+    /// E.g `pages L0 1/1 L1 3/12 | 20 resources | 1 skipped | 0 errors | 1.2 MiB @ 300 KiB/s`
+    fn summary(&self) -> String {
+        let levels: Vec<String> = self
+            .pages_queued
+            .iter()
+            .map(|(depth, queued)| {
+                let done = self.pages_done.get(depth).copied().unwrap_or_default();
+                format!("L{depth} {done}/{queued}")
+            })
+            .collect();
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        format!(
+            "pages {} | {} resources | {} skipped | {} errors | {} @ {}/s",
+            levels.join(" "),
+            self.resources,
+            self.skipped,
+            self.errors,
+            HumanBytes(self.bytes),
+            HumanBytes((self.bytes as f64 / elapsed) as u64)
+        )
+    }
+
+    fn finish(&mut self) {
+        for (_, bar) in self.active.drain() {
+            bar.finish_and_clear();
+        }
+        self.overall.finish_with_message(self.summary());
+    }
+}
@@ -0,0 +1,275 @@
+use crate::errors::{RuleError, WscError};
+use crate::scope::host_matches;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// Credentials sent to a host. They are read from environment variables when
+/// the session starts, so they don't end up in config files or the shell history.
+///
+/// Only requests with the scheme, host and port of the credentials get them.
+/// The session follows redirects itself, a redirect to another host, port or
+/// from `https` to `http` is requested without them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    /// A leading `.` also matches the subdomains, E.g `.example.org`
+    pub host: String,
+    /// `http` or `https`. The scheme of the seed on `host` by default, else of the first seed.
+    pub url_scheme: Option<String>,
+    /// The port of the seed on `host` by default, else the default port of the scheme.
+    pub port: Option<u16>,
+    pub scheme: AuthScheme,
+    /// Environment variable holding the user name, for basic auth
+    pub username_env: Option<String>,
+    /// Environment variable holding the password for basic auth, or the token for bearer auth
+    pub secret_env: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthScheme {
+    Basic,
+    Bearer,
+}
+
+impl Credentials {
+    pub(crate) fn validate(&self) -> Result<(), RuleError> {
+        let invalid = |message: &str| RuleError::InvalidCredentials {
+            host: self.host.clone(),
+            message: message.to_string(),
+        };
+        if self.host.trim_start_matches('.').trim().is_empty() {
+            return Err(invalid("host is empty"));
+        }
+        if let Some(url_scheme) = &self.url_scheme {
+            if !["http", "https"].contains(&url_scheme.to_lowercase().as_str()) {
+                return Err(invalid("url scheme isn't http or https"));
+            }
+        }
+        if self.secret_env.trim().is_empty() {
+            return Err(invalid("secret environment variable is empty"));
+        }
+        match (self.scheme, &self.username_env) {
+            (AuthScheme::Basic, None) => Err(invalid("basic auth needs a username variable")),
+            (AuthScheme::Bearer, Some(_)) => Err(invalid("bearer auth has no username")),
+            _ => Ok(()),
+        }
+    }
+
+    /// The `Authorization` header, from the environment variables.
+    fn authorization(&self) -> Result<HeaderValue, WscError> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| RuleError::InvalidCredentials {
+                host: self.host.clone(),
+                message: format!("environment variable {name} isn't set"),
+            })
+        };
+        let secret = var(&self.secret_env)?;
+        let value = match (self.scheme, &self.username_env) {
+            (AuthScheme::Basic, Some(username_env)) => basic(&var(username_env)?, &secret),
+            _ => format!("Bearer {secret}"),
+        };
+        header_value(&self.host, &value)
+    }
+}
+
+/// The urls credentials are sent to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CredentialScope {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+impl CredentialScope {
+    /// The scheme and port not given are the ones of the first seed on `host`.
+    /// With no seed on `host`, the scheme of the first seed and it's default port.
+    fn new(host: &str, scheme: Option<&str>, port: Option<u16>, seeds: &[Url]) -> Self {
+        let host = host.to_lowercase();
+        let seed_on_host = seeds
+            .iter()
+            .find(|seed| host_matches(&host, seed.host_str().unwrap_or_default()));
+        let scheme = match (scheme, seed_on_host.or(seeds.first())) {
+            (Some(scheme), _) => scheme.to_lowercase(),
+            (None, Some(seed)) => seed.scheme().to_string(),
+            (None, None) => "https".to_string(),
+        };
+        let port = port
+            .or_else(|| {
+                seed_on_host
+                    .filter(|seed| seed.scheme() == scheme)
+                    .and_then(Url::port_or_known_default)
+            })
+            .unwrap_or(if scheme == "http" { 80 } else { 443 });
+        CredentialScope { scheme, host, port }
+    }
+
+    /// Whether `url` has the scheme, host and port of the scope.
+    pub(crate) fn matches(&self, url: &Url) -> bool {
+        url.scheme() == self.scheme
+            && url.port_or_known_default() == Some(self.port)
+            && host_matches(&self.host, url.host_str().unwrap_or_default())
+    }
+}
+
+fn basic(login: &str, password: &str) -> String {
+    format!("Basic {}", STANDARD.encode(format!("{login}:{password}")))
+}
+
+/// Marked sensitive so it's never printed in debug output.
+fn header_value(host: &str, value: &str) -> Result<HeaderValue, WscError> {
+    let mut value = HeaderValue::from_str(value).map_err(|e| RuleError::InvalidCredentials {
+        host: host.to_string(),
+        message: e.to_string(),
+    })?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// The `Authorization` header of each scope with credentials. Entries of the
+/// netrc file come first, so `credentials` replace them for the same scope.
+/// netrc machines have the scheme and port of the `seeds` on them.
+pub(crate) fn authorization_headers(
+    credentials: &[Credentials],
+    netrc: Option<&str>,
+    seeds: &[Url],
+) -> Result<Vec<(CredentialScope, HeaderMap)>, WscError> {
+    let mut headers = Vec::new();
+    if let Some(path) = netrc {
+        for (machine, login, password) in read_netrc(path)? {
+            let value = header_value(&machine, &basic(&login, &password))?;
+            headers.push((
+                CredentialScope::new(&machine, None, None, seeds),
+                HeaderMap::from_iter([(AUTHORIZATION, value)]),
+            ));
+        }
+    }
+    for credentials in credentials {
+        let value = credentials.authorization()?;
+        let scope = CredentialScope::new(
+            &credentials.host,
+            credentials.url_scheme.as_deref(),
+            credentials.port,
+            seeds,
+        );
+        headers.push((scope, HeaderMap::from_iter([(AUTHORIZATION, value)])));
+    }
+    Ok(headers)
+}
+
+/// The `machine`, `login` and `password` of a netrc file. The `default`
+/// entry is left out, it would send the credentials to every host.
+fn read_netrc(path: &str) -> Result<Vec<(String, String, String)>, WscError> {
+    let content = std::fs::read_to_string(path).map_err(|e| WscError::FileOperationError {
+        file_name: path.to_string(),
+        message: e.to_string(),
+    })?;
+    let mut entries = Vec::new();
+    let mut machine: Option<String> = None;
+    let (mut login, mut password) = (None, None);
+    let mut lines = content.lines();
+    while let Some(line) = lines.next() {
+        let mut tokens = line.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" | "default" => {
+                    if let (Some(machine), Some(password)) = (machine.take(), password.take()) {
+                        entries.push((machine, login.take().unwrap_or_default(), password));
+                    }
+                    (login, password) = (None, None);
+                    if token == "machine" {
+                        machine = tokens.next().map(str::to_lowercase);
+                    } else {
+                        tracing::debug!("Default netrc entry ignored");
+                    }
+                }
+                "login" => login = tokens.next().map(str::to_string),
+                "password" => password = tokens.next().map(str::to_string),
+                "account" => {
+                    tokens.next();
+                }
+                // A macro runs until the next empty line
+                "macdef" => {
+                    for line in lines.by_ref() {
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                token if token.starts_with('#') => break,
+                _ => {}
+            }
+        }
+    }
+    if let (Some(machine), Some(password)) = (machine, password) {
+        entries.push((machine, login.unwrap_or_default(), password));
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(name: &str, content: &str) -> Vec<(String, String, String)> {
+        let path = std::env::temp_dir().join(format!("wsclone-{name}-{}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let entries = read_netrc(&path.to_string_lossy()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        entries
+    }
+
+    fn entry(machine: &str, login: &str, password: &str) -> (String, String, String) {
+        (machine.into(), login.into(), password.into())
+    }
+
+    #[test]
+    fn netrc_entries_are_read() {
+        let entries = read(
+            "netrc-entries",
+            "# work\n\
+             machine Docs.Example.org login alice password s3cret\n\
+             machine api.example.org\n  login bob\n  account x\n  password p4ss # token\n\
+             machine token.example.org password only\n\
+             machine nopassword.example.org login carol\n",
+        );
+        assert_eq!(
+            entries,
+            [
+                entry("docs.example.org", "alice", "s3cret"),
+                entry("api.example.org", "bob", "p4ss"),
+                entry("token.example.org", "", "only"),
+            ]
+        );
+    }
+
+    #[test]
+    fn netrc_default_and_macros_are_skipped() {
+        let entries = read(
+            "netrc-skipped",
+            "machine a.org login alice password one\n\
+             macdef init\n  machine b.org login mallory password two\n\n\
+             default login anonymous password guest\n\
+             machine c.org login carol password three\n",
+        );
+        assert_eq!(
+            entries,
+            [
+                entry("a.org", "alice", "one"),
+                entry("c.org", "carol", "three")
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_netrc_is_an_error() {
+        assert!(matches!(
+            read_netrc("/nonexistent/.netrc"),
+            Err(WscError::FileOperationError { .. })
+        ));
+    }
+}
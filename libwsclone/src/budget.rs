@@ -0,0 +1,86 @@
+use crate::event::SessionStats;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Session wide limits, checked before each page or resource is requested.
+/// Once one runs out, the urls left are skipped and no more levels are
+/// crawled, links in what was fetched are still rewritten.
+///
+/// Downloads in progress when a budget runs out are completed, so the bytes
+/// can go slightly over their limit. Downloads still going when the duration
+/// runs out are stopped and their partial files removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budget {
+    /// Most pages requested, seeds included
+    pub max_pages: Option<u64>,
+    /// Most static resources requested
+    pub max_resources: Option<u64>,
+    /// Most bytes written to disk. Files cached from a previous session aren't counted.
+    pub max_bytes: Option<u64>,
+    /// Longest the session runs for, in seconds
+    pub max_duration: Option<u64>,
+}
+
+/// The limit of a [`Budget`] that ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetKind {
+    Pages,
+    Resources,
+    Bytes,
+    Duration,
+}
+
+impl std::fmt::Display for BudgetKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            BudgetKind::Pages => "pages",
+            BudgetKind::Resources => "resources",
+            BudgetKind::Bytes => "bytes",
+            BudgetKind::Duration => "duration",
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl Budget {
+    /// The limits that are set, with their kind.
+    pub(crate) fn limits(&self) -> [(BudgetKind, Option<u64>); 4] {
+        [
+            (BudgetKind::Pages, self.max_pages),
+            (BudgetKind::Resources, self.max_resources),
+            (BudgetKind::Bytes, self.max_bytes),
+            (BudgetKind::Duration, self.max_duration),
+        ]
+    }
+
+    /// The budget that doesn't allow one more page, or resource, to be
+    /// requested. `requested` is the number already requested.
+    pub(crate) fn exhausted(
+        &self,
+        is_page: bool,
+        requested: u64,
+        stats: &SessionStats,
+    ) -> Option<BudgetKind> {
+        let reached = |limit: Option<u64>, value: u64| limit.is_some_and(|limit| value >= limit);
+        let elapsed = Duration::from_millis(stats.elapsed_ms);
+        if self.time_left(elapsed) == Some(Duration::ZERO) {
+            Some(BudgetKind::Duration)
+        } else if reached(self.max_bytes, stats.bytes_written) {
+            Some(BudgetKind::Bytes)
+        } else if is_page && reached(self.max_pages, requested) {
+            Some(BudgetKind::Pages)
+        } else if !is_page && reached(self.max_resources, requested) {
+            Some(BudgetKind::Resources)
+        } else {
+            None
+        }
+    }
+
+    /// Time left before the duration budget runs out, None without one.
+    pub(crate) fn time_left(&self, elapsed: Duration) -> Option<Duration> {
+        self.max_duration
+            .map(|seconds| Duration::from_secs(seconds).saturating_sub(elapsed))
+    }
+}
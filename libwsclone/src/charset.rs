@@ -0,0 +1,123 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::borrow::Cow;
+use url::Url;
+
+/// Bytes of a page looked at for a `<meta>` charset declaration, as browsers do.
+const META_PRESCAN_LEN: usize = 1024;
+
+lazy_static! {
+    /// `<meta charset="x">` or `<meta http-equiv="Content-Type" content="text/html; charset=x">`,
+    /// capturing the charset.
+    static ref META_CHARSET: Regex =
+        Regex::new(r#"(?i)<meta\b[^>]*?\bcharset\s*=\s*["']?\s*([a-z0-9_:.\-]+)"#).unwrap();
+    static ref HEAD_TAG: Regex = Regex::new(r"(?i)<head\b[^>]*>").unwrap();
+    static ref HTML_TAG: Regex = Regex::new(r"(?i)<html\b[^>]*>").unwrap();
+}
+
+/// Encoding of a downloaded page, looked for in order in it's byte order
+/// mark, the charset of it's `Content-Type` header, a `<meta>` declaration
+/// at the start of the page, and finally guessed from the content.
+pub(crate) fn detect_encoding(
+    bytes: &[u8],
+    content_type: Option<&str>,
+    url: &Url,
+) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if let Some(encoding) = content_type.and_then(header_charset) {
+        return encoding;
+    }
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(META_PRESCAN_LEN)]);
+    if let Some(encoding) = meta_charset(&start) {
+        return encoding;
+    }
+    let tld = url
+        .host_str()
+        .and_then(|host| host.rsplit('.').next())
+        .map(str::as_bytes);
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(tld, true)
+}
+
+/// Decodes `bytes`, removing the byte order mark. Malformed sequences are
+/// replaced, decoding never fails.
+pub(crate) fn decode(bytes: &[u8], encoding: &'static Encoding) -> String {
+    let (text, had_errors) = encoding.decode_with_bom_removal(bytes);
+    if had_errors {
+        tracing::debug!("Malformed {} sequences replaced", encoding.name());
+    }
+    text.into_owned()
+}
+
+/// Encodes a decoded page, with a `<meta>` declaration matching the
+/// encoding it's written in. Pages are written in `encoding`, or UTF-8 when
+/// `to_utf8` is set or `encoding` can't be written, E.g UTF-16. Characters
+/// the encoding can't represent become numeric character references.
+pub(crate) fn encode(html: &str, encoding: &'static Encoding, to_utf8: bool) -> Vec<u8> {
+    let output = if to_utf8 {
+        UTF_8
+    } else {
+        encoding.output_encoding()
+    };
+    let html = declare_charset(html, output);
+    let (bytes, _, had_unmappable) = output.encode(&html);
+    if had_unmappable {
+        tracing::debug!("Characters not in {} replaced", output.name());
+    }
+    bytes.into_owned()
+}
+
+/// Sets the charset of the first `<meta>` declaration to `encoding`, or adds
+/// one after the `<head>` (or `<html>`) tag. Local files have no
+/// `Content-Type` header, the declaration is all a browser has to go by.
+fn declare_charset<'a>(html: &'a str, encoding: &'static Encoding) -> Cow<'a, str> {
+    let name = encoding.name().to_lowercase();
+    if let Some(declared) = META_CHARSET.captures(html) {
+        let charset = declared.get(1).unwrap();
+        if Encoding::for_label(charset.as_str().as_bytes()) == Some(encoding) {
+            return Cow::Borrowed(html);
+        }
+        let mut html = html.to_string();
+        html.replace_range(charset.range(), &name);
+        return Cow::Owned(html);
+    }
+    match HEAD_TAG.find(html).or_else(|| HTML_TAG.find(html)) {
+        Some(tag) => {
+            let mut html = html.to_string();
+            html.insert_str(tag.end(), &format!(r#"<meta charset="{name}">"#));
+            Cow::Owned(html)
+        }
+        None => Cow::Borrowed(html),
+    }
+}
+
+/// E.g `text/html; charset="Shift_JIS"`
+fn header_charset(content_type: &str) -> Option<&'static Encoding> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("charset") {
+            return None;
+        }
+        Encoding::for_label(
+            value
+                .trim()
+                .trim_matches(|c| c == '"' || c == '\'')
+                .as_bytes(),
+        )
+    })
+}
+
+fn meta_charset(html: &str) -> Option<&'static Encoding> {
+    let label = META_CHARSET.captures(html)?.get(1)?.as_str();
+    match Encoding::for_label(label.as_bytes())? {
+        // A page that can be read as ASCII to find the declaration isn't UTF-16
+        encoding if encoding == UTF_16BE || encoding == UTF_16LE => Some(UTF_8),
+        encoding if encoding == X_USER_DEFINED => Some(WINDOWS_1252),
+        encoding => Some(encoding),
+    }
+}
@@ -0,0 +1,204 @@
+use brotli_decompressor::DecompressorWriter;
+use flate2::write::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use reqwest::header::{HeaderMap, CONTENT_ENCODING};
+use std::io::Write;
+
+/// Content codings the default fetcher asks for, all decoded by [`BodyDecoder`]
+pub(crate) const ACCEPT_ENCODING: &str = "gzip, deflate, br";
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+enum Decoder {
+    Gzip(GzDecoder<Vec<u8>>),
+    /// `deflate` is meant to be zlib wrapped, some servers send raw deflate.
+    /// Decided on the first two bytes, held until both arrived.
+    Deflate(Vec<u8>),
+    Zlib(ZlibDecoder<Vec<u8>>),
+    RawDeflate(DeflateDecoder<Vec<u8>>),
+    Brotli(Box<DecompressorWriter<Vec<u8>>>),
+}
+
+impl Decoder {
+    fn write(&mut self, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        if let Decoder::Deflate(start) = self {
+            start.extend_from_slice(input);
+            if start.len() < 2 {
+                return Ok(Vec::new());
+            }
+            let start = std::mem::take(start);
+            *self = if is_zlib_header(&start) {
+                Decoder::Zlib(ZlibDecoder::new(Vec::new()))
+            } else {
+                Decoder::RawDeflate(DeflateDecoder::new(Vec::new()))
+            };
+            return self.write(&start);
+        }
+        let output = match self {
+            Decoder::Gzip(decoder) => decoder.write_all(input).map(|_| decoder.get_mut()),
+            Decoder::Deflate(_) => unreachable!("deflate decoder picked above"),
+            Decoder::Zlib(decoder) => decoder.write_all(input).map(|_| decoder.get_mut()),
+            Decoder::RawDeflate(decoder) => decoder.write_all(input).map(|_| decoder.get_mut()),
+            Decoder::Brotli(decoder) => decoder.write_all(input).map(|_| decoder.get_mut()),
+        }?;
+        Ok(std::mem::take(output))
+    }
+
+    /// Decodes `input` and what is left, failing if the stream was cut short.
+    fn finish(mut self, input: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut output = self.write(input)?;
+        if let Decoder::Deflate(start) = &mut self {
+            // No bytes at all
+            if start.is_empty() {
+                return Ok(output);
+            }
+            // A single byte, too short for a zlib header
+            let start = std::mem::take(start);
+            self = Decoder::RawDeflate(DeflateDecoder::new(Vec::new()));
+            output.extend(self.write(&start)?);
+        }
+        let rest = match &mut self {
+            Decoder::Gzip(decoder) => decoder.try_finish().map(|_| decoder.get_mut()),
+            Decoder::Deflate(_) => unreachable!("deflate decoder picked above"),
+            Decoder::Zlib(decoder) => decoder.try_finish().map(|_| decoder.get_mut()),
+            Decoder::RawDeflate(decoder) => decoder.try_finish().map(|_| decoder.get_mut()),
+            Decoder::Brotli(decoder) => decoder.close().map(|_| decoder.get_mut()),
+        }?;
+        output.append(rest);
+        Ok(output)
+    }
+}
+
+/// A zlib stream starts with the deflate method and a header checksum.
+fn is_zlib_header(bytes: &[u8]) -> bool {
+    match bytes {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => true,
+    }
+}
+
+/// Decodes a body as it streams, as given by it's `Content-Encoding` header.
+/// Bodies with no encoding, or `identity`, are passed through.
+pub(crate) struct BodyDecoder {
+    /// In the order they are applied, the reverse of the header's
+    decoders: Vec<Decoder>,
+}
+
+impl BodyDecoder {
+    /// Fails on an encoding that can't be decoded, E.g `zstd`.
+    pub(crate) fn new(headers: &HeaderMap) -> Result<Self, String> {
+        let mut decoders = Vec::new();
+        for value in headers.get_all(CONTENT_ENCODING) {
+            let value = value.to_str().map_err(|e| e.to_string())?;
+            for coding in value.split(',').map(|coding| coding.trim().to_lowercase()) {
+                let decoder = match coding.as_str() {
+                    "" | "identity" => continue,
+                    "gzip" | "x-gzip" => Decoder::Gzip(GzDecoder::new(Vec::new())),
+                    "deflate" => Decoder::Deflate(Vec::new()),
+                    "br" => Decoder::Brotli(Box::new(DecompressorWriter::new(
+                        Vec::new(),
+                        BROTLI_BUFFER_SIZE,
+                    ))),
+                    _ => return Err(format!("unsupported content encoding \"{coding}\"")),
+                };
+                decoders.push(decoder);
+            }
+        }
+        decoders.reverse();
+        Ok(BodyDecoder { decoders })
+    }
+
+    /// Whether the body is compressed, it's decoded size isn't known until it's decoded.
+    pub(crate) fn is_encoded(&self) -> bool {
+        !self.decoders.is_empty()
+    }
+
+    /// Decodes a chunk of the body. Part of it can be held back until the next chunks.
+    pub(crate) fn decode(&mut self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        let mut data = chunk.to_vec();
+        for decoder in &mut self.decoders {
+            data = decoder.write(&data).map_err(|e| e.to_string())?;
+        }
+        Ok(data)
+    }
+
+    /// What is left of the body, once all the chunks were decoded.
+    pub(crate) fn finish(self) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        for decoder in self.decoders {
+            data = decoder.finish(&data).map_err(|e| e.to_string())?;
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::{DeflateEncoder, GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+    use reqwest::header::HeaderValue;
+
+    const TEXT: &[u8] = b"hello brotli";
+
+    fn decoder(encoding: &str) -> Result<BodyDecoder, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_str(encoding).unwrap());
+        BodyDecoder::new(&headers)
+    }
+
+    /// Decodes `body` one byte at a time, as if it was streamed.
+    fn decode(encoding: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut decoder = decoder(encoding)?;
+        let mut decoded = Vec::new();
+        for byte in body {
+            decoded.extend(decoder.decode(&[*byte])?);
+        }
+        decoded.extend(decoder.finish()?);
+        Ok(decoded)
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// `TEXT` as a brotli stream with a single uncompressed meta-block
+    fn brotli() -> Vec<u8> {
+        let mut body = vec![0xb0, 0x00, 0x10];
+        body.extend_from_slice(TEXT);
+        body.push(0x03);
+        body
+    }
+
+    #[test]
+    fn bodies_are_decoded() {
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(TEXT).unwrap();
+        let mut raw_deflate = DeflateEncoder::new(Vec::new(), Compression::default());
+        raw_deflate.write_all(TEXT).unwrap();
+
+        assert_eq!(decode("gzip", &gzip(TEXT)).unwrap(), TEXT);
+        assert_eq!(decode("X-GZIP", &gzip(TEXT)).unwrap(), TEXT);
+        assert_eq!(decode("deflate", &zlib.finish().unwrap()).unwrap(), TEXT);
+        assert_eq!(
+            decode("deflate", &raw_deflate.finish().unwrap()).unwrap(),
+            TEXT
+        );
+        assert_eq!(decode("br", &brotli()).unwrap(), TEXT);
+        assert_eq!(decode("identity", TEXT).unwrap(), TEXT);
+        assert!(!decoder("identity").unwrap().is_encoded());
+    }
+
+    #[test]
+    fn stacked_encodings_are_decoded_in_reverse() {
+        assert_eq!(decode("br, gzip", &gzip(&brotli())).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn bad_bodies_are_errors() {
+        let gzipped = gzip(TEXT);
+        assert!(decode("gzip", &gzipped[..gzipped.len() - 4]).is_err());
+        assert!(decode("gzip", TEXT).is_err());
+        assert!(decoder("zstd").is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use encoding_rs::Encoding;
+use std::fmt::Formatter;
+
+/// Why a `Content-Disposition` header couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ContentDispositionError {
+    Empty,
+    /// Parameter is the disposition type
+    InvalidType(String),
+    /// Parameter is the text where a `name=value` parameter was expected
+    InvalidParameter(String),
+    /// Parameter is the name of the parameter
+    UnterminatedQuote(String),
+    /// Parameter is the `charset'language'value` of an extended parameter
+    InvalidExtendedValue(String),
+    UnsupportedCharset(String),
+}
+
+impl std::fmt::Display for ContentDispositionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            ContentDispositionError::Empty => "header is empty".to_string(),
+            ContentDispositionError::InvalidType(disposition) => {
+                format!("invalid disposition type \"{disposition}\"")
+            }
+            ContentDispositionError::InvalidParameter(parameter) => {
+                format!("invalid parameter \"{parameter}\"")
+            }
+            ContentDispositionError::UnterminatedQuote(name) => {
+                format!("quoted value of {name} isn't terminated")
+            }
+            ContentDispositionError::InvalidExtendedValue(value) => {
+                format!("invalid extended value \"{value}\"")
+            }
+            ContentDispositionError::UnsupportedCharset(charset) => {
+                format!("unsupported charset \"{charset}\"")
+            }
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl std::error::Error for ContentDispositionError {}
+
+/// The file name of a `Content-Disposition` header, as in RFC 6266. None
+/// when it has none, E.g `inline`.
+///
+/// `filename*`, whose value is percent encoded in a charset as in RFC 5987,
+/// is preferred to `filename`. If it can't be decoded `filename` is used
+/// instead, when there is one. Only the last segment of a path is kept.
+///
+/// Headers are read the way browsers do when they slightly differ from the
+/// grammar: a missing disposition type, an unquoted value with spaces, a
+/// quoted extended value. Parameters repeated after the first are ignored.
+pub(crate) fn file_name(header: &[u8]) -> Result<Option<String>, ContentDispositionError> {
+    // Some servers send UTF-8 names as they are, anything else is Latin-1
+    let header = match std::str::from_utf8(header) {
+        Ok(header) => header.to_string(),
+        Err(_) => header.iter().map(|byte| char::from(*byte)).collect(),
+    };
+    let parameters = parameters(&header)?;
+    let parameter = |name: &str| {
+        parameters
+            .iter()
+            .find(|(parameter, _)| parameter == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let name = match (parameter("filename*"), parameter("filename")) {
+        (Some(extended), fallback) => match decode_extended_value(extended) {
+            Ok(name) => name,
+            Err(e) => match fallback {
+                Some(fallback) => {
+                    tracing::debug!("Using filename of Content-Disposition. {e}");
+                    fallback.to_string()
+                }
+                None => return Err(e),
+            },
+        },
+        (None, Some(name)) => name.to_string(),
+        (None, None) => return Ok(None),
+    };
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default().trim();
+    Ok(Some(name.to_string()).filter(|name| !name.is_empty()))
+}
+
+/// The names, lower cased, and values, unchanged, of the parameters after
+/// the disposition type.
+fn parameters(header: &str) -> Result<Vec<(String, String)>, ContentDispositionError> {
+    let header = header.trim();
+    if header.is_empty() {
+        return Err(ContentDispositionError::Empty);
+    }
+    let mut rest = header;
+    let type_end = rest.find(';').unwrap_or(rest.len());
+    let disposition = rest[..type_end].trim();
+    // Without a type the header starts with a parameter
+    if !disposition.contains('=') {
+        if !is_token(disposition) {
+            return Err(ContentDispositionError::InvalidType(
+                disposition.to_string(),
+            ));
+        }
+        rest = &rest[type_end..];
+    }
+
+    let mut parameters: Vec<(String, String)> = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+        if rest.is_empty() {
+            return Ok(parameters);
+        }
+        let name_end = rest.find(['=', ';']).unwrap_or(rest.len());
+        let name = rest[..name_end].trim().to_lowercase();
+        if !rest[name_end..].starts_with('=') || !is_token(&name) {
+            return Err(ContentDispositionError::InvalidParameter(
+                rest[..name_end].trim().to_string(),
+            ));
+        }
+        rest = rest[name_end + 1..].trim_start();
+        let value = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, length) = unquote(quoted)
+                    .ok_or_else(|| ContentDispositionError::UnterminatedQuote(name.clone()))?;
+                // Anything between the closing quote and the next parameter is ignored
+                rest = &quoted[length..];
+                rest = rest.find(';').map_or("", |idx| &rest[idx..]);
+                value
+            }
+            None => {
+                let value_end = rest.find(';').unwrap_or(rest.len());
+                let value = rest[..value_end].trim().to_string();
+                rest = &rest[value_end..];
+                value
+            }
+        };
+        if parameters.iter().all(|(parameter, _)| *parameter != name) {
+            parameters.push((name, value));
+        }
+    }
+}
+
+/// The value of a quoted string, after it's opening quote, and the length
+/// up to and with it's closing quote. None if it isn't terminated.
+fn unquote(quoted: &str) -> Option<(String, usize)> {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, idx + 1)),
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+fn is_token(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c))
+}
+
+/// Decodes a `charset'language'percent-encoded` value. RFC 5987 requires
+/// UTF-8 and ISO-8859-1, other charsets are decoded when they are known.
+fn decode_extended_value(value: &str) -> Result<String, ContentDispositionError> {
+    let invalid = || ContentDispositionError::InvalidExtendedValue(value.to_string());
+    let mut parts = value.splitn(3, '\'');
+    let (charset, encoded) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(charset), Some(_language), Some(encoded)) => (charset.trim(), encoded),
+        _ => return Err(invalid()),
+    };
+    let encoding = Encoding::for_label(charset.as_bytes())
+        .ok_or_else(|| ContentDispositionError::UnsupportedCharset(charset.to_string()))?;
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut encoded_bytes = encoded.bytes();
+    while let Some(byte) = encoded_bytes.next() {
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = [encoded_bytes.next(), encoded_bytes.next()];
+        let decoded = match hex {
+            [Some(high), Some(low)] => std::str::from_utf8(&[high, low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        bytes.push(decoded.ok_or_else(invalid)?);
+    }
+    encoding
+        .decode_without_bom_handling_and_without_replacement(&bytes)
+        .map(|name| name.into_owned())
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ContentDispositionError::*;
+
+    /// A header and it's file name, or why it has none
+    type Case = (
+        &'static [u8],
+        Result<Option<&'static str>, ContentDispositionError>,
+    );
+
+    #[test]
+    fn file_names_of_real_world_headers() {
+        let cases: Vec<Case> = vec![
+            (b"attachment; filename=\"report.pdf\"", Ok(Some("report.pdf"))),
+            (b"attachment; filename=report.pdf", Ok(Some("report.pdf"))),
+            (b"attachment;filename=\"no-space.pdf\"", Ok(Some("no-space.pdf"))),
+            (b"attachment; filename = \"spaced.pdf\"", Ok(Some("spaced.pdf"))),
+            (b"ATTACHMENT; FILENAME=\"Upper.PDF\"", Ok(Some("Upper.PDF"))),
+            (b"inline; filename=\"a;b.pdf\"", Ok(Some("a;b.pdf"))),
+            (
+                b"attachment; filename=\"report.pdf\"; size=1234; creation-date=\"Wed, 12 Feb 1997 16:29:51 -0500\"",
+                Ok(Some("report.pdf")),
+            ),
+            (b"attachment; size=1234; filename=last.pdf", Ok(Some("last.pdf"))),
+            (b"attachment; filename=\"trailing.pdf\";", Ok(Some("trailing.pdf"))),
+            (b"attachment; filename=\"\\\"quoted\\\".txt\"", Ok(Some("\"quoted\".txt"))),
+            (b"attachment; filename=my file.pdf", Ok(Some("my file.pdf"))),
+            (b"filename=\"no-type.pdf\"", Ok(Some("no-type.pdf"))),
+            (
+                b"attachment; filename*=UTF-8''%E2%82%AC%20rates.pdf",
+                Ok(Some("\u{20ac} rates.pdf")),
+            ),
+            (
+                b"attachment; filename=\"EURO rates.pdf\"; filename*=utf-8''%e2%82%ac%20rates.pdf",
+                Ok(Some("\u{20ac} rates.pdf")),
+            ),
+            (
+                b"attachment; filename*=UTF-8''foo.pdf; filename=\"bar.pdf\"",
+                Ok(Some("foo.pdf")),
+            ),
+            (
+                b"attachment; filename*=iso-8859-1'en'%A3%20rates.pdf",
+                Ok(Some("\u{a3} rates.pdf")),
+            ),
+            (b"attachment; filename*=windows-1252''%80.txt", Ok(Some("\u{20ac}.txt"))),
+            (
+                b"attachment; filename*=UTF-8''%E4%B8%AD%E6%96%87.txt",
+                Ok(Some("\u{4e2d}\u{6587}.txt")),
+            ),
+            (b"attachment; filename*=\"UTF-8''quoted-ext.pdf\"", Ok(Some("quoted-ext.pdf"))),
+            (
+                b"attachment; filename=\"fallback.pdf\"; filename*=UTF-8''%ZZ.pdf",
+                Ok(Some("fallback.pdf")),
+            ),
+            (
+                b"attachment; filename=\"fallback.pdf\"; filename*=x-unknown''a.pdf",
+                Ok(Some("fallback.pdf")),
+            ),
+            (b"attachment; filename=\"first.html\"; filename=\"second.html\"", Ok(Some("first.html"))),
+            (b"attachment; filename=\"../../etc/passwd\"", Ok(Some("passwd"))),
+            (b"attachment; filename=\"C:\\\\Users\\\\evil.exe\"", Ok(Some("evil.exe"))),
+            (b"attachment; filename=%E2%82%AC.pdf", Ok(Some("%E2%82%AC.pdf"))),
+            (b"attachment; filename=\"caf\xc3\xa9.txt\"", Ok(Some("caf\u{e9}.txt"))),
+            (b"attachment; filename=\"caf\xe9.txt\"", Ok(Some("caf\u{e9}.txt"))),
+            (b"attachment", Ok(None)),
+            (b"inline", Ok(None)),
+            (b"attachment; filename=\"\"", Ok(None)),
+            (b"attachment; filename=\"/\"", Ok(None)),
+            (b"", Err(Empty)),
+            (b"  ", Err(Empty)),
+            (b"attach ment; filename=a.pdf", Err(InvalidType("attach ment".to_string()))),
+            (b"attachment; filename", Err(InvalidParameter("filename".to_string()))),
+            (b"attachment; =a.pdf", Err(InvalidParameter("".to_string()))),
+            (
+                b"attachment; filename=\"unterminated.pdf",
+                Err(UnterminatedQuote("filename".to_string())),
+            ),
+            (
+                b"attachment; filename*=UTF-8''%E2%82",
+                Err(InvalidExtendedValue("UTF-8''%E2%82".to_string())),
+            ),
+            (
+                b"attachment; filename*=no-quotes.pdf",
+                Err(InvalidExtendedValue("no-quotes.pdf".to_string())),
+            ),
+            (
+                b"attachment; filename*=x-unknown''a.pdf",
+                Err(UnsupportedCharset("x-unknown".to_string())),
+            ),
+        ];
+        for (header, expected) in cases {
+            let expected = expected.map(|name| name.map(str::to_string));
+            assert_eq!(
+                file_name(header),
+                expected,
+                "{}",
+                String::from_utf8_lossy(header)
+            );
+        }
+    }
+}
@@ -1,41 +1,108 @@
+use crate::budget::BudgetKind;
+use crate::compression::BodyDecoder;
+use crate::content_disposition;
 use crate::errors::WscError;
-use crate::Update::{MessageUpdate, ProgressUpdate};
-use crate::{DownloadRule, Message, Progress, Update};
+use crate::event::{EventKind, EventSink, SkipReason};
+use crate::fetch::{BodyStream, FetchResponse};
+use crate::mime;
+use crate::sanitize::sanitize_file_name;
+use crate::session::Outcome;
+use crate::{exhaust_budget, skip_reason, DownloadProp};
 use chrono::Utc;
+use futures::StreamExt;
+use percent_encoding::percent_decode_str;
+use reqwest::header;
 use reqwest::header::HeaderMap;
-use reqwest::{header, Client};
 
-use phf::phf_map;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::mem::take;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::error::TrySendError;
-use tokio::sync::mpsc::Sender;
-use tokio::time::Instant;
+use tokio::time::{sleep, timeout, Instant};
 use url::Url;
 
+/// Maximum number of redirects followed for a single item, same as reqwest's default policy.
+pub(crate) const MAX_REDIRECTS: usize = 10;
+
 #[derive(Debug)]
 pub struct DownloadItem {
     pub link: Url,
     pub destination_dir: PathBuf,
+    /// Level of the page this item is, or belongs to
+    pub depth: u8,
+    pub is_page: bool,
 }
 
-/// Takes care of downloading a file. The returned optional string is the path to the downloaded file
+/// What happened to an item that didn't abort the session.
+#[derive(Debug)]
+pub struct DownloadResult {
+    /// Url the item was finally served from, after redirects
+    pub final_url: Url,
+    /// Urls that redirected to the final url, starting with the requested url
+    pub redirects: Vec<Url>,
+    /// None when the final url was already downloaded in the session and
+    /// it's local copy is reused
+    pub outcome: Option<Outcome>,
+    /// `Content-Type` header of the response, when the file was downloaded
+    pub content_type: Option<String>,
+    /// Type of the file from it's header, content and url extension. None
+    /// when it couldn't be found.
+    pub mime_type: Option<String>,
+}
+
+impl DownloadResult {
+    fn new(final_url: Url, outcome: Outcome) -> Self {
+        DownloadResult {
+            final_url,
+            redirects: Vec::new(),
+            outcome: Some(outcome),
+            content_type: None,
+            mime_type: None,
+        }
+    }
+
+    fn redirected(final_url: Url, redirects: Vec<Url>, outcome: Option<Outcome>) -> Self {
+        DownloadResult {
+            final_url,
+            redirects,
+            outcome,
+            content_type: None,
+            mime_type: None,
+        }
+    }
+}
+
+/// Takes care of downloading a file. Errors are returned only when the session should be aborted.
 #[tracing::instrument]
 pub async fn download_file(
-    session_id: String,
     mut dld_item: DownloadItem,
-    client: &Arc<Client>,
-    rule: &DownloadRule,
-    update_tx: Sender<Update>,
-    file_name: Option<String>,
-) -> Result<Option<String>, WscError> {
+    prop: &DownloadProp,
+) -> Result<DownloadResult, WscError> {
+    let DownloadProp {
+        rule,
+        events,
+        file_name,
+        ..
+    } = prop;
+    let url = dld_item.link.clone();
+    let depth = dld_item.depth;
     let link_str = dld_item.link.to_string();
     for link in rule.black_list_urls.iter() {
         if link_str.contains(link) {
-            return Ok(None);
+            let reason = SkipReason::BlackListed {
+                pattern: link.clone(),
+            };
+            events
+                .emit(
+                    Some(&url),
+                    depth,
+                    EventKind::ResourceSkipped {
+                        reason: reason.clone(),
+                    },
+                )
+                .await;
+            return Ok(DownloadResult::new(url, Outcome::Skipped { reason }));
         }
     }
 
@@ -46,66 +113,146 @@ pub async fn download_file(
         ));
     }
 
-    let mut response = match client.get(dld_item.link.to_string()).send().await {
-        Err(e) => {
-            tracing::error!(
-                msg = "Error downloading file from server.",
-                url = dld_item.link.to_string(),
-                error_msg = e.to_string(),
-            );
-            // File name is always passed for first page.
-            // If it's the first page, then we want to
-            // abort the whole download irrespective of
-            // the abort rule. Otherwise follow the rule.
-            if !rule.abort_on_download_error || file_name.is_some() {
-                return Ok(None);
-            }
-            return Err(WscError::NetworkError(dld_item.link.to_string()));
+    let mut redirects: Vec<Url> = Vec::new();
+    let mut current = url.clone();
+    let mut response = loop {
+        if redirects.len() > MAX_REDIRECTS {
+            let error = WscError::TooManyRedirects(url.to_string());
+            return failed(error, &url, current, redirects, depth, prop).await;
         }
-        Ok(r) => {
-            if !r.status().is_success() {
+        let response = match fetch_with_retries(&current, depth, prop).await {
+            Err(e) => {
                 tracing::error!(
-                    msg = "Invalid status code received",
-                    status_code = r.status().to_string(),
-                    url = dld_item.link.to_string()
+                    msg = "Error downloading file from server.",
+                    url = current.to_string(),
+                    error_msg = e.to_string(),
                 );
-                return if rule.abort_on_download_error {
-                    Err(WscError::ErrorStatusCode {
-                        status_code: r.status().to_string(),
-                        url: dld_item.link.to_string(),
-                    })
-                } else {
-                    if (update_tx
-                        .send(MessageUpdate(Message {
-                            session_id: session_id.clone(),
-                            resource_name: dld_item.link.to_string(),
-                            is_error: true,
-                            content: "Error downloading resource".into(),
-                        }))
-                        .await)
-                        .is_err()
-                    {};
-                    Ok(None)
+                let error = match e {
+                    WscError::ProxyError(_) => e,
+                    _ => WscError::NetworkError(current.to_string()),
                 };
+                return failed(error, &url, current, redirects, depth, prop).await;
+            }
+            Ok(r) => r,
+        };
+        // Fetchers following redirects by themselves only report the final url
+        if response.url != current {
+            let served_from = response.url.clone();
+            match follow_redirect(&mut redirects, current, &served_from, &dld_item, prop).await {
+                Ok(next) => current = next,
+                Err(result) => return Ok(result),
             }
-            r
+        }
+        let location = response
+            .headers
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| current.join(l).ok());
+        match location {
+            Some(next) if response.status.is_redirection() => {
+                match follow_redirect(&mut redirects, current, &next, &dld_item, prop).await {
+                    Ok(next) => current = next,
+                    Err(result) => return Ok(result),
+                }
+            }
+            _ => break response,
         }
     };
 
-    let headers = response.headers();
-    let f_name: String;
-    if file_name.is_none() {
-        let f_ext = get_file_extension(&dld_item, headers);
-        tracing::debug!(
-            "File extension for {} is {}",
-            dld_item.link.to_string(),
-            f_ext
+    if !response.status.is_success() {
+        tracing::error!(
+            msg = "Invalid status code received",
+            status_code = response.status.to_string(),
+            url = current.to_string()
         );
+        let error = WscError::ErrorStatusCode {
+            status_code: response.status.to_string(),
+            url: current.to_string(),
+        };
+        return failed(error, &url, current, redirects, depth, prop).await;
+    }
 
-        f_name = get_file_name(&dld_item, headers, f_ext);
+    // The file is named after the url it was served from
+    dld_item.link = current.clone();
+    let final_url = current;
+    let headers = &response.headers;
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .map(str::to_string);
+    let decoder = match BodyDecoder::new(headers) {
+        Ok(decoder) => decoder,
+        Err(message) => {
+            let error = WscError::ContentDecodingError {
+                url: final_url.to_string(),
+                message,
+            };
+            return failed(error, &url, final_url, redirects, depth, prop).await;
+        }
+    };
+
+    let content_length = match headers.get(header::CONTENT_LENGTH) {
+        None => 0u64,
+        Some(s) => s
+            .to_str()
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0u64),
+    };
+    // The length of a compressed body isn't it's size once decoded, which
+    // is only checked as it streams
+    let f_size = if decoder.is_encoded() {
+        0
+    } else {
+        content_length
+    };
+
+    if (f_size > 0 && f_size > rule.max_static_file_size)
+        || (content_length == 0 && !rule.download_static_resource_with_unknown_size)
+    {
+        let reason = if content_length == 0 {
+            SkipReason::UnknownSize
+        } else {
+            SkipReason::TooLarge {
+                size: f_size,
+                limit: rule.max_static_file_size,
+            }
+        };
+        events
+            .emit(
+                Some(&url),
+                depth,
+                EventKind::ResourceSkipped {
+                    reason: reason.clone(),
+                },
+            )
+            .await;
+        return Ok(DownloadResult::redirected(
+            final_url,
+            redirects,
+            Some(Outcome::Skipped { reason }),
+        ));
+    }
+
+    // The first bytes tell the type of the file, which it's named after
+    let mut decoder = Some(decoder);
+    let first_chunk = match next_chunk(&mut response.body, &mut decoder, &final_url).await {
+        Ok(chunk) => chunk,
+        Err(e) => return body_failed(e, &url, final_url, redirects, depth, prop).await,
+    };
+    let mime_type = mime::resolve(
+        content_type.as_deref(),
+        &dld_item.link,
+        first_chunk.as_deref().unwrap_or_default(),
+    );
+    tracing::debug!("Mime type of {} is {:?}", dld_item.link, mime_type);
+
+    let f_name: String;
+    if file_name.is_none() {
+        f_name = get_file_name(&dld_item, headers, mime_type.as_deref());
         tracing::debug!("File name for {} is {}", dld_item.link.to_string(), &f_name);
     } else {
-        f_name = file_name.unwrap();
+        f_name = file_name.clone().unwrap();
     }
 
     dld_item.destination_dir.push(&f_name);
@@ -113,6 +260,7 @@ pub async fn download_file(
     let mut dest_file = match OpenOptions::new()
         .write(true)
         .create(true)
+        .truncate(false)
         .open(dld_item.destination_dir.as_path())
         .await
     {
@@ -123,35 +271,24 @@ pub async fn download_file(
                 error_msg = e.to_string(),
                 error_kind = e.kind().to_string()
             );
-            if (update_tx
-                .send(MessageUpdate(Message {
-                    session_id,
-                    resource_name: f_name,
-                    is_error: true,
-                    content: "Error opening destination file".into(),
-                }))
-                .await)
-                .is_err()
-            {};
-            return Err(WscError::FileOperationError {
+            let error = WscError::FileOperationError {
                 file_name: dld_item.destination_dir.to_string_lossy().to_string(),
                 message: format!("{} | {}", e, e.kind()),
-            });
+            };
+            events
+                .emit(
+                    Some(&url),
+                    depth,
+                    EventKind::ResourceFailed {
+                        error: error.clone(),
+                    },
+                )
+                .await;
+            return Err(error);
         }
         Ok(f) => f,
     };
 
-    let f_size = match headers.get(header::CONTENT_LENGTH) {
-        None => 0u64,
-        Some(s) => s.to_str().unwrap().parse::<u64>().unwrap_or(0u64),
-    };
-
-    if (f_size > 0 && f_size > rule.max_static_file_size)
-        || (f_size == 0 && !rule.download_static_resource_with_unknown_size)
-    {
-        return Ok(None);
-    }
-
     let dest_f_size = match dest_file.metadata().await {
         Ok(m) => m.len(),
         Err(e) => {
@@ -165,109 +302,113 @@ pub async fn download_file(
         }
     };
 
-    if dest_f_size > 0 && f_size != 0 && dest_file.metadata().await.unwrap().len() >= f_size {
+    if dest_f_size > 0 && f_size != 0 && dest_f_size >= f_size {
         let f_path = dld_item.destination_dir.to_string_lossy().to_string();
         tracing::debug!(
             "File : |{}| from |{}| has already been downloaded.",
             f_name,
             dld_item.link.to_string()
         );
-        if (update_tx
-            .send(ProgressUpdate(Progress {
-                bytes_written: f_size,
-                file_size: f_size,
-                resource_name: f_name.clone(),
-                session_id,
-            }))
-            .await)
-            .is_err()
-        {};
-        return Ok(Some(f_path));
+        events
+            .emit(
+                Some(&url),
+                depth,
+                EventKind::ResourceCompleted {
+                    resource_name: f_name,
+                    file_path: f_path.clone(),
+                    bytes_written: f_size,
+                    is_page: dld_item.is_page,
+                    cached: true,
+                },
+            )
+            .await;
+        return Ok(DownloadResult {
+            content_type,
+            mime_type,
+            ..DownloadResult::redirected(
+                final_url,
+                redirects,
+                Some(Outcome::Cached { file_path: f_path }),
+            )
+        });
     }
 
+    // The file may hold an older, longer copy
+    if let Err(e) = dest_file.set_len(0).await {
+        return Err(write_failed(e, &dld_item.destination_dir, &url, depth, prop).await);
+    }
+    let file_size = if f_size == 0 { None } else { Some(f_size) };
+    events
+        .emit(
+            Some(&url),
+            depth,
+            EventKind::ResourceStarted {
+                resource_name: f_name.clone(),
+                file_size,
+                is_page: dld_item.is_page,
+            },
+        )
+        .await;
+
     let progress_update_interval = Duration::from_millis(rule.progress_update_interval);
     let mut last_update_time = Instant::now() - progress_update_interval;
     let mut bytes_written = 0;
 
-    while let Some(chunks) = match response.chunk().await {
-        Err(e) => {
-            tracing::error!(
-                msg = "Error downloading file from server",
-                url = dld_item.link.to_string(),
-                error_msg = e.to_string()
-            );
-            if e.is_connect() {
-                if (update_tx
-                    .send(MessageUpdate(Message {
-                        session_id,
-                        resource_name: f_name,
-                        is_error: true,
-                        content: "Network error".into(),
-                    }))
-                    .await)
-                    .is_err()
-                {};
-                return Err(WscError::NetworkError(e.to_string()));
-            } else if e.is_status() {
-                let status = e.status().unwrap();
-                if (update_tx
-                    .send(MessageUpdate(Message {
-                        session_id,
-                        resource_name: f_name,
-                        is_error: true,
-                        content: format!("Error status code : {status}"),
-                    }))
-                    .await)
-                    .is_err()
-                {};
-                if rule.abort_on_download_error {
-                    return Err(WscError::ErrorStatusCode {
-                        status_code: status.to_string(),
-                        url: dld_item.link.to_string(),
-                    });
-                }
-            }
-            return Ok(None);
+    let mut chunk = first_chunk;
+    while let Some(chunks) = chunk {
+        if rule.budget.time_left(events.elapsed()) == Some(Duration::ZERO) {
+            tracing::debug!("Duration budget ran out downloading {}", dld_item.link);
+            exhaust_budget(BudgetKind::Duration, prop).await;
+            let reason = SkipReason::BudgetExhausted {
+                budget: BudgetKind::Duration,
+            };
+            return Ok(stopped(reason, &dld_item, final_url, redirects, events).await);
+        }
+        if bytes_written + chunks.len() as u64 > rule.max_static_file_size {
+            tracing::debug!("{} is larger than the limit once decoded", dld_item.link);
+            let reason = SkipReason::TooLarge {
+                size: bytes_written + chunks.len() as u64,
+                limit: rule.max_static_file_size,
+            };
+            return Ok(stopped(reason, &dld_item, final_url, redirects, events).await);
         }
-        Ok(bytes) => bytes,
-    } {
         if let Err(e) = dest_file.write_all(&chunks).await {
-            tracing::error!(
-                "Error writing to destination file {}\nError : {} | {}",
-                dld_item.destination_dir.to_str().unwrap(),
-                e,
-                e.kind()
-            );
-            if (update_tx
-                .send(MessageUpdate(Message {
-                    session_id,
-                    resource_name: f_name,
-                    is_error: true,
-                    content: "Error writing to file".into(),
-                }))
-                .await)
-                .is_err()
-            {};
-            return Err(WscError::FileOperationError {
-                file_name: dld_item.destination_dir.to_string_lossy().to_string(),
-                message: format!("{} | {}", e, e.kind()),
-            });
+            return Err(write_failed(e, &dld_item.destination_dir, &url, depth, prop).await);
         };
-        bytes_written += chunks.len();
-        if Instant::now().duration_since(last_update_time) > progress_update_interval {
-            if let Err(e) = update_tx.try_send(ProgressUpdate(Progress {
-                bytes_written: bytes_written as u64,
-                file_size: f_size,
-                resource_name: f_name.to_owned(),
-                session_id: session_id.to_owned(),
-            })) {
-                if let TrySendError::Closed(_) = e {
-                    return Err(WscError::ChannelClosed);
-                }
-            } else {
-                last_update_time = Instant::now();
-            };
+        bytes_written += chunks.len() as u64;
+        if Instant::now().duration_since(last_update_time) > progress_update_interval
+            && events
+                .emit_progress(
+                    Some(&url),
+                    depth,
+                    EventKind::ResourceProgress {
+                        resource_name: f_name.to_owned(),
+                        bytes_written,
+                        file_size,
+                    },
+                )
+                .await?
+        {
+            last_update_time = Instant::now();
         }
+        let next = next_chunk(&mut response.body, &mut decoder, &final_url);
+        // A body that stalls past the duration budget gives an empty chunk,
+        // the budget is checked at the start of the loop.
+        let next = match rule.budget.time_left(events.elapsed()) {
+            Some(left) => timeout(left, next).await.unwrap_or(Ok(Some(Vec::new()))),
+            None => next.await,
+        };
+        chunk = match next {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                remove_partial_file(&dld_item.destination_dir).await;
+                return body_failed(e, &url, final_url, redirects, depth, prop).await;
+            }
+        };
+    }
+    // Pages are read back as soon as this returns
+    if let Err(e) = dest_file.flush().await {
+        return Err(write_failed(e, &dld_item.destination_dir, &url, depth, prop).await);
     }
     // destination_dir has been updated previously to point to the destination file
     tracing::debug!(
@@ -275,38 +416,306 @@ pub async fn download_file(
         &dld_item.link,
         dld_item.destination_dir.to_str().unwrap()
     );
-    if (update_tx
-        .send(ProgressUpdate(Progress {
-            bytes_written: bytes_written as u64,
-            file_size: if f_size == 0 {
-                bytes_written as u64
-            } else {
-                f_size
+    let f_path = dld_item.destination_dir.to_string_lossy().to_string();
+    events
+        .emit(
+            Some(&url),
+            depth,
+            EventKind::ResourceCompleted {
+                resource_name: f_name,
+                file_path: f_path.clone(),
+                bytes_written,
+                is_page: dld_item.is_page,
+                cached: false,
             },
-            resource_name: f_name,
-            session_id,
-        }))
-        .await)
-        .is_err()
-    {};
-    Ok(Some(dld_item.destination_dir.to_string_lossy().to_string()))
+        )
+        .await;
+    Ok(DownloadResult {
+        content_type,
+        mime_type,
+        ..DownloadResult::redirected(
+            final_url,
+            redirects,
+            Some(Outcome::Downloaded {
+                file_path: f_path,
+                bytes: bytes_written,
+            }),
+        )
+    })
 }
 
-#[tracing::instrument]
-fn get_file_name(dld_item: &DownloadItem, headers: &HeaderMap, f_ext: &str) -> String {
-    let mut file_name = dld_item.link.to_string();
-    if file_name.contains('?') {
-        file_name = file_name[0..file_name.find('?').unwrap()].parse().unwrap();
+/// The next decoded chunk of the body, skipping what the decoder held back.
+/// None once the body ended and the rest of the decoder was returned.
+async fn next_chunk(
+    body: &mut BodyStream,
+    decoder: &mut Option<BodyDecoder>,
+    url: &Url,
+) -> Result<Option<Vec<u8>>, WscError> {
+    let decoding_error = |message: String| WscError::ContentDecodingError {
+        url: url.to_string(),
+        message,
+    };
+    loop {
+        let body_decoder = match decoder.as_mut() {
+            Some(body_decoder) => body_decoder,
+            None => return Ok(None),
+        };
+        let decoded = match body.next().await.transpose()? {
+            Some(bytes) => body_decoder.decode(&bytes),
+            None => decoder.take().map_or(Ok(Vec::new()), BodyDecoder::finish),
+        }
+        .map_err(decoding_error)?;
+        if !decoded.is_empty() {
+            return Ok(Some(decoded));
+        }
     }
-    let slash_idx = file_name.rfind('/').unwrap();
+}
 
-    if slash_idx != file_name.len() - 1 {
-        file_name = file_name[slash_idx + 1..file_name.len()].parse().unwrap();
-    } else {
-        file_name = "".into();
+/// Removes the partial file of a download stopped for `reason`, and reports it skipped.
+async fn stopped(
+    reason: SkipReason,
+    dld_item: &DownloadItem,
+    final_url: Url,
+    redirects: Vec<Url>,
+    events: &EventSink,
+) -> DownloadResult {
+    remove_partial_file(&dld_item.destination_dir).await;
+    events
+        .emit(
+            Some(&dld_item.link),
+            dld_item.depth,
+            EventKind::ResourceSkipped {
+                reason: reason.clone(),
+            },
+        )
+        .await;
+    DownloadResult::redirected(final_url, redirects, Some(Outcome::Skipped { reason }))
+}
+
+/// Reports an error reading the body. Network and proxy errors abort the
+/// session, other errors only when the rule says to.
+async fn body_failed(
+    error: WscError,
+    url: &Url,
+    final_url: Url,
+    redirects: Vec<Url>,
+    depth: u8,
+    prop: &DownloadProp,
+) -> Result<DownloadResult, WscError> {
+    tracing::error!(
+        msg = "Error downloading file from server",
+        url = final_url.to_string(),
+        error_msg = error.to_string()
+    );
+    prop.events
+        .emit(
+            Some(url),
+            depth,
+            EventKind::ResourceFailed {
+                error: error.clone(),
+            },
+        )
+        .await;
+    match error {
+        WscError::NetworkError(_) | WscError::ProxyError(_) => return Err(error),
+        WscError::ErrorStatusCode { .. } | WscError::ContentDecodingError { .. }
+            if prop.rule.abort_on_download_error =>
+        {
+            return Err(error)
+        }
+        _ => {}
     }
+    Ok(DownloadResult::redirected(
+        final_url,
+        redirects,
+        Some(Outcome::Failed { error }),
+    ))
+}
+
+/// Reports an error writing to the destination file, which aborts the session.
+async fn write_failed(
+    e: std::io::Error,
+    destination: &Path,
+    url: &Url,
+    depth: u8,
+    prop: &DownloadProp,
+) -> WscError {
+    tracing::error!(
+        "Error writing to destination file {}\nError : {} | {}",
+        destination.to_string_lossy(),
+        e,
+        e.kind()
+    );
+    let error = WscError::FileOperationError {
+        file_name: destination.to_string_lossy().to_string(),
+        message: format!("{} | {}", e, e.kind()),
+    };
+    prop.events
+        .emit(
+            Some(url),
+            depth,
+            EventKind::ResourceFailed {
+                error: error.clone(),
+            },
+        )
+        .await;
+    error
+}
+
+/// Removes what was written of a file whose download was given up.
+async fn remove_partial_file(destination: &Path) {
+    if let Err(e) = tokio::fs::remove_file(destination).await {
+        tracing::debug!(
+            "Couldn't remove partial file {} : {}",
+            destination.to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Reports a failed download. The error is returned when the rule says to
+/// abort, otherwise it's the outcome of the final url.
+async fn failed(
+    error: WscError,
+    url: &Url,
+    final_url: Url,
+    redirects: Vec<Url>,
+    depth: u8,
+    prop: &DownloadProp,
+) -> Result<DownloadResult, WscError> {
+    prop.events
+        .emit(
+            Some(url),
+            depth,
+            EventKind::ResourceFailed {
+                error: error.clone(),
+            },
+        )
+        .await;
+    // The rule of the initial page always aborts, see `DownloadRule::for_initial_page`
+    if prop.rule.abort_on_download_error {
+        return Err(error);
+    }
+    Ok(DownloadResult::redirected(
+        final_url,
+        redirects,
+        Some(Outcome::Failed { error }),
+    ))
+}
+
+/// Records the redirect from `from` to `to`. Returns the normalized url to
+/// fetch next, or the result of the download when it stops here: the target
+/// is out of scope, filtered out, or already downloaded in the session.
+async fn follow_redirect(
+    redirects: &mut Vec<Url>,
+    from: Url,
+    to: &Url,
+    dld_item: &DownloadItem,
+    prop: &DownloadProp,
+) -> Result<Url, DownloadResult> {
+    let to = prop.rule.normalization.normalize(to);
+    tracing::debug!("{} redirected to {}", from, to);
+    redirects.push(from);
+    let requested = &dld_item.link;
+    // The initial page is always downloaded, wherever it redirects to.
+    let is_initial_page = prop.file_name.is_some();
+    if !is_initial_page {
+        if let Some(reason) = skip_reason(&to, dld_item.is_page, prop).await {
+            prop.events
+                .emit(
+                    Some(requested),
+                    dld_item.depth,
+                    EventKind::ResourceSkipped {
+                        reason: reason.clone(),
+                    },
+                )
+                .await;
+            let outcome = Some(Outcome::Skipped { reason });
+            return Err(DownloadResult::redirected(to, take(redirects), outcome));
+        }
+    }
+    let already_downloaded = {
+        let session = prop.session.read().await;
+        session
+            .local_copy(to.as_str(), dld_item.is_page)
+            .and_then(|_| Url::parse(session.final_url(to.as_str())).ok())
+    };
+    if let Some(to) = already_downloaded {
+        let reason = SkipReason::Duplicate { of: to.to_string() };
+        prop.events
+            .emit(
+                Some(requested),
+                dld_item.depth,
+                EventKind::ResourceSkipped { reason },
+            )
+            .await;
+        return Err(DownloadResult::redirected(to, take(redirects), None));
+    }
+    Ok(to)
+}
+
+/// Fetches `url`, retrying transport errors and server error responses
+/// as configured by the rule.
+async fn fetch_with_retries(
+    url: &Url,
+    depth: u8,
+    prop: &DownloadProp,
+) -> Result<FetchResponse, WscError> {
+    let rule = &prop.rule;
+    let mut attempt = 0;
+    loop {
+        let can_retry = attempt < rule.max_retries;
+        let error = match prop.fetcher.fetch(url).await {
+            Ok(r) if r.status.is_server_error() && can_retry => WscError::ErrorStatusCode {
+                status_code: r.status.to_string(),
+                url: url.to_string(),
+            },
+            Err(e) if can_retry => e,
+            result => return result,
+        };
+        attempt += 1;
+        let delay_ms = rule.retry_delay * attempt as u64;
+        tracing::debug!(
+            "Retrying {} in {}ms, attempt {}. {}",
+            url,
+            delay_ms,
+            attempt,
+            error
+        );
+        prop.events
+            .emit(
+                Some(url),
+                depth,
+                EventKind::RetryScheduled {
+                    attempt,
+                    delay_ms,
+                    error,
+                },
+            )
+            .await;
+        sleep(Duration::from_millis(delay_ms)).await;
+    }
+}
+
+#[tracing::instrument]
+fn get_file_name(dld_item: &DownloadItem, headers: &HeaderMap, mime_type: Option<&str>) -> String {
+    let f_ext = mime_type.and_then(mime::extension).unwrap_or_default();
+    // Last segment of the url path, without the query. Header names aren't percent encoded.
+    let segment = dld_item
+        .link
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default();
+    let mut file_name = percent_decode_str(segment).decode_utf8_lossy().to_string();
     if file_name.is_empty() {
-        file_name = match headers.get(header::CONTENT_DISPOSITION) {
+        let disposition = headers.get(header::CONTENT_DISPOSITION).map(|cd| {
+            content_disposition::file_name(cd.as_bytes()).unwrap_or_else(|e| {
+                tracing::warn!("Invalid Content-Disposition of {}. {}", dld_item.link, e);
+                None
+            })
+        });
+        file_name = match disposition.flatten() {
+            Some(name) => name,
             None => {
                 tracing::warn!(
                     "File name can't be determined, using generic name. {}",
@@ -314,85 +723,129 @@ fn get_file_name(dld_item: &DownloadItem, headers: &HeaderMap, f_ext: &str) -> S
                 );
                 format!("file-{time}{ext}", time = Utc::now().time(), ext = f_ext)
             }
-            Some(cd) => {
-                let mut val: String = cd.to_str().unwrap().parse().unwrap();
-                // If content disposition uses RFC_5987 style
-                if val.contains("filename*") {
-                    val = val[val.rfind('\'').unwrap() + 1..file_name.len()]
-                        .parse()
-                        .unwrap();
-                } else if val.contains("filename=") {
-                    val = val[val.rfind('=').unwrap() + 1..val.len()].parse().unwrap();
-                    if val.contains('\"') {
-                        val = val[1..val.len() - 1].parse().unwrap();
-                    }
-                } else {
-                    tracing::warn!(
-                        "File name can't be determined, using generic name. {}",
-                        dld_item.link.to_string()
-                    );
-                    val = format!("file-{time}{ext}", time = Utc::now().time(), ext = f_ext);
-                }
-                val
-            }
-        }
+        };
     }
-    if !file_name.contains(f_ext) {
+    if !mime_type.is_some_and(|mime_type| mime::has_extension_of(&file_name, mime_type)) {
         file_name = format!("{file_name}{f_ext}");
     }
-    file_name
+    sanitize_file_name(&file_name)
 }
 
-#[tracing::instrument]
-fn get_file_extension<'a>(dld_item: &'a DownloadItem, headers: &HeaderMap) -> &'a str {
-    match headers.get(header::CONTENT_TYPE) {
-        None => {
-            tracing::warn!(
-                "File extension can not be determined for {}",
-                dld_item.link.to_string()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn file_name(url: &str, content_disposition: Option<&str>) -> String {
+        let item = DownloadItem {
+            link: Url::parse(url).unwrap(),
+            destination_dir: PathBuf::new(),
+            depth: 0,
+            is_page: false,
+        };
+        let mut headers = HeaderMap::new();
+        if let Some(value) = content_disposition {
+            headers.insert(
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_str(value).unwrap(),
             );
-            ""
         }
-        Some(ct) => {
-            let mut val = ct.to_str().unwrap().to_lowercase();
-            // Remove charset if present (E.g text/html; charset=utf-8))
-            if val.contains(';') {
-                val = val[0..val.find(';').unwrap()].parse().unwrap();
-            }
-            match MIME_TYPES.get(&val) {
-                None => {
-                    tracing::warn!(
-                        "File extension can not be determined for {}",
-                        dld_item.link.to_string()
-                    );
-                    ""
-                }
-                Some(ext) => ext,
+        get_file_name(&item, &headers, Some("application/pdf"))
+    }
+
+    #[test]
+    fn names_are_percent_decoded_once() {
+        // `%25` is a literal `%` in each, which must not be decoded again
+        let names = [
+            file_name(
+                "https://example.com/",
+                Some("attachment; filename*=UTF-8''report%2541.pdf"),
+            ),
+            file_name(
+                "https://example.com/",
+                Some("attachment; filename=\"report%41.pdf\""),
+            ),
+            file_name("https://example.com/report%2541.pdf", None),
+        ];
+        assert_eq!(names[0], sanitize_file_name("report%41.pdf"));
+        assert!(names[0].starts_with("report_41-"), "{}", names[0]);
+        assert!(names.iter().all(|name| name == &names[0]), "{names:?}");
+        assert_eq!(
+            file_name("https://example.com/my%20report.pdf", None),
+            "my report.pdf"
+        );
+    }
+
+    /// The limit applies to the decoded size of a compressed body, which can
+    /// be much larger than it's `Content-Length`.
+    #[tokio::test]
+    async fn decoded_size_is_limited() {
+        use crate::{DownloadRule, Downloader, Event, Outcome, ReplayFetcher};
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("wsclone-decoded-{}", std::process::id()));
+        let site = dir.join("fixtures/example.com");
+        std::fs::create_dir_all(&site).unwrap();
+        std::fs::write(
+            site.join("index.html"),
+            r#"<html><body><img src="/zeros.bin"><img src="/small.bin"></body></html>"#,
+        )
+        .unwrap();
+        for (name, body) in [
+            ("zeros.bin", vec![0; 100_000]),
+            ("small.bin", b"tiny".to_vec()),
+        ] {
+            let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+            gzipped.write_all(&body).unwrap();
+            std::fs::write(site.join(name), gzipped.finish().unwrap()).unwrap();
+            std::fs::write(
+                site.join(format!("{name}.headers")),
+                "HTTP/1.1 200 OK\nContent-Encoding: gzip\n",
+            )
+            .unwrap();
+        }
+        let rule = DownloadRule {
+            max_static_file_size: 1000,
+            ..DownloadRule::default()
+        };
+
+        let fetcher = ReplayFetcher::from_dir(dir.join("fixtures")).unwrap();
+        let report = Downloader::new(
+            "decoded",
+            "https://example.com/",
+            &dir.join("site").to_string_lossy(),
+            rule,
+        )
+        .fetcher(Arc::new(fetcher))
+        .run_with_observer(Arc::new(|_: &Event| {}))
+        .await
+        .unwrap();
+        let outcome = |url: &str| {
+            report
+                .urls
+                .iter()
+                .find(|report| report.url == url)
+                .map(|report| report.outcome.outcome.clone())
+                .unwrap()
+        };
+        match outcome("https://example.com/zeros.bin") {
+            Outcome::Skipped {
+                reason: SkipReason::TooLarge { size, limit: 1000 },
+            } => assert!(size > 1000),
+            outcome => panic!("{outcome:?}"),
+        }
+        match outcome("https://example.com/small.bin") {
+            Outcome::Downloaded { file_path, bytes } => {
+                assert_eq!(bytes, 4);
+                assert_eq!(std::fs::read(file_path).unwrap(), b"tiny");
             }
+            outcome => panic!("{outcome:?}"),
         }
+        let files: Vec<_> = std::fs::read_dir(dir.join("site")).unwrap().collect();
+        assert_eq!(files.len(), 2, "index.html and small.bin only");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
-
-static MIME_TYPES: phf::Map<&'static str, &str> = phf_map! {
-    "application/json" => ".json",
-    "application/javascript" => ".js",
-    "application/pdf" => ".pdf",
-    "application/vnd.ms-powerpoint" => ".ppt",
-    "application/xhtml+xml" => ".xhtml",
-    "audio/mpeg" => ".mp3",
-    "audio/ogg" => ".oga",
-    "audio/webm" => ".webm",
-    "image/jpeg" => ".jpg",
-    "image/png" => ".png",
-    "image/gif" => ".gif",
-    "image/webp" => ".webp",
-    "text/html" => ".html",
-    "text/javascript" => ".js",
-    "video/mp4" => ".mp4",
-    "video/mpeg" => ".mpeg",
-    "video/ogg" => ".ogv",
-    "video/webm" => ".webm",
-    "font/otf" => ".otf",
-    "text/css" => ".css",
-
-};
@@ -1,6 +1,9 @@
+use crate::budget::BudgetKind;
+use serde::Serialize;
 use std::fmt::Formatter;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "detail")]
 pub enum WscError {
     ErrorCreatingDestinationDirectory(String),
     InvalidHtml(String),
@@ -19,6 +22,18 @@ pub enum WscError {
     },
     ChannelClosed,
     InvalidUrl(String),
+    InvalidDownloadRule(RuleError),
+    /// Parameter is the requested url
+    TooManyRedirects(String),
+    /// Parameter is why the login form was rejected
+    LoginFailed(String),
+    /// Parameter is the proxy and why it failed
+    ProxyError(String),
+    /// The body couldn't be decoded as given by it's `Content-Encoding`
+    ContentDecodingError {
+        url: String,
+        message: String,
+    },
 }
 
 impl std::fmt::Display for WscError {
@@ -43,9 +58,104 @@ impl std::fmt::Display for WscError {
             }
             WscError::ChannelClosed => "Channel closed before download completion".to_string(),
             WscError::InvalidUrl(url) => format!("Invalid url received : {url}"),
+            WscError::InvalidDownloadRule(err) => format!("invalid download rule. {err}"),
+            WscError::TooManyRedirects(url) => format!("too many redirects : {url}"),
+            WscError::LoginFailed(reason) => format!("login failed. {reason}"),
+            WscError::ProxyError(err) => format!("error connecting through proxy. {err}"),
+            WscError::ContentDecodingError { url, message } => {
+                format!("error decoding response body. {message} : {url}")
+            }
         };
         write!(f, "{str}")
     }
 }
 
 impl std::error::Error for WscError {}
+
+/// Reasons a [`crate::DownloadRule`] is rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", content = "detail")]
+pub enum RuleError {
+    ZeroMaxStaticFileSize,
+    ZeroProgressUpdateInterval,
+    /// Parameter is the index of the empty entry
+    EmptyBlackListEntry(usize),
+    /// Parameter is the prefix, which doesn't start with a `/`
+    InvalidPathPrefix(String),
+    EmptyAllowedHost,
+    /// Parameter is the index of the empty entry
+    EmptyStripParam(usize),
+    InvalidSitemapUrl(String),
+    ZeroBudget(BudgetKind),
+    InvalidFilter {
+        index: usize,
+        message: String,
+    },
+    /// A header name or value, or the user agent, that can't be sent
+    InvalidHeader {
+        name: String,
+        message: String,
+    },
+    EmptyHeaderHost,
+    InvalidCredentials {
+        host: String,
+        message: String,
+    },
+    InvalidLogin(String),
+    InvalidProxy {
+        proxy: String,
+        message: String,
+    },
+    EmptyNoProxyHost,
+    InvalidTls(String),
+}
+
+impl std::fmt::Display for RuleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            RuleError::ZeroMaxStaticFileSize => {
+                "max static file size must be greater than 0".to_string()
+            }
+            RuleError::ZeroProgressUpdateInterval => {
+                "progress update interval must be greater than 0".to_string()
+            }
+            RuleError::EmptyBlackListEntry(idx) => {
+                format!("black list entry {idx} is empty and would match every url")
+            }
+            RuleError::InvalidPathPrefix(prefix) => {
+                format!("scope path prefix \"{prefix}\" must start with a /")
+            }
+            RuleError::EmptyAllowedHost => "scope allowed hosts can't be empty".to_string(),
+            RuleError::EmptyStripParam(idx) => {
+                format!("query parameter {idx} to strip is empty")
+            }
+            RuleError::InvalidSitemapUrl(url) => format!("invalid sitemap url \"{url}\""),
+            RuleError::ZeroBudget(kind) => format!("{kind} budget must be greater than 0"),
+            RuleError::InvalidFilter { index, message } => {
+                format!("filter {index} is invalid. {message}")
+            }
+            RuleError::InvalidHeader { name, message } => {
+                format!("header \"{name}\" is invalid. {message}")
+            }
+            RuleError::EmptyHeaderHost => "header hosts can't be empty".to_string(),
+            RuleError::InvalidCredentials { host, message } => {
+                format!("credentials of {host} are invalid. {message}")
+            }
+            RuleError::InvalidLogin(message) => format!("login form is invalid. {message}"),
+            RuleError::InvalidProxy { proxy, message } => {
+                format!("proxy {proxy} is invalid. {message}")
+            }
+            RuleError::EmptyNoProxyHost => "no proxy hosts can't be empty".to_string(),
+            RuleError::InvalidTls(message) => format!("tls options are invalid. {message}"),
+        };
+        write!(f, "{str}")
+    }
+}
+
+impl std::error::Error for RuleError {}
+
+impl From<RuleError> for WscError {
+    fn from(err: RuleError) -> Self {
+        WscError::InvalidDownloadRule(err)
+    }
+}
@@ -0,0 +1,496 @@
+use crate::budget::BudgetKind;
+use crate::errors::WscError;
+use crate::observer::{Observer, SlowConsumerPolicy};
+use crate::{Message, Progress, Update};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::time::Instant;
+use url::Url;
+
+/// Most events held back by [`SlowConsumerPolicy::Coalesce`]. Past it, events
+/// wait for room in the channel.
+const MAX_PENDING_EVENTS: usize = 1000;
+
+/// A typed event emitted during a download session.
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub session_id: String,
+    /// Url of the page or resource the event is about. None for session wide events.
+    pub url: Option<String>,
+    /// Level of the page the event relates to. 0 is the initial page and it's resources.
+    pub depth: u8,
+    /// Milliseconds since the session started.
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type")]
+pub enum EventKind {
+    SessionStarted,
+    /// A page was found and will be downloaded once the current level is done.
+    PageQueued,
+    ResourceStarted {
+        resource_name: String,
+        /// None when the server didn't report the size
+        file_size: Option<u64>,
+        is_page: bool,
+    },
+    ResourceProgress {
+        resource_name: String,
+        bytes_written: u64,
+        file_size: Option<u64>,
+    },
+    ResourceCompleted {
+        resource_name: String,
+        file_path: String,
+        bytes_written: u64,
+        is_page: bool,
+        /// The file was already on disk and wasn't downloaded again
+        cached: bool,
+    },
+    ResourceSkipped {
+        reason: SkipReason,
+    },
+    ResourceFailed {
+        error: WscError,
+    },
+    RetryScheduled {
+        /// Attempt number of the upcoming retry, starting at 1
+        attempt: u8,
+        delay_ms: u64,
+        error: WscError,
+    },
+    /// Links in a downloaded page were replaced with their local file paths.
+    PageRewritten {
+        file_path: String,
+    },
+    /// A budget of the rule ran out, the urls left are skipped. Sent once.
+    BudgetExhausted {
+        budget: BudgetKind,
+    },
+    /// Certificates of these hosts aren't verified, see `TlsOptions::insecure_hosts`.
+    /// Sent when the session starts.
+    TlsVerificationDisabled {
+        hosts: Vec<String>,
+    },
+    /// Free text information, E.g a page whose links couldn't be extracted.
+    Message {
+        content: String,
+        is_error: bool,
+    },
+    SessionFinished {
+        stats: SessionStats,
+    },
+    SessionFailed {
+        error: WscError,
+        stats: SessionStats,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The url contains an entry of `DownloadRule::black_list_urls`
+    BlackListed {
+        pattern: String,
+    },
+    /// `size` is what was decoded so far when the body was given up mid-stream
+    TooLarge {
+        size: u64,
+        limit: u64,
+    },
+    UnknownSize,
+    /// The url doesn't match the page or resource scope of the rule
+    OutOfScope,
+    /// The url redirects to, or declares as canonical url, an already
+    /// downloaded url. Links to it point to that url's file.
+    Duplicate {
+        of: String,
+    },
+    /// An exclude filter of the rule matched the url
+    Filtered {
+        /// Position of the filter in `DownloadRule::filters`
+        index: usize,
+        filter: String,
+    },
+    /// A budget of the rule ran out before the url was requested
+    BudgetExhausted {
+        budget: BudgetKind,
+    },
+    /// The link looks like it would log the session out, see `HttpOptions::logout_patterns`
+    LogoutLink,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::BlackListed { pattern } => write!(f, "black listed by \"{pattern}\""),
+            SkipReason::TooLarge { size, limit } => {
+                write!(f, "size {size} bytes exceeds limit of {limit} bytes")
+            }
+            SkipReason::UnknownSize => write!(f, "size unknown"),
+            SkipReason::OutOfScope => write!(f, "out of scope"),
+            SkipReason::Duplicate { of } => write!(f, "duplicate of {of}"),
+            SkipReason::Filtered { index, filter } => {
+                write!(f, "excluded by filter {index} ({filter})")
+            }
+            SkipReason::BudgetExhausted { budget } => write!(f, "{budget} budget exhausted"),
+            SkipReason::LogoutLink => write!(f, "logout link"),
+        }
+    }
+}
+
+/// Counters for a session, updated as events are emitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SessionStats {
+    pub pages_queued: u64,
+    pub pages: u64,
+    pub resources: u64,
+    /// Pages and resources already on disk from a previous session
+    pub cached: u64,
+    pub bytes_written: u64,
+    pub skipped: u64,
+    pub failed: u64,
+    pub retries: u64,
+    pub elapsed_ms: u64,
+}
+
+impl SessionStats {
+    fn record(&mut self, kind: &EventKind) {
+        match kind {
+            EventKind::PageQueued => self.pages_queued += 1,
+            EventKind::ResourceCompleted {
+                is_page,
+                cached,
+                bytes_written,
+                ..
+            } => {
+                if *is_page {
+                    self.pages += 1;
+                } else {
+                    self.resources += 1;
+                }
+                if *cached {
+                    self.cached += 1;
+                } else {
+                    self.bytes_written += bytes_written;
+                }
+            }
+            EventKind::ResourceSkipped { .. } => self.skipped += 1,
+            EventKind::ResourceFailed { .. } => self.failed += 1,
+            EventKind::RetryScheduled { .. } => self.retries += 1,
+            _ => {}
+        }
+    }
+}
+
+impl Event {
+    /// Converts the event to the equivalent [`Update`], for consumers of the
+    /// older two-variant api. Events without an equivalent return None.
+    pub fn to_update(&self) -> Option<Update> {
+        let resource_name = self.url.clone().unwrap_or_default();
+        match &self.kind {
+            EventKind::ResourceProgress {
+                resource_name,
+                bytes_written,
+                file_size,
+            } => Some(Update::ProgressUpdate(Progress {
+                bytes_written: *bytes_written,
+                file_size: file_size.unwrap_or(0),
+                resource_name: resource_name.clone(),
+                session_id: self.session_id.clone(),
+            })),
+            EventKind::ResourceCompleted {
+                resource_name,
+                bytes_written,
+                ..
+            } => Some(Update::ProgressUpdate(Progress {
+                bytes_written: *bytes_written,
+                file_size: *bytes_written,
+                resource_name: resource_name.clone(),
+                session_id: self.session_id.clone(),
+            })),
+            EventKind::ResourceFailed { error } => Some(Update::MessageUpdate(Message {
+                session_id: self.session_id.clone(),
+                content: error.to_string(),
+                resource_name,
+                is_error: true,
+            })),
+            EventKind::BudgetExhausted { budget } => Some(Update::MessageUpdate(Message {
+                session_id: self.session_id.clone(),
+                content: format!("{budget} budget exhausted, stopping the crawl"),
+                resource_name,
+                is_error: false,
+            })),
+            EventKind::TlsVerificationDisabled { hosts } => Some(Update::MessageUpdate(Message {
+                session_id: self.session_id.clone(),
+                content: format!(
+                    "INSECURE : certificates of {} aren't verified",
+                    hosts.join(", ")
+                ),
+                resource_name,
+                is_error: true,
+            })),
+            EventKind::Message { content, is_error } => Some(Update::MessageUpdate(Message {
+                session_id: self.session_id.clone(),
+                content: content.clone(),
+                resource_name,
+                is_error: *is_error,
+            })),
+            _ => None,
+        }
+    }
+}
+
+/// Where the events of a session are delivered.
+#[derive(Clone)]
+pub(crate) enum EventTarget {
+    Channel(Sender<Event>),
+    Observer(Arc<dyn Observer>),
+}
+
+impl std::fmt::Debug for EventTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventTarget::Channel(tx) => f.debug_tuple("Channel").field(tx).finish(),
+            EventTarget::Observer(_) => f.write_str("Observer"),
+        }
+    }
+}
+
+/// Delivers the events of a session to it's target, following the slow
+/// consumer policy. Cheap to clone, all clones share the same stats.
+#[derive(Debug, Clone)]
+pub(crate) struct EventSink {
+    session_id: String,
+    started: Instant,
+    target: EventTarget,
+    policy: SlowConsumerPolicy,
+    /// Events held back by [`SlowConsumerPolicy::Coalesce`]
+    pending: Arc<Mutex<VecDeque<Event>>>,
+    stats: Arc<Mutex<SessionStats>>,
+}
+
+impl EventSink {
+    pub(crate) fn new(session_id: &str, target: EventTarget, policy: SlowConsumerPolicy) -> Self {
+        EventSink {
+            session_id: session_id.to_string(),
+            started: Instant::now(),
+            target,
+            policy,
+            pending: Default::default(),
+            stats: Default::default(),
+        }
+    }
+
+    /// Time since the session started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub(crate) fn stats(&self) -> SessionStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.elapsed_ms = self.started.elapsed().as_millis() as u64;
+        stats
+    }
+
+    fn event(&self, url: Option<&Url>, depth: u8, kind: EventKind) -> Event {
+        self.stats.lock().unwrap().record(&kind);
+        Event {
+            session_id: self.session_id.clone(),
+            url: url.map(Url::to_string),
+            depth,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            kind,
+        }
+    }
+
+    /// Sends an event. Depending on the policy this waits for room in the
+    /// channel. A closed channel is ignored.
+    pub(crate) async fn emit(&self, url: Option<&Url>, depth: u8, kind: EventKind) {
+        let event = self.event(url, depth, kind);
+        if self.deliver(event, false).await.is_err() {
+            tracing::debug!("Event channel closed, event dropped");
+        }
+    }
+
+    /// Sends a progress event. Returns whether the event was delivered, or an
+    /// error if the consumer is gone and the download should stop.
+    pub(crate) async fn emit_progress(
+        &self,
+        url: Option<&Url>,
+        depth: u8,
+        kind: EventKind,
+    ) -> Result<bool, WscError> {
+        let event = self.event(url, depth, kind);
+        self.deliver(event, true).await
+    }
+
+    async fn deliver(&self, event: Event, is_progress: bool) -> Result<bool, WscError> {
+        let tx = match &self.target {
+            EventTarget::Observer(observer) => {
+                observer.on_event(&event);
+                return Ok(true);
+            }
+            EventTarget::Channel(tx) => tx,
+        };
+        match self.policy {
+            SlowConsumerPolicy::Block => tx
+                .send(event)
+                .await
+                .map(|_| true)
+                .map_err(|_| WscError::ChannelClosed),
+            SlowConsumerPolicy::DropProgress if is_progress => match tx.try_send(event) {
+                Ok(_) => Ok(true),
+                Err(TrySendError::Full(_)) => Ok(false),
+                Err(TrySendError::Closed(_)) => Err(WscError::ChannelClosed),
+            },
+            SlowConsumerPolicy::DropProgress => tx
+                .send(event)
+                .await
+                .map(|_| true)
+                .map_err(|_| WscError::ChannelClosed),
+            SlowConsumerPolicy::Coalesce => {
+                let event = {
+                    let mut pending = self.pending.lock().unwrap();
+                    send_pending(tx, &mut pending)?;
+                    if pending.is_empty() {
+                        match tx.try_send(event) {
+                            Ok(_) => {}
+                            Err(TrySendError::Full(event)) => pending.push_back(event),
+                            Err(TrySendError::Closed(_)) => return Err(WscError::ChannelClosed),
+                        }
+                        return Ok(true);
+                    }
+                    match hold_back(&mut pending, event) {
+                        Some(event) => event,
+                        None => return Ok(true),
+                    }
+                };
+                // Too many events held back, wait for the consumer like `Block`
+                self.flush().await;
+                tx.send(event)
+                    .await
+                    .map(|_| true)
+                    .map_err(|_| WscError::ChannelClosed)
+            }
+        }
+    }
+
+    /// Waits until every held back event has been sent.
+    pub(crate) async fn flush(&self) {
+        let tx = match &self.target {
+            EventTarget::Channel(tx) => tx,
+            EventTarget::Observer(_) => return,
+        };
+        loop {
+            let event = match self.pending.lock().unwrap().pop_front() {
+                Some(e) => e,
+                None => return,
+            };
+            if tx.send(event).await.is_err() {
+                self.pending.lock().unwrap().clear();
+                return;
+            }
+        }
+    }
+}
+
+/// Sends held back events until the channel is full.
+fn send_pending(tx: &Sender<Event>, pending: &mut VecDeque<Event>) -> Result<(), WscError> {
+    while let Some(event) = pending.pop_front() {
+        match tx.try_send(event) {
+            Ok(_) => {}
+            Err(TrySendError::Full(event)) => {
+                pending.push_front(event);
+                return Ok(());
+            }
+            Err(TrySendError::Closed(_)) => {
+                pending.clear();
+                return Err(WscError::ChannelClosed);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Queues an event, replacing a held back progress event of the same resource.
+/// Returns the event when it can't be queued, the queue being full.
+fn hold_back(pending: &mut VecDeque<Event>, event: Event) -> Option<Event> {
+    if let EventKind::ResourceProgress { .. } = event.kind {
+        let held = pending
+            .iter_mut()
+            .find(|e| matches!(e.kind, EventKind::ResourceProgress { .. }) && e.url == event.url);
+        if let Some(held) = held {
+            *held = event;
+            return None;
+        }
+    }
+    if pending.len() >= MAX_PENDING_EVENTS {
+        return Some(event);
+    }
+    pending.push_back(event);
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::channel;
+
+    fn message(content: &str) -> EventKind {
+        EventKind::Message {
+            content: content.to_string(),
+            is_error: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn coalesced_events_are_bounded() {
+        let (tx, mut rx) = channel(1);
+        let sink = EventSink::new(
+            "coalesce",
+            EventTarget::Channel(tx),
+            SlowConsumerPolicy::Coalesce,
+        );
+        let url = Url::parse("https://example.com/big.zip").unwrap();
+        for bytes_written in 0..MAX_PENDING_EVENTS as u64 * 2 {
+            let progress = EventKind::ResourceProgress {
+                resource_name: "big.zip".to_string(),
+                bytes_written,
+                file_size: None,
+            };
+            sink.emit_progress(Some(&url), 0, progress).await.unwrap();
+        }
+        // The first event is in the channel, the others replaced each other
+        assert_eq!(sink.pending.lock().unwrap().len(), 1);
+
+        for index in 1..MAX_PENDING_EVENTS {
+            sink.emit(None, 0, message(&index.to_string())).await;
+        }
+        assert_eq!(sink.pending.lock().unwrap().len(), MAX_PENDING_EVENTS);
+        let blocked = sink.clone();
+        let full = tokio::spawn(async move { blocked.emit(None, 0, message("full")).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!full.is_finished());
+
+        let consumer = tokio::spawn(async move {
+            let mut received = 0;
+            while rx.recv().await.is_some() {
+                received += 1;
+            }
+            received
+        });
+        full.await.unwrap();
+        sink.flush().await;
+        drop(sink);
+        // Both progress events, the held back messages and the one that waited
+        assert_eq!(consumer.await.unwrap(), MAX_PENDING_EVENTS + 2);
+    }
+}
@@ -0,0 +1,189 @@
+use crate::auth::CredentialScope;
+use crate::errors::WscError;
+use crate::proxy::{redact, ProxyOptions};
+use crate::scope::host_matches;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::HeaderMap;
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use std::fmt::Debug;
+use url::Url;
+
+/// Stream of body chunks returned by a [`Fetcher`].
+pub type BodyStream = BoxStream<'static, Result<Bytes, WscError>>;
+
+/// The response to a single fetch. `url` is the url the response was served
+/// from, it differs from the requested url when the fetcher followed redirects.
+pub struct FetchResponse {
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BodyStream,
+}
+
+impl Debug for FetchResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FetchResponse")
+            .field("url", &self.url.as_str())
+            .field("status", &self.status)
+            .field("headers", &self.headers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Abstraction over the transport used to retrieve pages and static resources.
+///
+/// [`ReqwestFetcher`] talks to the network, [`crate::ReplayFetcher`] serves
+/// previously recorded responses from disk.
+pub trait Fetcher: Send + Sync + Debug {
+    /// Fetch `url`. Transport failures are returned as errors, error status
+    /// codes are returned as a normal response.
+    ///
+    /// Redirect responses should be returned as they are, the session follows
+    /// them so it can check the scope of every target and record the chain.
+    fn fetch<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<FetchResponse, WscError>>;
+}
+
+/// Default [`Fetcher`] backed by a [`reqwest::Client`]. The client should be
+/// built with [`reqwest::redirect::Policy::none`], redirects it follows by
+/// itself are only seen as a single hop to the final url.
+#[derive(Debug, Clone)]
+pub struct ReqwestFetcher {
+    client: Client,
+    /// Headers added to the requests to a host, by host
+    host_headers: Vec<(String, HeaderMap)>,
+    credentials: Vec<(CredentialScope, HeaderMap)>,
+    /// Proxies of the client, to tell proxy errors from network errors
+    proxy: ProxyOptions,
+    /// Client not verifying certificates, used for `insecure_hosts`
+    insecure_client: Option<Client>,
+    insecure_hosts: Vec<String>,
+}
+
+impl ReqwestFetcher {
+    pub fn new(client: Client) -> Self {
+        ReqwestFetcher {
+            client,
+            host_headers: Vec::new(),
+            credentials: Vec::new(),
+            proxy: ProxyOptions::default(),
+            insecure_client: None,
+            insecure_hosts: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_proxy(mut self, proxy: ProxyOptions) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Requests to `hosts` are made with `client`, which doesn't verify certificates.
+    pub(crate) fn insecure_client(mut self, hosts: Vec<String>, client: Client) -> Self {
+        self.insecure_hosts = hosts;
+        self.insecure_client = Some(client);
+        self
+    }
+
+    /// Sends `headers` with every request to `host`, on top of the default
+    /// headers of the client. A host starting with a `.` also matches it's
+    /// subdomains. Since redirects are followed by the session, a redirect to
+    /// another host never gets them.
+    pub fn host_headers(mut self, host: &str, headers: HeaderMap) -> Self {
+        self.host_headers.push((host.to_lowercase(), headers));
+        self
+    }
+
+    /// Sends the `Authorization` of `headers` with requests in `scope`. The
+    /// last credentials added replace the others of a request.
+    pub(crate) fn credentials(mut self, scope: CredentialScope, headers: HeaderMap) -> Self {
+        self.credentials.push((scope, headers));
+        self
+    }
+
+    /// A request to `url` with the headers of it's host, and the credentials of it's scope.
+    pub(crate) fn request(&self, method: Method, url: &Url) -> RequestBuilder {
+        let host = url.host_str().unwrap_or_default();
+        let client = match &self.insecure_client {
+            Some(client)
+                if self
+                    .insecure_hosts
+                    .iter()
+                    .any(|allowed| allowed == "*" || host_matches(allowed, host)) =>
+            {
+                client
+            }
+            _ => &self.client,
+        };
+        let mut request = client.request(method, url.as_str());
+        for (_, headers) in self
+            .host_headers
+            .iter()
+            .filter(|(allowed, _)| host_matches(allowed, host))
+        {
+            request = request.headers(headers.clone());
+        }
+        if let Some((_, headers)) = self
+            .credentials
+            .iter()
+            .rev()
+            .find(|(scope, _)| scope.matches(url))
+        {
+            request = request.headers(headers.clone());
+        }
+        request
+    }
+
+    /// A connection error of a proxied request is the proxy failing, or
+    /// refusing to connect to the url.
+    pub(crate) fn send_error(&self, url: &Url, e: &reqwest::Error) -> WscError {
+        match self.proxy.proxy_for(url) {
+            Some(proxy) if e.is_connect() => {
+                WscError::ProxyError(format!("{} couldn't connect to {url}. {e}", redact(proxy)))
+            }
+            _ => WscError::NetworkError(url.to_string()),
+        }
+    }
+}
+
+impl Fetcher for ReqwestFetcher {
+    fn fetch<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<FetchResponse, WscError>> {
+        Box::pin(async move {
+            let response = match self.request(Method::GET, url).send().await {
+                Ok(r) => r,
+                Err(e) => {
+                    tracing::error!(
+                        msg = "Error sending request",
+                        url = url.to_string(),
+                        error_msg = e.to_string(),
+                    );
+                    return Err(self.send_error(url, &e));
+                }
+            };
+            let final_url = response.url().clone();
+            Ok(FetchResponse {
+                url: final_url.clone(),
+                status: response.status(),
+                headers: response.headers().clone(),
+                body: response
+                    .bytes_stream()
+                    .map_err(move |e| map_body_error(e, &final_url))
+                    .boxed(),
+            })
+        })
+    }
+}
+
+fn map_body_error(e: reqwest::Error, url: &Url) -> WscError {
+    if e.is_connect() {
+        WscError::NetworkError(e.to_string())
+    } else if let Some(status) = e.status() {
+        WscError::ErrorStatusCode {
+            status_code: status.to_string(),
+            url: url.to_string(),
+        }
+    } else {
+        WscError::UnknownError(e.to_string())
+    }
+}
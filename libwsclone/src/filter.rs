@@ -0,0 +1,241 @@
+use crate::errors::RuleError;
+use crate::mime::{guess_mime_type, url_extension};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterPattern {
+    /// Regular expression searched for in the full url
+    Regex(String),
+    /// Glob matched against the full url. `*` also matches `/`, E.g `*/private/*`
+    Glob(String),
+    /// Glob matched against the mime type guessed from the url extension,
+    /// E.g `image/*`. Pages without an extension are `text/html`.
+    MimeType(String),
+    /// Extension of the url path, without the dot. Case insensitive.
+    Extension(String),
+}
+
+/// An include or exclude rule on urls, see [`crate::DownloadRule::filters`].
+///
+/// Serialized with the pattern kind as key, E.g `{ action = "exclude", glob = "*.pdf" }`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "FilterFields")]
+pub struct UrlFilter {
+    pub action: FilterAction,
+    #[serde(flatten)]
+    pub pattern: FilterPattern,
+}
+
+/// The keys of a [`UrlFilter`]. A flattened pattern would accept unknown keys.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterFields {
+    action: FilterAction,
+    regex: Option<String>,
+    glob: Option<String>,
+    mime_type: Option<String>,
+    extension: Option<String>,
+}
+
+impl TryFrom<FilterFields> for UrlFilter {
+    type Error = String;
+
+    fn try_from(fields: FilterFields) -> Result<Self, Self::Error> {
+        let patterns = [
+            fields.regex.map(FilterPattern::Regex),
+            fields.glob.map(FilterPattern::Glob),
+            fields.mime_type.map(FilterPattern::MimeType),
+            fields.extension.map(FilterPattern::Extension),
+        ];
+        let mut patterns = patterns.into_iter().flatten();
+        match (patterns.next(), patterns.next()) {
+            (Some(pattern), None) => Ok(UrlFilter {
+                action: fields.action,
+                pattern,
+            }),
+            _ => Err("a filter needs one of regex, glob, mime_type or extension".to_string()),
+        }
+    }
+}
+
+impl UrlFilter {
+    pub fn include(pattern: FilterPattern) -> Self {
+        UrlFilter {
+            action: FilterAction::Include,
+            pattern,
+        }
+    }
+
+    pub fn exclude(pattern: FilterPattern) -> Self {
+        UrlFilter {
+            action: FilterAction::Exclude,
+            pattern,
+        }
+    }
+}
+
+impl std::fmt::Display for UrlFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            FilterAction::Include => "include",
+            FilterAction::Exclude => "exclude",
+        };
+        let (kind, pattern) = match &self.pattern {
+            FilterPattern::Regex(p) => ("regex", p),
+            FilterPattern::Glob(p) => ("glob", p),
+            FilterPattern::MimeType(p) => ("mime type", p),
+            FilterPattern::Extension(p) => ("extension", p),
+        };
+        write!(f, "{action} {kind} \"{pattern}\"")
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Regex(Regex),
+    Glob(GlobMatcher),
+    MimeType(GlobMatcher),
+    Extension(String),
+}
+
+/// Filters of a rule, compiled once per session.
+#[derive(Debug, Default)]
+pub(crate) struct FilterSet {
+    filters: Vec<(UrlFilter, Matcher)>,
+}
+
+impl FilterSet {
+    pub(crate) fn new(filters: &[UrlFilter]) -> Result<Self, RuleError> {
+        let filters = filters
+            .iter()
+            .enumerate()
+            .map(|(index, filter)| {
+                let invalid = |message: String| RuleError::InvalidFilter { index, message };
+                let glob = |pattern: &str| {
+                    Glob::new(pattern)
+                        .map(|g| g.compile_matcher())
+                        .map_err(|e| invalid(e.to_string()))
+                };
+                let matcher = match &filter.pattern {
+                    FilterPattern::Regex(p) => {
+                        Matcher::Regex(Regex::new(p).map_err(|e| invalid(e.to_string()))?)
+                    }
+                    FilterPattern::Glob(p) => Matcher::Glob(glob(p)?),
+                    FilterPattern::MimeType(p) => Matcher::MimeType(glob(&p.to_lowercase())?),
+                    FilterPattern::Extension(p) => {
+                        let ext = p.trim_start_matches('.').to_lowercase();
+                        if ext.is_empty() {
+                            return Err(invalid("empty extension".to_string()));
+                        }
+                        Matcher::Extension(ext)
+                    }
+                };
+                Ok((filter.clone(), matcher))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(FilterSet { filters })
+    }
+
+    /// The first filter matching `url`, with it's index. Urls matched by an
+    /// exclude filter must be skipped.
+    pub(crate) fn first_match(&self, url: &Url, is_page: bool) -> Option<(usize, &UrlFilter)> {
+        let extension = url_extension(url);
+        let mime_type = match &extension {
+            Some(ext) => guess_mime_type(ext),
+            None if is_page => Some("text/html"),
+            None => None,
+        };
+        self.filters
+            .iter()
+            .enumerate()
+            .find(|(_, (_, matcher))| match matcher {
+                Matcher::Regex(re) => re.is_match(url.as_str()),
+                Matcher::Glob(glob) => glob.is_match(url.as_str()),
+                Matcher::MimeType(glob) => mime_type.is_some_and(|m| glob.is_match(m)),
+                Matcher::Extension(ext) => extension.as_deref() == Some(ext.as_str()),
+            })
+            .map(|(idx, (filter, _))| (idx, filter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first_match(filters: &FilterSet, url: &str, is_page: bool) -> Option<usize> {
+        filters
+            .first_match(&Url::parse(url).unwrap(), is_page)
+            .map(|(index, _)| index)
+    }
+
+    #[test]
+    fn first_matching_filter_wins() {
+        let docs = UrlFilter::include(FilterPattern::Glob("*/docs/*".to_string()));
+        let pdf = UrlFilter::exclude(FilterPattern::Extension(".PDF".to_string()));
+        let filters = FilterSet::new(&[docs.clone(), pdf.clone()]).unwrap();
+        assert_eq!(
+            first_match(&filters, "https://a.org/docs/a.pdf", false),
+            Some(0)
+        );
+        assert_eq!(first_match(&filters, "https://a.org/b.pdf", false), Some(1));
+        assert_eq!(first_match(&filters, "https://a.org/c.html", true), None);
+
+        let filters = FilterSet::new(&[pdf, docs]).unwrap();
+        assert_eq!(
+            first_match(&filters, "https://a.org/docs/a.pdf", false),
+            Some(0)
+        );
+        assert_eq!(
+            first_match(&filters, "https://a.org/docs/a.html", true),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn mime_types_are_guessed_from_extensions() {
+        let filters = FilterSet::new(&[
+            UrlFilter::exclude(FilterPattern::MimeType("image/*".to_string())),
+            UrlFilter::include(FilterPattern::MimeType("text/html".to_string())),
+            UrlFilter::exclude(FilterPattern::Regex(r"\?page=\d+$".to_string())),
+        ])
+        .unwrap();
+        assert_eq!(
+            first_match(&filters, "https://a.org/logo.PNG", false),
+            Some(0)
+        );
+        // Pages without an extension are HTML, resources have no type
+        assert_eq!(first_match(&filters, "https://a.org/about", true), Some(1));
+        assert_eq!(
+            first_match(&filters, "https://a.org/api?page=2", false),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn invalid_filters_are_reported_with_their_index() {
+        let filters = [
+            UrlFilter::include(FilterPattern::Glob("*.html".to_string())),
+            UrlFilter::exclude(FilterPattern::Regex("(".to_string())),
+        ];
+        assert!(matches!(
+            FilterSet::new(&filters),
+            Err(RuleError::InvalidFilter { index: 1, .. })
+        ));
+        let empty = UrlFilter::exclude(FilterPattern::Extension(".".to_string()));
+        assert!(matches!(
+            FilterSet::new(&[empty]),
+            Err(RuleError::InvalidFilter { index: 0, .. })
+        ));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::auth::{authorization_headers, Credentials};
+use crate::compression::ACCEPT_ENCODING;
+use crate::errors::{RuleError, WscError};
+use crate::fetch::ReqwestFetcher;
+use crate::login::{default_logout_patterns, FormLogin};
+use crate::proxy::ProxyOptions;
+use crate::tls::TlsOptions;
+use chrono::Utc;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::Client;
+use reqwest_cookie_store::{CookieStore, CookieStoreMutex};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+use url::Url;
+
+const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36";
+/// Marks a `cookies.txt` line for an HttpOnly cookie, instead of a comment
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// How requests are made by the default fetcher. A fetcher given with
+/// [`crate::Downloader::fetcher`] makes it's own requests and ignores these.
+///
+/// Cookies set by servers are kept for the whole session, starting with the
+/// ones of `cookie_jar` and `import_cookies`.
+///
+/// Responses are asked for compressed, unless `headers` has an
+/// `Accept-Encoding`, and always stored decoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpOptions {
+    pub user_agent: String,
+    /// File the cookies are loaded from when the session starts, if it
+    /// exists, and saved to when it ends. Session cookies are saved too.
+    pub cookie_jar: Option<String>,
+    /// Netscape `cookies.txt` files, E.g exported from a browser, whose
+    /// cookies are added to the jar when the session starts.
+    pub import_cookies: Vec<String>,
+    /// netrc file with the login and password of hosts, sent with basic auth
+    pub netrc: Option<String>,
+    /// Anchor links whose url or text contain one of these, ignoring case,
+    /// are never followed so the session isn't logged out. E.g `sign out`
+    pub logout_patterns: Vec<String>,
+    /// Headers sent with every request
+    pub headers: BTreeMap<String, String>,
+    /// Headers sent only with requests to a host, by host. A host starting
+    /// with a `.` matches the domain and all it's subdomains, E.g `.example.org`.
+    /// They replace the headers of the same name in `headers`.
+    pub host_headers: BTreeMap<String, BTreeMap<String, String>>,
+    /// Proxies of the requests
+    pub proxy: ProxyOptions,
+    /// Root certificates, client certificate and hosts whose certificate isn't verified
+    pub tls: TlsOptions,
+    /// Form posted before the crawl starts, to get the cookies of a logged in session.
+    pub login: Option<FormLogin>,
+    /// Basic or bearer auth of hosts. They replace the netrc login of the same host.
+    pub credentials: Vec<Credentials>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            cookie_jar: None,
+            import_cookies: Vec::new(),
+            netrc: None,
+            logout_patterns: default_logout_patterns(),
+            headers: BTreeMap::new(),
+            host_headers: BTreeMap::new(),
+            proxy: ProxyOptions::default(),
+            tls: TlsOptions::default(),
+            login: None,
+            credentials: Vec::new(),
+        }
+    }
+}
+
+impl HttpOptions {
+    /// Checks the user agent and headers are valid header values, the proxies
+    /// are valid urls, and the credentials, certificates and login are complete.
+    pub(crate) fn validate(&self) -> Result<(), RuleError> {
+        self.header_maps()?;
+        self.proxy.validate()?;
+        self.tls.validate()?;
+        if let Some(login) = &self.login {
+            login.validate()?;
+        }
+        self.credentials.iter().try_for_each(Credentials::validate)
+    }
+
+    /// The headers for every request, and the headers for each host.
+    fn header_maps(&self) -> Result<(HeaderMap, Vec<(String, HeaderMap)>), RuleError> {
+        HeaderValue::from_str(&self.user_agent).map_err(|e| RuleError::InvalidHeader {
+            name: "user-agent".to_string(),
+            message: e.to_string(),
+        })?;
+        let headers = header_map(&self.headers)?;
+        let mut host_headers = Vec::new();
+        for (host, headers) in &self.host_headers {
+            if host.trim_start_matches('.').trim().is_empty() {
+                return Err(RuleError::EmptyHeaderHost);
+            }
+            host_headers.push((host.clone(), header_map(headers)?));
+        }
+        Ok((headers, host_headers))
+    }
+}
+
+fn header_map(headers: &BTreeMap<String, String>) -> Result<HeaderMap, RuleError> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let invalid = |message: String| RuleError::InvalidHeader {
+            name: name.clone(),
+            message,
+        };
+        let header_name =
+            HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(e.to_string()))?;
+        let header_value = HeaderValue::from_str(value).map_err(|e| invalid(e.to_string()))?;
+        map.insert(header_name, header_value);
+    }
+    Ok(map)
+}
+
+/// The client of the default fetcher, with the cookies of the session.
+pub(crate) struct HttpClient {
+    fetcher: ReqwestFetcher,
+    cookies: Arc<CookieStoreMutex>,
+    cookie_jar: Option<String>,
+}
+
+impl HttpClient {
+    /// Credentials without a scheme or port get the ones of the `seeds` on their host.
+    pub(crate) fn new(options: &HttpOptions, seeds: &[Url]) -> Result<Self, WscError> {
+        let (mut headers, host_headers) = options.header_maps()?;
+        if !headers.contains_key(header::ACCEPT_ENCODING) {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static(ACCEPT_ENCODING),
+            );
+        }
+        let credentials =
+            authorization_headers(&options.credentials, options.netrc.as_deref(), seeds)?;
+        let mut store = match &options.cookie_jar {
+            Some(path) if Path::new(path).exists() => load_cookie_jar(path)?,
+            _ => CookieStore::default(),
+        };
+        for path in &options.import_cookies {
+            let imported = import_cookies_txt(&mut store, path)?;
+            tracing::debug!("{imported} cookies imported from {path}");
+        }
+        let cookies = Arc::new(CookieStoreMutex::new(store));
+        let proxy = options.proxy.reqwest_proxy()?;
+        let build = |insecure: bool| {
+            let mut builder = Client::builder()
+                .user_agent(&options.user_agent)
+                .default_headers(headers.clone())
+                .cookie_provider(cookies.clone())
+                .redirect(reqwest::redirect::Policy::none())
+                .danger_accept_invalid_certs(insecure)
+                .danger_accept_invalid_hostnames(insecure);
+            if let Some(proxy) = &proxy {
+                // The proxies of the environment would be used for the no proxy hosts
+                builder = builder.no_proxy().proxy(proxy.clone());
+            }
+            options
+                .tls
+                .configure(builder)?
+                .build()
+                .map_err(|e| WscError::UnknownError(e.to_string()))
+        };
+        let mut fetcher = ReqwestFetcher::new(build(false)?).with_proxy(options.proxy.clone());
+        if !options.tls.insecure_hosts.is_empty() {
+            fetcher = fetcher.insecure_client(options.tls.insecure_hosts.clone(), build(true)?);
+        }
+        let fetcher = host_headers
+            .into_iter()
+            .fold(fetcher, |fetcher, (host, headers)| {
+                fetcher.host_headers(&host, headers)
+            });
+        let fetcher = credentials
+            .into_iter()
+            .fold(fetcher, |fetcher, (scope, headers)| {
+                fetcher.credentials(scope, headers)
+            });
+        Ok(HttpClient {
+            fetcher,
+            cookies,
+            cookie_jar: options.cookie_jar.clone(),
+        })
+    }
+
+    pub(crate) fn fetcher(&self) -> ReqwestFetcher {
+        self.fetcher.clone()
+    }
+
+    /// Posts the login form, the cookies it gets are used by the fetcher.
+    pub(crate) async fn login(&self, login: &FormLogin) -> Result<Url, WscError> {
+        login.run(&self.fetcher).await
+    }
+
+    /// Writes the cookies to the cookie jar, if there is one.
+    pub(crate) fn save_cookies(&self) -> Result<(), WscError> {
+        let path = match &self.cookie_jar {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file_error = |message: String| WscError::FileOperationError {
+            file_name: path.clone(),
+            message,
+        };
+        let file = std::fs::File::create(path).map_err(|e| file_error(e.to_string()))?;
+        let store = self.cookies.lock().map_err(|e| file_error(e.to_string()))?;
+        store
+            .save_incl_expired_and_nonpersistent_json(&mut BufWriter::new(file))
+            .map_err(|e| file_error(e.to_string()))
+    }
+}
+
+fn load_cookie_jar(path: &str) -> Result<CookieStore, WscError> {
+    let file_error = |message: String| WscError::FileOperationError {
+        file_name: path.to_string(),
+        message,
+    };
+    let file = std::fs::File::open(path).map_err(|e| file_error(e.to_string()))?;
+    // Expired cookies are dropped
+    CookieStore::load_json(BufReader::new(file)).map_err(|e| file_error(e.to_string()))
+}
+
+/// Adds the cookies of a Netscape `cookies.txt` file to `store`. Each line is
+/// `domain include_subdomains path secure expiry name value`, tab separated.
+/// Returns the number of cookies added, expired ones are left out.
+fn import_cookies_txt(store: &mut CookieStore, path: &str) -> Result<usize, WscError> {
+    let content = std::fs::read_to_string(path).map_err(|e| WscError::FileOperationError {
+        file_name: path.to_string(),
+        message: e.to_string(),
+    })?;
+    let mut imported = 0;
+    for (idx, line) in content.lines().enumerate() {
+        let invalid = |message: &str| WscError::FileOperationError {
+            file_name: path.to_string(),
+            message: format!("line {} : {message}", idx + 1),
+        };
+        let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        // The value is left out by some exporters when it's empty
+        if fields.len() != 6 && fields.len() != 7 {
+            return Err(invalid("expected 7 tab separated fields"));
+        }
+        let domain = fields[0].trim_start_matches('.');
+        let include_subdomains = fields[1].eq_ignore_ascii_case("true");
+        let (cookie_path, secure, name) = (fields[2], fields[3], fields[5]);
+        let value = fields.get(6).copied().unwrap_or_default();
+        let expiry: i64 = fields[4]
+            .parse()
+            .map_err(|_| invalid("expiry isn't a unix timestamp"))?;
+
+        let mut cookie = format!("{name}={value}; Path={cookie_path}");
+        if include_subdomains {
+            cookie.push_str(&format!("; Domain={domain}"));
+        }
+        if secure.eq_ignore_ascii_case("true") {
+            cookie.push_str("; Secure");
+        }
+        if http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        // 0 is a session cookie
+        if expiry > 0 {
+            let max_age = expiry - Utc::now().timestamp();
+            if max_age <= 0 {
+                continue;
+            }
+            cookie.push_str(&format!("; Max-Age={max_age}"));
+        }
+        let url = Url::parse(&format!("https://{domain}{cookie_path}"))
+            .map_err(|e| invalid(&e.to_string()))?;
+        match store.parse(&cookie, &url) {
+            Ok(_) => imported += 1,
+            Err(e) => tracing::warn!("Cookie {name} of {domain} not imported. {e}"),
+        }
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthScheme;
+    use reqwest::header::AUTHORIZATION;
+    use reqwest::Method;
+
+    #[test]
+    fn https_credentials_are_not_sent_after_a_redirect_to_http() {
+        std::env::set_var("WSCLONE_TEST_REDIRECT_TOKEN", "secret");
+        let options = HttpOptions {
+            credentials: vec![Credentials {
+                host: "example.com".to_string(),
+                url_scheme: None,
+                port: None,
+                scheme: AuthScheme::Bearer,
+                username_env: None,
+                secret_env: "WSCLONE_TEST_REDIRECT_TOKEN".to_string(),
+            }],
+            ..HttpOptions::default()
+        };
+        let seeds = [Url::parse("https://example.com/").unwrap()];
+        let fetcher = HttpClient::new(&options, &seeds).unwrap().fetcher();
+        let authorization = |url: &Url| {
+            let request = fetcher.request(Method::GET, url).build().unwrap();
+            request.headers().get(AUTHORIZATION).cloned()
+        };
+
+        let page = seeds[0].join("/private").unwrap();
+        assert_eq!(authorization(&page).unwrap(), "Bearer secret");
+        // Location of the redirect, on the same host
+        let redirected = page.join("http://example.com/private").unwrap();
+        assert_eq!(authorization(&redirected), None);
+        let other_port = page.join("https://example.com:8443/private").unwrap();
+        assert_eq!(authorization(&other_port), None);
+    }
+}
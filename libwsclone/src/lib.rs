@@ -1,38 +1,76 @@
+use crate::charset::{decode, detect_encoding, encode};
 use crate::download::{download_file, DownloadItem};
-use crate::errors::WscError;
-use crate::link::{get_anchor_links, get_static_resource_links};
+use crate::event::{EventSink, EventTarget};
+use crate::filter::FilterSet;
+use crate::http::HttpClient;
+use crate::link::{
+    get_anchor_links, get_canonical_link, get_logout_links, get_static_resource_links,
+};
+use crate::login::is_logout_link;
 use crate::session::{LinkInfo, Session};
-use reqwest::Client;
-use std::path::PathBuf;
+use encoding_rs::{Encoding, UTF_8};
+use futures::{stream, Stream};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
-use tokio::sync::mpsc::Sender;
+use tokio::sync::mpsc::{channel, Sender};
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use tokio::{fs, spawn};
 use tracing::instrument;
 use url::Url;
 
+mod auth;
+mod budget;
+mod charset;
+mod compression;
+mod content_disposition;
 mod download;
 mod errors;
+mod event;
+mod fetch;
+mod filter;
+mod http;
 mod link;
+mod login;
+mod mime;
+mod normalize;
+mod observer;
+mod proxy;
+mod replay;
+mod rule;
+mod sanitize;
+mod scope;
 mod session;
+mod sitemap;
+mod tls;
 
-#[derive(Debug, Clone)]
-pub struct DownloadRule {
-    /// Maximum size for static files to download
-    pub max_static_file_size: u64,
-    pub download_static_resource_with_unknown_size: bool,
-    /// Progress update interval in millisecond
-    pub progress_update_interval: u64,
-    /// Max levels of pages to download. Minimum is 0, which means download
-    /// only the initial page and it's resources.
-    pub max_level: u8,
-    pub black_list_urls: Vec<String>,
-    /// Abort download if any resource other than the first page encounters an error.
-    pub abort_on_download_error: bool,
-}
+pub use auth::{AuthScheme, Credentials};
+pub use budget::{Budget, BudgetKind};
+pub use errors::{RuleError, WscError};
+pub use event::{Event, EventKind, SessionStats, SkipReason};
+pub use fetch::{BodyStream, FetchResponse, Fetcher, ReqwestFetcher};
+pub use filter::{FilterAction, FilterPattern, UrlFilter};
+pub use http::HttpOptions;
+pub use login::FormLogin;
+pub use normalize::{Normalization, TrailingSlash};
+pub use observer::{Observer, SlowConsumerPolicy};
+pub use proxy::ProxyOptions;
+pub use replay::ReplayFetcher;
+pub use rule::{DownloadRule, DownloadRuleBuilder};
+pub use scope::{HostScope, Scope};
+pub use session::{Outcome, SessionReport, UrlOutcome, UrlReport};
+pub use sitemap::SitemapSeeding;
+pub use tls::TlsOptions;
 
+/// Buffer size of the event channels created by the library, E.g for [`Downloader::stream`]
+const EVENT_BUFFER_SIZE: usize = 100;
+/// Longest host and path part of the file name of a seed
+const MAX_SEED_SLUG_LEN: usize = 80;
+
+/// The original, untyped session updates. See [`Event`] for the typed events
+/// these are derived from.
 #[derive(Debug)]
 pub enum Update {
     MessageUpdate(Message),
@@ -71,7 +109,7 @@ impl Update {
     pub fn is_error(&self) -> bool {
         match self {
             Update::MessageUpdate(msg) => msg.is_error,
-            _ => true,
+            _ => false,
         }
     }
 }
@@ -93,13 +131,16 @@ pub struct Progress {
 }
 
 #[derive(Debug, Clone)]
-struct DownloadProp {
-    session_id: String,
+pub(crate) struct DownloadProp {
     dest_dir: String,
     rule: DownloadRule,
     file_name: Option<String>,
     session: Arc<RwLock<Session>>,
-    client: Arc<Client>,
+    fetcher: Arc<dyn Fetcher>,
+    events: EventSink,
+    filters: Arc<FilterSet>,
+    /// Level of the page being downloaded
+    depth: u8,
 }
 
 #[instrument]
@@ -107,230 +148,910 @@ pub async fn init_download(
     session_id: &str,
     link: &str,
     dest_dir: &str,
-    mut rule: DownloadRule,
+    rule: DownloadRule,
     update_tx: Sender<Update>,
-) -> Result<(), WscError> {
-    let initial_url = if let Ok(u) = Url::parse(link) {
-        u
-    } else {
-        return Err(WscError::InvalidUrl(link.to_string()));
-    };
+) -> Result<SessionReport, WscError> {
+    forward_updates(Downloader::new(session_id, link, dest_dir, rule), update_tx).await
+}
 
-    if let Err(e) = fs::create_dir_all(dest_dir).await {
-        tracing::error!("Failed to create destination directory\nError : {}", e);
-        return Err(WscError::ErrorCreatingDestinationDirectory(e.to_string()));
-    };
+/// Same as [`init_download`], but all pages and resources are retrieved through
+/// the given fetcher. E.g a [`ReplayFetcher`] to crawl without network access.
+#[instrument]
+pub async fn init_download_with_fetcher(
+    session_id: &str,
+    link: &str,
+    dest_dir: &str,
+    rule: DownloadRule,
+    update_tx: Sender<Update>,
+    fetcher: Arc<dyn Fetcher>,
+) -> Result<SessionReport, WscError> {
+    forward_updates(
+        Downloader::new(session_id, link, dest_dir, rule).fetcher(fetcher),
+        update_tx,
+    )
+    .await
+}
+
+/// Runs the session and converts it's events to [`Update`]s for `update_tx`.
+async fn forward_updates(
+    downloader: Downloader,
+    update_tx: Sender<Update>,
+) -> Result<SessionReport, WscError> {
+    let (event_tx, mut event_rx) = channel::<Event>(EVENT_BUFFER_SIZE);
+    let forwarder = spawn(async move {
+        while let Some(event) = event_rx.recv().await {
+            if let Some(update) = event.to_update() {
+                if update_tx.send(update).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+    let result = downloader.run(event_tx).await;
+    if let Err(e) = forwarder.await {
+        tracing::error!("Update forwarding task panicked\nError : {}", e);
+    }
+    result
+}
+
+/// Configures and runs a download session, reporting progress as [`Event`]s.
+/// ```no_run
+/// # async fn example() -> Result<libwsclone::SessionReport, libwsclone::WscError> {
+/// use libwsclone::{DownloadRule, Downloader};
+///
+/// let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+/// tokio::spawn(async move {
+///     while let Some(event) = rx.recv().await {
+///         println!("{event:?}");
+///     }
+/// });
+/// Downloader::new("session", "https://example.com", "./example", DownloadRule::default())
+///     .run(tx)
+///     .await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Downloader {
+    session_id: String,
+    /// The initial link, followed by the seeds added with [`Downloader::seed`]
+    links: Vec<String>,
+    dest_dir: String,
+    rule: DownloadRule,
+    fetcher: Option<Arc<dyn Fetcher>>,
+    slow_consumer_policy: SlowConsumerPolicy,
+}
 
-    let client = Arc::new(Client::builder()
-        .user_agent(
-            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/108.0.0.0 Safari/537.36"
-        ).build().unwrap());
-
-    let session_lock = Arc::new(RwLock::new(Session {
-        initial_url,
-        session_id: session_id.to_string(),
-        processed_static_files: Default::default(),
-        processed_pages: Default::default(),
-    }));
-
-    let mut a_href_links: Vec<(String, Url)> = match download_page_with_static_resources(
-        update_tx.clone(),
-        rule.max_level > 0,
-        link,
-        &Url::parse(link).unwrap(),
-        DownloadProp {
+impl Downloader {
+    pub fn new(session_id: &str, link: &str, dest_dir: &str, rule: DownloadRule) -> Self {
+        Downloader {
             session_id: session_id.to_string(),
+            links: vec![link.to_string()],
             dest_dir: dest_dir.to_string(),
-            rule: DownloadRule {
-                black_list_urls: rule.black_list_urls.clone(),
-                max_level: rule.max_level,
-                abort_on_download_error: true,
-                max_static_file_size: rule.max_static_file_size,
-                download_static_resource_with_unknown_size: true,
-                progress_update_interval: rule.progress_update_interval,
+            rule,
+            fetcher: None,
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+        }
+    }
+
+    /// Adds a page to start the crawl from, downloaded at level 0 like the
+    /// initial page. Seeds share the session, so resources they have in
+    /// common are downloaded once. With more than one seed each is named after
+    /// it's url, and an `index.html` linking to all of them is written.
+    pub fn seed(mut self, link: &str) -> Self {
+        self.links.push(link.to_string());
+        self
+    }
+
+    /// Retrieve pages and resources through `fetcher` instead of the default [`ReqwestFetcher`].
+    pub fn fetcher(mut self, fetcher: Arc<dyn Fetcher>) -> Self {
+        self.fetcher = Some(fetcher);
+        self
+    }
+
+    /// What to do when the consumer of [`Downloader::run`] or
+    /// [`Downloader::stream`] falls behind. Defaults to [`SlowConsumerPolicy::DropProgress`].
+    pub fn slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.slow_consumer_policy = policy;
+        self
+    }
+
+    /// Runs the session and returns it's report. The last event sent is either
+    /// [`EventKind::SessionFinished`] or [`EventKind::SessionFailed`].
+    #[instrument]
+    pub async fn run(self, event_tx: Sender<Event>) -> Result<SessionReport, WscError> {
+        let events = EventSink::new(
+            &self.session_id,
+            EventTarget::Channel(event_tx),
+            self.slow_consumer_policy,
+        );
+        self.run_with_sink(events).await
+    }
+
+    /// Runs the session, calling `observer` for every event instead of sending them on a channel.
+    #[instrument(skip(observer))]
+    pub async fn run_with_observer(
+        self,
+        observer: Arc<dyn Observer>,
+    ) -> Result<SessionReport, WscError> {
+        let events = EventSink::new(
+            &self.session_id,
+            EventTarget::Observer(observer),
+            self.slow_consumer_policy,
+        );
+        self.run_with_sink(events).await
+    }
+
+    /// Runs the session on a new task and returns it's events as a stream, with
+    /// the task giving the report or the error the session ended with. The
+    /// stream ends after [`EventKind::SessionFinished`] or [`EventKind::SessionFailed`].
+    /// Dropping the stream stops the session once it next reports progress.
+    pub fn stream(
+        self,
+    ) -> (
+        impl Stream<Item = Event> + Send + 'static,
+        JoinHandle<Result<SessionReport, WscError>>,
+    ) {
+        let (event_tx, event_rx) = channel::<Event>(EVENT_BUFFER_SIZE);
+        let session = spawn(self.run(event_tx));
+        let events = stream::unfold(event_rx, |mut event_rx| async move {
+            event_rx.recv().await.map(|event| (event, event_rx))
+        });
+        (events, session)
+    }
+
+    async fn run_with_sink(mut self, events: EventSink) -> Result<SessionReport, WscError> {
+        let http = match self.fetcher {
+            Some(_) => None,
+            None => {
+                // Invalid links are reported by the download
+                let seeds: Vec<Url> = self
+                    .links
+                    .iter()
+                    .filter_map(|link| Url::parse(link).ok())
+                    .collect();
+                Some(HttpClient::new(&self.rule.http, &seeds))
+            }
+        };
+        let result = match http.transpose() {
+            Ok(Some(http)) => {
+                self.fetcher = Some(Arc::new(http.fetcher()));
+                let result = self.download(Some(&http), &events).await;
+                // Saved even when the session failed, E.g for the cookies of a login
+                match http.save_cookies() {
+                    Ok(()) => result,
+                    Err(e) => result.and(Err(e)),
+                }
+            }
+            Ok(None) => self.download(None, &events).await,
+            Err(e) => Err(e),
+        };
+        let stats = events.stats();
+        let kind = match &result {
+            Ok(_) => EventKind::SessionFinished { stats },
+            Err(error) => EventKind::SessionFailed {
+                error: error.clone(),
+                stats,
             },
-            file_name: Some("index.html".to_string()),
-            session: session_lock.clone(),
-            client: client.clone(),
-        },
-    )
-    .await
-    {
-        Err(e) => return Err(e),
-        Ok(some_links) => {
-            if some_links.is_some() {
-                some_links.unwrap()
-            } else {
-                Vec::new()
+        };
+        events.flush().await;
+        events.emit(None, 0, kind).await;
+        events.flush().await;
+        result
+    }
+
+    /// `http` is the client of the default fetcher, None when another fetcher was given.
+    async fn download(
+        self,
+        http: Option<&HttpClient>,
+        events: &EventSink,
+    ) -> Result<SessionReport, WscError> {
+        let Downloader {
+            session_id,
+            links,
+            dest_dir,
+            mut rule,
+            fetcher,
+            ..
+        } = self;
+        rule.validate()?;
+        let filters = Arc::new(FilterSet::new(&rule.filters)?);
+
+        let mut seeds: Vec<(String, Url)> = Vec::new();
+        for link in links {
+            let url = match Url::parse(&link) {
+                Ok(u) => rule.normalization.normalize(&u),
+                Err(_) => return Err(WscError::InvalidUrl(link)),
+            };
+            if !seeds.iter().any(|(_, seed)| seed == &url) {
+                seeds.push((link, url));
             }
         }
-    };
+        let initial_url = seeds[0].1.clone();
+        events
+            .emit(Some(&initial_url), 0, EventKind::SessionStarted)
+            .await;
+        for (_, url) in seeds.iter().skip(1) {
+            events.emit(Some(url), 0, EventKind::PageQueued).await;
+        }
 
-    while rule.max_level > 0 {
-        let more_pages = rule.max_level - 1 > 0;
-        let mut new_pages: Vec<(String, Url)> = Vec::new();
-        for (raw_link, pg_url) in a_href_links.iter() {
-            if let Some(mut pages) = download_page_with_static_resources(
-                update_tx.clone(),
-                more_pages,
-                raw_link,
-                pg_url,
+        if let Err(e) = fs::create_dir_all(&dest_dir).await {
+            tracing::error!("Failed to create destination directory\nError : {}", e);
+            return Err(WscError::ErrorCreatingDestinationDirectory(e.to_string()));
+        };
+
+        if http.is_some() && !rule.http.tls.insecure_hosts.is_empty() {
+            tracing::warn!(
+                "Certificates of {} aren't verified",
+                rule.http.tls.insecure_hosts.join(", ")
+            );
+            events
+                .emit(
+                    Some(&initial_url),
+                    0,
+                    EventKind::TlsVerificationDisabled {
+                        hosts: rule.http.tls.insecure_hosts.clone(),
+                    },
+                )
+                .await;
+        }
+        if let Some(login) = &rule.http.login {
+            let content = match http {
+                Some(http) => format!("Logged in, ended on {}", http.login(login).await?),
+                None => {
+                    tracing::warn!("Login skipped, it needs the default fetcher");
+                    "Login skipped, it needs the default fetcher".to_string()
+                }
+            };
+            events
+                .emit(
+                    Some(&initial_url),
+                    0,
+                    EventKind::Message {
+                        content,
+                        is_error: http.is_none(),
+                    },
+                )
+                .await;
+        }
+
+        // Set by run_with_sink when none was given
+        let fetcher = fetcher.expect("fetcher set before the download");
+
+        let session_lock = Arc::new(RwLock::new(Session::new(
+            seeds.iter().map(|(_, url)| url.clone()).collect(),
+            &session_id,
+        )));
+
+        let mut a_href_links: Vec<(String, Url)> = Vec::new();
+        for (index, (link, seed_url)) in seeds.iter().enumerate() {
+            let file_name = if seeds.len() == 1 {
+                "index.html".to_string()
+            } else {
+                seed_file_name(index, seed_url)
+            };
+            let pages = download_page_with_static_resources(
+                rule.max_level > 0,
+                link,
+                seed_url,
                 DownloadProp {
-                    rule: rule.clone(),
-                    file_name: None,
-                    session_id: session_id.to_string(),
                     dest_dir: dest_dir.to_string(),
-                    client: client.clone(),
+                    rule: rule.for_initial_page(),
+                    file_name: Some(file_name),
                     session: session_lock.clone(),
+                    fetcher: fetcher.clone(),
+                    events: events.clone(),
+                    filters: filters.clone(),
+                    depth: 0,
                 },
             )
-            .await?
-            {
-                new_pages.append(&mut pages);
-            };
+            .await?;
+            a_href_links.extend(pages.unwrap_or_default());
+        }
+
+        if rule.sitemap.enabled {
+            let seed_urls = session_lock.read().await.seed_urls.clone();
+            let urls = sitemap::sitemap_urls(&seed_urls, &rule.sitemap, &fetcher, events).await;
+            events
+                .emit(
+                    Some(&seed_urls[0]),
+                    0,
+                    EventKind::Message {
+                        content: format!("{} urls found in sitemaps", urls.len()),
+                        is_error: false,
+                    },
+                )
+                .await;
+            let (logout_links, urls): (Vec<_>, Vec<_>) = urls
+                .into_iter()
+                .map(|url| (url.to_string(), rule.normalization.normalize(&url)))
+                .partition(|(_, url)| is_logout_link(url, "", &rule.http.logout_patterns));
+            for (_, url) in logout_links {
+                let mut session = session_lock.write().await;
+                if session.logout_links.insert(url.to_string()) {
+                    tracing::debug!("Skipping {url}, {}", SkipReason::LogoutLink);
+                    session.record_outcome(
+                        &url,
+                        1,
+                        true,
+                        Outcome::Skipped {
+                            reason: SkipReason::LogoutLink,
+                        },
+                    );
+                    drop(session);
+                    events
+                        .emit(
+                            Some(&url),
+                            1,
+                            EventKind::ResourceSkipped {
+                                reason: SkipReason::LogoutLink,
+                            },
+                        )
+                        .await;
+                }
+            }
+            // Sitemap urls are downloaded even when links aren't followed
+            if !urls.is_empty() && rule.max_level == 0 {
+                rule.max_level = 1;
+            }
+            a_href_links.extend(urls);
+        }
+
+        let mut depth = 0;
+        while rule.max_level > 0 {
+            if session_lock.read().await.budget_exhausted.is_some() {
+                break;
+            }
+            depth += 1;
+            let mut queued = HashSet::new();
+            a_href_links.retain(|(_, url)| queued.insert(url.to_string()));
+            for (_, pg_url) in a_href_links.iter() {
+                events
+                    .emit(Some(pg_url), depth, EventKind::PageQueued)
+                    .await;
+            }
+            let more_pages = rule.max_level - 1 > 0;
+            let mut new_pages: Vec<(String, Url)> = Vec::new();
+            for (raw_link, pg_url) in a_href_links.iter() {
+                if let Some(mut pages) = download_page_with_static_resources(
+                    more_pages,
+                    raw_link,
+                    pg_url,
+                    DownloadProp {
+                        rule: rule.clone(),
+                        file_name: None,
+                        dest_dir: dest_dir.to_string(),
+                        fetcher: fetcher.clone(),
+                        session: session_lock.clone(),
+                        events: events.clone(),
+                        filters: filters.clone(),
+                        depth,
+                    },
+                )
+                .await?
+                {
+                    new_pages.append(&mut pages);
+                };
+            }
+            a_href_links.clear();
+            a_href_links.append(&mut new_pages);
+            rule.max_level -= 1;
+        }
+
+        let session = session_lock.read().await;
+        let (raw_links, res_f_loc) = session.link_rewrites();
+
+        for (page_url, link_info) in session.processed_pages.iter() {
+            let encoding = session
+                .page_encodings
+                .get(&link_info.file_path)
+                .copied()
+                .unwrap_or(UTF_8);
+            link_page_to_static_resources(
+                &link_info.file_path,
+                encoding,
+                rule.convert_to_utf8,
+                &raw_links,
+                &res_f_loc,
+            )
+            .await?;
+            events
+                .emit(
+                    Url::parse(page_url).ok().as_ref(),
+                    link_info.depth,
+                    EventKind::PageRewritten {
+                        file_path: link_info.file_path.clone(),
+                    },
+                )
+                .await;
+        }
+        if seeds.len() > 1 {
+            let seed_urls: Vec<&Url> = seeds.iter().map(|(_, url)| url).collect();
+            let index_path = write_seed_index(&dest_dir, &seed_urls, &session).await?;
+            events
+                .emit(
+                    Some(&initial_url),
+                    0,
+                    EventKind::Message {
+                        content: format!("Index of the seeds written to {index_path}"),
+                        is_error: false,
+                    },
+                )
+                .await;
         }
-        a_href_links.clear();
-        a_href_links.append(&mut new_pages);
-        rule.max_level -= 1;
+        tracing::debug!("Session {} completed", session.session_id);
+        Ok(session.report(&dest_dir, events.stats()))
     }
+}
 
-    let mut raw_links: Vec<String> = Vec::new();
-    let mut res_f_loc: Vec<String> = Vec::new();
+/// Name of the local copy of a seed, when there are more than one. E.g
+/// `2-example.com-landing-spring.html` for `https://example.com/landing/spring`
+fn seed_file_name(index: usize, url: &Url) -> String {
+    let slug: String = format!("{}{}", url.host_str().unwrap_or_default(), url.path())
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let slug: String = slug
+        .trim_matches('-')
+        .chars()
+        .take(MAX_SEED_SLUG_LEN)
+        .collect();
+    format!("{}-{slug}.html", index + 1)
+}
+
+/// Writes `index.html` in `dest_dir`, linking to the local copy of each seed.
+/// Returns the path to the index.
+async fn write_seed_index(
+    dest_dir: &str,
+    seeds: &[&Url],
+    session: &Session,
+) -> Result<String, WscError> {
+    let mut items = String::new();
+    for url in seeds {
+        let escaped_url = escape_html(url.as_str());
+        let item = match session.local_copy(url.as_str(), true) {
+            Some(info) => {
+                let path = Path::new(&info.file_path);
+                let href = path
+                    .strip_prefix(dest_dir)
+                    .unwrap_or(path)
+                    .to_string_lossy()
+                    .to_string();
+                format!(
+                    r#"<li><a href="{}">{escaped_url}</a></li>"#,
+                    escape_html(&href)
+                )
+            }
+            None => format!("<li>{escaped_url} (not downloaded)</li>"),
+        };
+        items.push_str(&format!("    {item}\n"));
+    }
+    let html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n  <meta charset=\"utf-8\">\n  <title>{id}</title>\n</head>\n<body>\n  <h1>{id}</h1>\n  <ul>\n{items}  </ul>\n</body>\n</html>\n",
+        id = escape_html(&session.session_id)
+    );
+    let index_path = PathBuf::from(dest_dir).join("index.html");
+    let index_path = index_path.to_string_lossy().to_string();
+    fs::write(&index_path, html)
+        .await
+        .map_err(|e| WscError::FileOperationError {
+            file_name: index_path.clone(),
+            message: format!("{} | {}", e, e.kind()),
+        })?;
+    Ok(index_path)
+}
 
-    let session = session_lock.read().await;
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
 
-    for (_, link_info) in session
-        .processed_static_files
-        .iter()
-        .chain(session.processed_pages.iter())
+/// Why the scope or the filters of the rule leave `url` out, if they do.
+/// Checked before any request is made for it.
+pub(crate) async fn skip_reason(
+    url: &Url,
+    is_page: bool,
+    prop: &DownloadProp,
+) -> Option<SkipReason> {
+    let scope = if is_page {
+        &prop.rule.page_scope
+    } else {
+        &prop.rule.resource_scope
+    };
+    let in_scope = {
+        let session = prop.session.read().await;
+        session
+            .seed_urls
+            .iter()
+            .any(|seed_url| scope.contains(seed_url, url))
+    };
+    if !in_scope {
+        return Some(SkipReason::OutOfScope);
+    }
+    match prop.filters.first_match(url, is_page) {
+        Some((index, filter)) if filter.action == FilterAction::Exclude => {
+            Some(SkipReason::Filtered {
+                index,
+                filter: filter.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The budget that ran out, if one did. Otherwise counts the page or
+/// resource about to be requested against the budget.
+async fn budget_exhausted(is_page: bool, prop: &DownloadProp) -> Option<BudgetKind> {
+    let stats = prop.events.stats();
+    let budget = {
+        let mut session = prop.session.write().await;
+        if session.budget_exhausted.is_some() {
+            return session.budget_exhausted;
+        }
+        let requested = if is_page {
+            &mut session.pages_requested
+        } else {
+            &mut session.resources_requested
+        };
+        match prop.rule.budget.exhausted(is_page, *requested, &stats) {
+            None => {
+                *requested += 1;
+                return None;
+            }
+            Some(budget) => budget,
+        }
+    };
+    exhaust_budget(budget, prop).await;
+    Some(budget)
+}
+
+/// Records that `budget` ran out, unless another one already did. Reported once.
+pub(crate) async fn exhaust_budget(budget: BudgetKind, prop: &DownloadProp) {
     {
-        raw_links.push(format!(
-            r#"{attribute}="{relative_link}""#,
-            attribute = link_info.element_attribute,
-            relative_link = link_info.relative_link
-        ));
-        res_f_loc.push(format!(
-            r#"{attribute}="{file_path}""#,
-            attribute = link_info.element_attribute,
-            file_path = link_info.file_path
-        ));
+        let mut session = prop.session.write().await;
+        if session.budget_exhausted.is_some() {
+            return;
+        }
+        session.budget_exhausted = Some(budget);
     }
+    tracing::info!("{budget} budget exhausted, stopping the crawl");
+    prop.events
+        .emit(None, prop.depth, EventKind::BudgetExhausted { budget })
+        .await;
+}
+
+/// Records and reports a url left out by the scope, the filters or the budget.
+async fn skip(url: &Url, is_page: bool, reason: SkipReason, prop: &DownloadProp) {
+    tracing::debug!("Skipping {url}, {reason}");
+    prop.session.write().await.record_outcome(
+        url,
+        prop.depth,
+        is_page,
+        Outcome::Skipped {
+            reason: reason.clone(),
+        },
+    );
+    prop.events
+        .emit(Some(url), prop.depth, EventKind::ResourceSkipped { reason })
+        .await;
+}
 
-    for (_, link_info) in session_lock.read().await.processed_pages.iter() {
-        link_page_to_static_resources(&link_info.file_path, &raw_links, &res_f_loc).await?;
+/// Records `url` as a duplicate of the page `of`, links to it will point to `file_path`.
+async fn record_duplicate(
+    url: &Url,
+    relative_link: &str,
+    of: String,
+    file_path: String,
+    prop: &DownloadProp,
+) {
+    prop.session.write().await.aliases.insert(
+        url.to_string(),
+        LinkInfo {
+            relative_link: relative_link.to_string(),
+            file_path,
+            element_attribute: "href".to_string(),
+            depth: prop.depth,
+        },
+    );
+    skip(url, true, SkipReason::Duplicate { of }, prop).await;
+}
+
+/// Checks the canonical url of a downloaded page. When it belongs to an
+/// already processed page the downloaded file is removed and the page is
+/// recorded as a duplicate, otherwise the canonical url is remembered for
+/// the pages to come.
+async fn is_duplicate_page(
+    html: &str,
+    relative_link: &str,
+    full_link: &Url,
+    file_path: &str,
+    prop: &DownloadProp,
+) -> bool {
+    let canonical = match get_canonical_link(html, full_link) {
+        Some(url) => prop.rule.normalization.normalize(&url),
+        None => return false,
+    };
+    if &canonical == full_link {
+        return false;
+    }
+    let mut session = prop.session.write().await;
+    let page = session
+        .canonical_page(canonical.as_str())
+        .filter(|(url, _)| url.as_str() != full_link.as_str())
+        .map(|(url, info)| (url.clone(), info.file_path.clone()));
+    match page {
+        None => {
+            session
+                .canonical_urls
+                .entry(canonical.to_string())
+                .or_insert_with(|| full_link.to_string());
+            false
+        }
+        Some((of, existing_file)) => {
+            session.processed_pages.remove(full_link.as_str());
+            drop(session);
+            if let Err(e) = fs::remove_file(file_path).await {
+                tracing::warn!("Error removing duplicate page {file_path} : {e}");
+            }
+            record_duplicate(full_link, relative_link, of, existing_file, prop).await;
+            true
+        }
     }
-    tracing::debug!("Session completed");
-    Ok(())
 }
 
 #[tracing::instrument]
 async fn download_page_with_static_resources(
-    update_tx: Sender<Update>,
     more_pages: bool,
     relative_link: &str,
     full_link: &Url,
     prop: DownloadProp,
 ) -> Result<Option<Vec<(String, Url)>>, WscError> {
-    // Check if current page and initial page belong to the same host.
-    if let Some(initial_page_host) = prop.session.read().await.initial_url.host() {
-        if let Some(host) = full_link.host() {
-            if initial_page_host.to_string() != host.to_string() {
-                tracing::debug!("Skipping {}", full_link.to_string());
+    // Seeds are always downloaded, scope and filters apply to the pages they link to.
+    if prop.depth > 0 {
+        if let Some(reason) = skip_reason(full_link, true, &prop).await {
+            skip(full_link, true, reason, &prop).await;
+            return Ok(None);
+        }
+        // Pages are deduplicated when queued, but a level can link to a page
+        // processed after it's links were extracted.
+        let processed = prop
+            .session
+            .read()
+            .await
+            .local_copy(full_link.as_str(), true)
+            .is_some();
+        if processed {
+            tracing::debug!("Skipping {full_link}, already processed");
+            return Ok(None);
+        }
+        // A page that an already downloaded page declared as it's canonical url
+        if prop.rule.normalization.follow_canonical {
+            let page = prop
+                .session
+                .read()
+                .await
+                .canonical_page(full_link.as_str())
+                .filter(|(url, _)| url.as_str() != full_link.as_str())
+                .map(|(url, info)| (url.clone(), info.file_path.clone()));
+            if let Some((of, file_path)) = page {
+                record_duplicate(full_link, relative_link, of, file_path, &prop).await;
                 return Ok(None);
             }
         }
     }
 
+    if let Some(budget) = budget_exhausted(true, &prop).await {
+        skip(
+            full_link,
+            true,
+            SkipReason::BudgetExhausted { budget },
+            &prop,
+        )
+        .await;
+        return Ok(None);
+    }
+
     let mut pages: Option<Vec<(String, Url)>> = None;
 
     match download_file(
-        prop.session_id.to_string(),
         DownloadItem {
             link: full_link.to_owned(),
             destination_dir: PathBuf::from(&prop.dest_dir),
+            depth: prop.depth,
+            is_page: true,
         },
-        &prop.client,
-        &prop.rule,
-        update_tx.clone(),
-        prop.file_name.clone(),
+        &prop,
     )
     .await
     {
-        Err(e) => return Err(e),
-        Ok(res) => {
-            if res.is_some() {
-                let page_f_path = res.unwrap();
+        Err(e) => {
+            prop.session.write().await.record_outcome(
+                full_link,
+                prop.depth,
+                true,
+                Outcome::Failed { error: e.clone() },
+            );
+            return Err(e);
+        }
+        Ok(result) => {
+            let requested = full_link;
+            // Links in the page are relative to the url it was served from
+            let full_link = &result.final_url.clone();
+            // The page redirected to an already downloaded page, links to it
+            // are rewritten through the session redirects.
+            let reused = result.outcome.is_none();
+            // A cached copy was written by an earlier session, with it's `<meta>`
+            // charset updated. The header describes the bytes served, not these.
+            let content_type = match result.outcome {
+                Some(Outcome::Cached { .. }) => None,
+                _ => result.content_type.clone(),
+            };
+            let is_html = mime::is_html(result.mime_type.as_deref());
+            let recorded = {
+                let mut session = prop.session.write().await;
+                if prop.file_name.is_some() {
+                    session.redirect_seed(requested, full_link);
+                }
+                session.record_download(prop.depth, true, result)
+            };
+            if reused {
+                return Ok(None);
+            }
+            // A link to a file that isn't HTML, E.g a PDF, is kept as it is
+            if let Some(f_path) = recorded.as_ref().filter(|_| !is_html) {
+                prop.session.write().await.processed_static_files.insert(
+                    full_link.to_string(),
+                    LinkInfo {
+                        relative_link: relative_link.to_string(),
+                        file_path: f_path.clone(),
+                        element_attribute: "href".to_string(),
+                        depth: prop.depth,
+                    },
+                );
+                return Ok(None);
+            }
+            if let Some(page_f_path) = recorded {
                 prop.session.write().await.processed_pages.insert(
                     full_link.to_string(),
                     LinkInfo {
                         relative_link: relative_link.to_string(),
                         file_path: page_f_path.to_string(),
                         element_attribute: "href".to_string(),
+                        depth: prop.depth,
                     },
                 );
-                let static_res_links: Vec<(String, Url, String)> =
-                    match fs::read_to_string(&page_f_path).await {
-                        // This might mostly be a UTF-8 error and rarely a read operation error
-                        // We only abort if it's the initial page.
-                        Err(e) => {
-                            tracing::error!("Error reading file {}\nError : {}", page_f_path, e);
-                            update_tx
-                                .send(Update::MessageUpdate(Message {
-                                    session_id: prop.session_id.clone(),
+                let page = fs::read(&page_f_path).await.map(|bytes| {
+                    let encoding = detect_encoding(&bytes, content_type.as_deref(), full_link);
+                    (decode(&bytes, encoding), encoding)
+                });
+                let static_res_links: Vec<(String, Url, String)> = match page {
+                    // We only abort if it's a seed.
+                    Err(e) => {
+                        tracing::error!("Error reading file {}\nError : {}", page_f_path, e);
+                        prop.events
+                            .emit(
+                                Some(full_link),
+                                prop.depth,
+                                EventKind::Message {
                                     content: format!("Error reading file for resource links. {e}"),
-                                    resource_name: "".to_string(),
                                     is_error: false,
-                                }))
-                                .await
-                                .unwrap();
-                            // This is valid for only the initial page
-                            if prop.file_name.is_some() {
-                                return Err(WscError::FileOperationError {
-                                    file_name: page_f_path,
-                                    message: format!("{} | {}", e, e.kind()),
-                                });
-                            }
+                                },
+                            )
+                            .await;
+                        // This is valid for only the seeds
+                        if prop.file_name.is_some() {
+                            return Err(WscError::FileOperationError {
+                                file_name: page_f_path,
+                                message: format!("{} | {}", e, e.kind()),
+                            });
+                        }
+                        return Ok(None);
+                    }
+                    Ok((html, encoding)) => {
+                        tracing::debug!("Encoding of {full_link} is {}", encoding.name());
+                        prop.session
+                            .write()
+                            .await
+                            .page_encodings
+                            .insert(page_f_path.clone(), encoding);
+                        if prop.rule.normalization.follow_canonical
+                            && is_duplicate_page(
+                                &html,
+                                relative_link,
+                                full_link,
+                                &page_f_path,
+                                &prop,
+                            )
+                            .await
+                        {
                             return Ok(None);
                         }
-                        Ok(html) => {
-                            let dest_dir = &prop.dest_dir;
-                            let session = prop.session.read().await;
-                            if more_pages {
-                                // If a page has already been downloaded and all links replaced, the
-                                // links to the static resources will point to their local files. Which
-                                // we don't want to try downloading (404). Hence the filtering.
-                                pages = Some(
-                                    get_anchor_links(&html, full_link.to_owned())
-                                        .into_iter()
-                                        .filter(|(relative_link, url)| {
-                                            !relative_link.contains(dest_dir)
-                                                && !session
-                                                    .processed_pages
-                                                    .contains_key(&url.to_string())
-                                        })
-                                        .collect(),
-                                );
-                            }
-                            get_static_resource_links(&html, full_link.to_owned())
+                        let normalization = &prop.rule.normalization;
+                        let dest_dir = &prop.dest_dir;
+                        // If a page has already been downloaded and all links replaced, the
+                        // links to the static resources will point to their local files. Which
+                        // we don't want to try downloading (404). Hence the filtering.
+                        let anchor_links: Vec<(String, Url)> = if more_pages {
+                            get_anchor_links(&html, full_link.to_owned())
                                 .into_iter()
-                                .filter(|(relative_link, url, _)| {
-                                    !relative_link.contains(dest_dir)
-                                        && !session
-                                            .processed_static_files
-                                            .contains_key(&url.to_string())
+                                .filter(|(relative_link, _)| !relative_link.contains(dest_dir))
+                                .map(|(relative_link, url)| {
+                                    (relative_link, normalization.normalize(&url))
                                 })
                                 .collect()
+                        } else {
+                            Vec::new()
+                        };
+                        let resource_links: Vec<(String, Url, String)> =
+                            get_static_resource_links(&html, full_link.to_owned())
+                                .into_iter()
+                                .filter(|(relative_link, _, _)| !relative_link.contains(dest_dir))
+                                .map(|(relative_link, url, attribute)| {
+                                    (relative_link, normalization.normalize(&url), attribute)
+                                })
+                                .collect();
+
+                        // Following a logout link would end a logged in session
+                        let logout_links: HashSet<Url> =
+                            get_logout_links(&html, full_link, &prop.rule.http.logout_patterns)
+                                .iter()
+                                .map(|url| normalization.normalize(url))
+                                .collect();
+                        let (logout_links, anchor_links): (Vec<_>, Vec<_>) = anchor_links
+                            .into_iter()
+                            .partition(|(_, url)| logout_links.contains(url));
+                        for (_, url) in logout_links {
+                            let found = prop
+                                .session
+                                .write()
+                                .await
+                                .logout_links
+                                .insert(url.to_string());
+                            if found {
+                                skip(&url, true, SkipReason::LogoutLink, &prop).await;
+                            }
                         }
-                    };
+
+                        let mut session = prop.session.write().await;
+                        for (relative_link, url) in anchor_links
+                            .iter()
+                            .map(|(relative_link, url)| (relative_link, url))
+                            .chain(resource_links.iter().map(|(l, url, _)| (l, url)))
+                        {
+                            session.record_link(url, relative_link);
+                        }
+                        if more_pages {
+                            pages = Some(
+                                anchor_links
+                                    .into_iter()
+                                    .filter(|(_, url)| {
+                                        session.local_copy(url.as_str(), true).is_none()
+                                    })
+                                    .collect(),
+                            );
+                        }
+                        // Links differing only by what normalization removes are downloaded once
+                        let mut unique = HashSet::new();
+                        resource_links
+                            .into_iter()
+                            .filter(|(_, url, _)| {
+                                session.local_copy(url.as_str(), false).is_none()
+                                    && unique.insert(url.clone())
+                            })
+                            .collect()
+                    }
+                };
                 let mut dld_tasks: Vec<JoinHandle<Option<WscError>>> = Vec::new();
                 for (raw_link, parsed_link, attrib) in static_res_links {
-                    let task = download_static_resource(
-                        update_tx.clone(),
-                        raw_link,
-                        parsed_link,
-                        attrib,
-                        prop.clone(),
-                    );
+                    let task =
+                        download_static_resource(raw_link, parsed_link, attrib, prop.clone());
                     dld_tasks.push(task);
                 }
 
@@ -341,6 +1062,7 @@ async fn download_page_with_static_resources(
                                 let err = opt_error.unwrap();
                                 if matches!(err, WscError::DestinationDirectoryDoesNotExist(_))
                                     || matches!(err, WscError::NetworkError(_))
+                                    || matches!(err, WscError::ProxyError(_))
                                     || (matches!(
                                         err,
                                         WscError::ErrorStatusCode {
@@ -368,7 +1090,6 @@ async fn download_page_with_static_resources(
 }
 
 fn download_static_resource(
-    update_tx: Sender<Update>,
     relative_link: String,
     full_link: Url,
     attribute: String,
@@ -376,45 +1097,72 @@ fn download_static_resource(
 ) -> JoinHandle<Option<WscError>> {
     prop.file_name = None;
     spawn(async move {
-        return match download_file(
-            prop.session_id,
+        if let Some(reason) = skip_reason(&full_link, false, &prop).await {
+            skip(&full_link, false, reason, &prop).await;
+            return None;
+        }
+        if let Some(budget) = budget_exhausted(false, &prop).await {
+            skip(
+                &full_link,
+                false,
+                SkipReason::BudgetExhausted { budget },
+                &prop,
+            )
+            .await;
+            return None;
+        }
+        match download_file(
             DownloadItem {
                 link: full_link.clone(),
-                destination_dir: PathBuf::from(prop.dest_dir),
+                destination_dir: PathBuf::from(&prop.dest_dir),
+                depth: prop.depth,
+                is_page: false,
             },
-            &prop.client,
-            &prop.rule,
-            update_tx,
-            None,
+            &prop,
         )
         .await
         {
-            Ok(opt_f_path) => {
-                if let Some(f_path) = opt_f_path {
-                    prop.session.write().await.processed_static_files.insert(
-                        full_link.to_string(),
+            Ok(result) => {
+                let final_url = result.final_url.to_string();
+                let reused = result.outcome.is_none();
+                let mut session = prop.session.write().await;
+                let recorded = session.record_download(prop.depth, false, result);
+                if let Some(f_path) = recorded.filter(|_| !reused) {
+                    session.processed_static_files.insert(
+                        final_url,
                         LinkInfo {
                             relative_link,
                             file_path: f_path,
                             element_attribute: attribute,
+                            depth: prop.depth,
                         },
                     );
                 }
-                return None;
+                None
             }
-            Err(e) => Some(e),
-        };
+            Err(e) => {
+                prop.session.write().await.record_outcome(
+                    &full_link,
+                    prop.depth,
+                    false,
+                    Outcome::Failed { error: e.clone() },
+                );
+                Some(e)
+            }
+        }
     })
 }
 
 #[tracing::instrument]
 async fn link_page_to_static_resources(
     page_file_path: &str,
+    encoding: &'static Encoding,
+    convert_to_utf8: bool,
     raw_links: &[String],
     res_f_loc: &[String],
 ) -> Result<(), WscError> {
-    let html_string = match fs::read_to_string(&page_file_path).await {
-        Ok(s) => s,
+    let html_string = match fs::read(&page_file_path).await {
+        Ok(bytes) => decode(&bytes, encoding),
         Err(e) => {
             tracing::error!(
                 "Error reading file {}\nError : {} | {}",
@@ -429,17 +1177,9 @@ async fn link_page_to_static_resources(
         }
     };
 
-    let html_string_bytes = html_string.as_bytes();
-
-    let mut final_html_bytes = Vec::new();
-
     let ac = aho_corasick::AhoCorasick::new(raw_links);
-
-    if let Err(e) = ac.stream_replace_all(html_string_bytes, &mut final_html_bytes, res_f_loc) {
-        // Shouldn't happen though
-        tracing::error!("AHOCORASICK ERROR : {}", e);
-        return Err(WscError::UnknownError(e.to_string()));
-    };
+    let final_html = ac.replace_all(&html_string, res_f_loc);
+    let final_html_bytes = encode(&final_html, encoding, convert_to_utf8);
 
     let mut file = match fs::OpenOptions::new()
         .create(true)
@@ -463,7 +1203,12 @@ async fn link_page_to_static_resources(
         }
     };
 
-    if let Err(e) = file.write_all(&final_html_bytes).await {
+    // Written in the background otherwise, it could still be going when the session ends
+    let written = match file.write_all(&final_html_bytes).await {
+        Ok(()) => file.flush().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
         tracing::error!("Error writing to file : {}\nError : {}", page_file_path, e);
         return Err(WscError::FileOperationError {
             file_name: page_file_path.into(),
@@ -473,3 +1218,148 @@ async fn link_page_to_static_resources(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page served as windows-1252 is written as UTF-8 by the first run,
+    /// the second run reads it's cached copy with it's `<meta>` charset.
+    #[tokio::test]
+    async fn cached_page_is_decoded_with_its_meta_charset() {
+        let dir = std::env::temp_dir().join(format!("wsclone-charset-{}", std::process::id()));
+        let fixtures = dir.join("fixtures");
+        std::fs::create_dir_all(fixtures.join("example.com")).unwrap();
+        std::fs::write(
+            fixtures.join("example.com/index.html"),
+            b"<html><head><title>Caf\xe9</title></head><body>D\xe9j\xe0 vu</body></html>",
+        )
+        .unwrap();
+        std::fs::write(
+            fixtures.join("example.com/index.html.headers"),
+            "HTTP/1.1 200 OK\nContent-Type: text/html; charset=windows-1252\n",
+        )
+        .unwrap();
+        let dest_dir = dir.join("site");
+        let rule = DownloadRule {
+            convert_to_utf8: true,
+            ..DownloadRule::default()
+        };
+
+        for run in ["first", "second"] {
+            let fetcher = ReplayFetcher::from_dir(&fixtures).unwrap();
+            Downloader::new(
+                run,
+                "https://example.com/",
+                &dest_dir.to_string_lossy(),
+                rule.clone(),
+            )
+            .fetcher(Arc::new(fetcher))
+            .run_with_observer(Arc::new(|_: &Event| {}))
+            .await
+            .unwrap();
+            let page = std::fs::read_to_string(dest_dir.join("index.html")).unwrap();
+            assert!(page.contains("<title>Café</title>"), "{run} run: {page}");
+            assert!(page.contains("Déjà vu"), "{run} run: {page}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Logout links are skipped once however many pages link to them, and
+    /// sitemap urls matching the logout patterns aren't requested.
+    #[tokio::test]
+    async fn logout_links_are_skipped_once() {
+        let dir = std::env::temp_dir().join(format!("wsclone-logout-{}", std::process::id()));
+        let site = dir.join("fixtures/example.com");
+        std::fs::create_dir_all(&site).unwrap();
+        let page = r#"<html><body><a href="/a.html">A</a><a href="/logout">Bye</a></body></html>"#;
+        std::fs::write(site.join("index.html"), page).unwrap();
+        std::fs::write(site.join("a.html"), page).unwrap();
+        std::fs::write(
+            site.join("sitemap.xml"),
+            r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <url><loc>https://example.com/a.html</loc></url>
+                <url><loc>https://example.com/sign-out</loc></url>
+            </urlset>"#,
+        )
+        .unwrap();
+        let mut rule = DownloadRule {
+            max_level: 2,
+            ..DownloadRule::default()
+        };
+        rule.sitemap.enabled = true;
+        let skipped = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observed = skipped.clone();
+
+        let fetcher = ReplayFetcher::from_dir(dir.join("fixtures")).unwrap();
+        let report = Downloader::new(
+            "logout",
+            "https://example.com/",
+            &dir.join("site").to_string_lossy(),
+            rule,
+        )
+        .fetcher(Arc::new(fetcher))
+        .run_with_observer(Arc::new(move |event: &Event| {
+            if let EventKind::ResourceSkipped {
+                reason: SkipReason::LogoutLink,
+            } = event.kind
+            {
+                observed.lock().unwrap().push(event.url.clone().unwrap());
+            }
+        }))
+        .await
+        .unwrap();
+        let mut skipped = skipped.lock().unwrap().clone();
+        skipped.sort();
+        assert_eq!(
+            skipped,
+            ["https://example.com/logout", "https://example.com/sign-out"]
+        );
+        assert!(report
+            .urls
+            .iter()
+            .any(|url| url.url == "https://example.com/sign-out"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn streamed_session_gives_its_report() {
+        use futures::StreamExt;
+
+        let dir = std::env::temp_dir().join(format!("wsclone-stream-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("fixtures/example.com")).unwrap();
+        std::fs::write(dir.join("fixtures/example.com/index.html"), "<html></html>").unwrap();
+        let fetcher = ReplayFetcher::from_dir(dir.join("fixtures")).unwrap();
+        let (events, session) = Downloader::new(
+            "stream",
+            "https://example.com/",
+            &dir.join("site").to_string_lossy(),
+            DownloadRule::default(),
+        )
+        .fetcher(Arc::new(fetcher))
+        .stream();
+        let events: Vec<Event> = events.collect().await;
+        assert!(matches!(
+            events.last().unwrap().kind,
+            EventKind::SessionFinished { .. }
+        ));
+        let report = session.await.unwrap().unwrap();
+        assert_eq!(report.session_id, "stream");
+        assert_eq!(report.stats.pages, 1);
+
+        let (events, session) = Downloader::new(
+            "stream",
+            "not a url",
+            &dir.to_string_lossy(),
+            DownloadRule::default(),
+        )
+        .stream();
+        let events: Vec<Event> = events.collect().await;
+        assert!(matches!(
+            events.last().unwrap().kind,
+            EventKind::SessionFailed { .. }
+        ));
+        assert!(session.await.unwrap().is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
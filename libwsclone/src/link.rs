@@ -1,3 +1,4 @@
+use crate::login::is_logout_link;
 use scraper::{Html, Selector};
 use std::collections::HashSet;
 use tracing::{event, instrument, Level};
@@ -61,6 +62,24 @@ pub fn get_anchor_links(html_string: &str, page_url: Url) -> HashSet<(String, Ur
         .collect::<_>()
 }
 
+/// Gets the anchor links that look like they end the session, E.g
+/// "Sign out", by their url or text. See [`is_logout_link`].
+pub fn get_logout_links(html_string: &str, page_url: &Url, patterns: &[String]) -> HashSet<Url> {
+    if patterns.is_empty() {
+        return HashSet::new();
+    }
+    let html_document = Html::parse_document(html_string);
+    let anchor_tag_selector = Selector::parse("a[href]").unwrap();
+    html_document
+        .select(&anchor_tag_selector)
+        .filter_map(|element| {
+            let url = get_full_link(element.value().attr("href")?, page_url)?;
+            let text = element.text().collect::<String>();
+            is_logout_link(&url, &text, patterns).then_some(url)
+        })
+        .collect()
+}
+
 /// Gets all valid static web resource file links. Each tuple,
 /// has as first element, the link found in the page and the second
 /// element is a parsed URL object of that link in relation with the
@@ -81,13 +100,13 @@ pub fn get_static_resource_links(
         .chain(html_document.select(&js_tag_selector))
         .chain(html_document.select(&img_tag_selector))
         .map(|element| {
-            return if let Some(href) = element.value().attr("href") {
+            if let Some(href) = element.value().attr("href") {
                 (href, "href")
             } else if let Some(src) = element.value().attr("src") {
                 (src, "src")
             } else {
                 ("", "")
-            };
+            }
         })
         .map(|(relative_link, attrib)| {
             let full_link = get_full_link(relative_link, &page_url);
@@ -101,3 +120,12 @@ pub fn get_static_resource_links(
         })
         .collect::<_>()
 }
+
+/// Gets the url of the `<link rel="canonical">` of a page, if it has one.
+pub fn get_canonical_link(html_string: &str, page_url: &Url) -> Option<Url> {
+    let html_document = Html::parse_document(html_string);
+    let canonical_selector = Selector::parse(r#"link[href][rel~="canonical"]"#).unwrap();
+    html_document
+        .select(&canonical_selector)
+        .find_map(|element| get_full_link(element.value().attr("href")?.trim(), page_url))
+}
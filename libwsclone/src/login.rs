@@ -0,0 +1,158 @@
+use crate::compression::BodyDecoder;
+use crate::errors::{RuleError, WscError};
+use crate::fetch::ReqwestFetcher;
+use reqwest::header::LOCATION;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use url::Url;
+
+/// Most redirects followed after posting the login form
+const MAX_LOGIN_REDIRECTS: usize = 10;
+
+/// A login form posted before the crawl starts. The cookies the site sets
+/// are sent with every later request of the session, and saved to the
+/// cookie jar if there is one.
+///
+/// The login is made with the default fetcher only, it's skipped with a
+/// warning when another fetcher is used.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FormLogin {
+    /// Url the form is posted to, the `action` of the form
+    pub url: String,
+    /// The login succeeded if the page it ends on contains this text, E.g `Welcome back`
+    pub success_text: Option<String>,
+    /// The login succeeded if the url it ends on, after redirects, contains this text
+    pub success_url: Option<String>,
+    /// Form fields sent as they are, E.g the user name
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Form fields read from environment variables, by field name. E.g the password
+    #[serde(default)]
+    pub field_envs: BTreeMap<String, String>,
+}
+
+impl FormLogin {
+    pub(crate) fn validate(&self) -> Result<(), RuleError> {
+        let invalid = RuleError::InvalidLogin;
+        Url::parse(&self.url).map_err(|e| invalid(format!("url \"{}\" : {e}", self.url)))?;
+        if self
+            .fields
+            .keys()
+            .chain(self.field_envs.keys())
+            .any(|name| name.trim().is_empty())
+        {
+            return Err(invalid("field names can't be empty".to_string()));
+        }
+        if let Some((name, _)) = self
+            .field_envs
+            .iter()
+            .find(|(_, var)| var.trim().is_empty())
+        {
+            return Err(invalid(format!(
+                "environment variable of field {name} is empty"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Posts the form and checks the page it ends on. Redirects are
+    /// followed, cookies set on the way are kept by the client.
+    pub(crate) async fn run(&self, fetcher: &ReqwestFetcher) -> Result<Url, WscError> {
+        let mut form = self.fields.clone();
+        for (name, var) in &self.field_envs {
+            let value = std::env::var(var).map_err(|_| {
+                RuleError::InvalidLogin(format!("environment variable {var} isn't set"))
+            })?;
+            form.insert(name.clone(), value);
+        }
+        let mut url = Url::parse(&self.url).map_err(|_| WscError::InvalidUrl(self.url.clone()))?;
+        let send_error = |url: &Url, e: reqwest::Error| {
+            tracing::error!("Error sending login request to {url}\nError : {e}");
+            fetcher.send_error(url, &e)
+        };
+        let mut response = fetcher
+            .request(Method::POST, &url)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| send_error(&url, e))?;
+        let mut redirects = 0;
+        while response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| url.join(location).ok());
+            match location {
+                Some(location) if redirects < MAX_LOGIN_REDIRECTS => url = location,
+                Some(_) => return Err(WscError::TooManyRedirects(self.url.clone())),
+                None => break,
+            }
+            redirects += 1;
+            response = fetcher
+                .request(Method::GET, &url)
+                .send()
+                .await
+                .map_err(|e| send_error(&url, e))?;
+        }
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(WscError::LoginFailed(format!("{url} returned {status}")));
+        }
+        if let Some(expected) = &self.success_url {
+            if !url.as_str().contains(expected.as_str()) {
+                return Err(WscError::LoginFailed(format!(
+                    "ended on {url}, which doesn't contain \"{expected}\""
+                )));
+            }
+        }
+        if let Some(expected) = &self.success_text {
+            let decoding_error = |message: String| WscError::ContentDecodingError {
+                url: url.to_string(),
+                message,
+            };
+            let mut decoder = BodyDecoder::new(response.headers()).map_err(decoding_error)?;
+            let body = response.bytes().await.map_err(|e| send_error(&url, e))?;
+            let mut decoded = decoder.decode(&body).map_err(decoding_error)?;
+            decoded.extend(decoder.finish().map_err(decoding_error)?);
+            let body = String::from_utf8_lossy(&decoded);
+            if !body.contains(expected.as_str()) {
+                return Err(WscError::LoginFailed(format!(
+                    "{url} doesn't contain \"{expected}\""
+                )));
+            }
+        }
+        Ok(url)
+    }
+}
+
+/// Default `HttpOptions::logout_patterns`
+pub(crate) fn default_logout_patterns() -> Vec<String> {
+    [
+        "logout", "log-out", "log_out", "log out", "logoff", "log-off", "log off", "signout",
+        "sign-out", "sign_out", "sign out",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Whether a link looks like it ends the session, from it's url or text.
+pub(crate) fn is_logout_link(url: &Url, text: &str, patterns: &[String]) -> bool {
+    let target = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    }
+    .to_lowercase();
+    let text = text
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        target.contains(&pattern) || text.contains(&pattern)
+    })
+}
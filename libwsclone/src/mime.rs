@@ -0,0 +1,570 @@
+use phf::phf_map;
+use url::Url;
+
+/// Types telling nothing about the content, E.g the default of a server
+const GENERIC_MIME_TYPES: [&str; 6] = [
+    "application/octet-stream",
+    "application/unknown",
+    "application/x-unknown",
+    "binary/octet-stream",
+    "unknown/unknown",
+    "text/plain",
+];
+/// Types crawled for links
+const HTML_MIME_TYPES: [&str; 2] = ["text/html", "application/xhtml+xml"];
+/// Most bytes of the first chunk looked at for a textual signature
+const SNIFF_LENGTH: usize = 1024;
+
+/// The type of a downloaded file, from it's `Content-Type` header, the
+/// extension of it's url and it's first bytes.
+///
+/// A binary signature, E.g of a PNG image, always wins since servers get
+/// the header wrong more often than files lie. Otherwise the header wins
+/// when it's specific, unless it says HTML and the content doesn't look
+/// like it while the url has a known extension. Generic headers like
+/// `application/octet-stream` only come last. None when nothing is known.
+pub(crate) fn resolve(content_type: Option<&str>, url: &Url, first_chunk: &[u8]) -> Option<String> {
+    if let Some(sniffed) = sniff_binary(first_chunk) {
+        return Some(sniffed.to_string());
+    }
+    let header = content_type.and_then(essence);
+    let url_type = url_extension(url).and_then(|ext| guess_mime_type(&ext));
+    let text_type = sniff_text(first_chunk);
+    let specific = header
+        .as_deref()
+        .filter(|mime| !GENERIC_MIME_TYPES.contains(mime));
+    let mime_type = match (specific, url_type) {
+        (Some(header), Some(url_type)) if header != url_type => match text_type {
+            Some(text_type) if text_type == header => header,
+            Some(text_type) => text_type,
+            None if is_html(Some(header)) => url_type,
+            None => header,
+        },
+        (Some(header), _) => header,
+        (None, Some(url_type)) => url_type,
+        (None, None) => {
+            return text_type
+                .or_else(|| sniff_weak(first_chunk))
+                .map(str::to_string)
+                .or(header)
+        }
+    };
+    Some(mime_type.to_string())
+}
+
+/// Whether a file of this type is crawled for links. A type that couldn't
+/// be found is, pages without a type have always been.
+pub(crate) fn is_html(mime_type: Option<&str>) -> bool {
+    mime_type.is_none_or(|mime| HTML_MIME_TYPES.contains(&mime))
+}
+
+/// The extension of a type, with the dot. E.g `.svg` for `image/svg+xml`
+pub(crate) fn extension(mime_type: &str) -> Option<&'static str> {
+    MIME_TYPES.get(mime_type).copied()
+}
+
+/// Whether `file_name` already has an extension of `mime_type`, E.g
+/// `photo.jpeg` for `image/jpeg`.
+pub(crate) fn has_extension_of(file_name: &str, mime_type: &str) -> bool {
+    match file_name.rsplit_once('.') {
+        Some((name, ext)) if !name.is_empty() => {
+            let ext = ext.to_lowercase();
+            guess_mime_type(&ext) == Some(canonical(mime_type))
+                || extension(mime_type) == Some(format!(".{ext}").as_str())
+        }
+        _ => false,
+    }
+}
+
+/// Mime type for a file extension, without the dot.
+pub(crate) fn guess_mime_type(extension: &str) -> Option<&'static str> {
+    EXTENSIONS.get(extension).copied()
+}
+
+/// Lower cased extension of the last path segment, if it has one.
+pub(crate) fn url_extension(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    let (name, ext) = segment.rsplit_once('.')?;
+    if name.is_empty() || ext.is_empty() {
+        return None;
+    }
+    Some(ext.to_lowercase())
+}
+
+/// The type of a `Content-Type` value, without it's parameters and
+/// with aliases replaced. E.g `text/javascript` for `application/x-javascript; charset=utf-8`
+fn essence(content_type: &str) -> Option<String> {
+    let essence = content_type.split(';').next()?.trim().to_lowercase();
+    if !essence.contains('/') {
+        return None;
+    }
+    Some(canonical(&essence).to_string())
+}
+
+fn canonical(mime_type: &str) -> &str {
+    ALIASES.get(mime_type).copied().unwrap_or(mime_type)
+}
+
+/// Signatures that can't be mistaken for another type.
+fn sniff_binary(bytes: &[u8]) -> Option<&'static str> {
+    let riff =
+        |format: &[u8]| bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == format;
+    let mime_type = match bytes {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => "image/png",
+        [0xff, 0xd8, 0xff, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "image/gif",
+        _ if riff(b"WEBP") => "image/webp",
+        _ if riff(b"WAVE") => "audio/wav",
+        _ if riff(b"AVI ") => "video/x-msvideo",
+        [0x00, 0x00, 0x01, 0x00, ..] => "image/x-icon",
+        [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => "image/tiff",
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] if brand.len() >= 4 => match &brand[..4] {
+            b"avif" | b"avis" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            b"qt  " => "video/quicktime",
+            b"M4A " => "audio/mp4",
+            _ => "video/mp4",
+        },
+        [0x1a, 0x45, 0xdf, 0xa3, ..] => "video/webm",
+        [b'O', b'g', b'g', b'S', 0x00, ..] => "audio/ogg",
+        [b'I', b'D', b'3', ..] => "audio/mpeg",
+        [b'f', b'L', b'a', b'C', ..] => "audio/flac",
+        [b'%', b'P', b'D', b'F', b'-', ..] => "application/pdf",
+        [0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c, ..] => "application/x-7z-compressed",
+        [b'R', b'a', b'r', b'!', 0x1a, 0x07, ..] => "application/vnd.rar",
+        [0x00, b'a', b's', b'm', ..] => "application/wasm",
+        [b'w', b'O', b'F', b'F', ..] => "font/woff",
+        [b'w', b'O', b'F', b'2', ..] => "font/woff2",
+        [b'O', b'T', b'T', b'O', ..] => "font/otf",
+        [0x00, 0x01, 0x00, 0x00, 0x00, ..] => "font/ttf",
+        [b't', b't', b'c', b'f', ..] => "font/collection",
+        _ => return None,
+    };
+    Some(mime_type)
+}
+
+/// Signatures of containers of many types, E.g a `.docx` is a zip file.
+/// Only used when nothing else is known.
+fn sniff_weak(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [b'P', b'K', 0x03, 0x04, ..] => Some("application/zip"),
+        [0x1f, 0x8b, 0x08, ..] => Some("application/gzip"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ if bytes.iter().take(SNIFF_LENGTH).any(is_binary_byte) => {
+            Some("application/octet-stream")
+        }
+        _ => None,
+    }
+}
+
+/// Control characters not found in text, as in the WHATWG mime sniffing standard.
+fn is_binary_byte(byte: &u8) -> bool {
+    matches!(byte, 0x00..=0x08 | 0x0b | 0x0e..=0x1a | 0x1c..=0x1f)
+}
+
+/// Markup at the start of a text file, as in the WHATWG mime sniffing standard.
+fn sniff_text(bytes: &[u8]) -> Option<&'static str> {
+    let bytes = &bytes[..bytes.len().min(SNIFF_LENGTH)];
+    let text = String::from_utf8_lossy(bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes))
+        .trim_start()
+        .to_lowercase();
+    let starts_with_tag = |tag: &str| {
+        text.strip_prefix(tag)
+            .and_then(|rest| rest.chars().next())
+            .is_some_and(|c| c == ' ' || c == '>')
+    };
+    if starts_with_tag("<!doctype html") || starts_with_tag("<html") {
+        return Some("text/html");
+    }
+    if text.starts_with("<?xml") || text.starts_with("<!--") || text.starts_with("<svg") {
+        if text.contains("<svg") && !text.contains("<html") {
+            return Some("image/svg+xml");
+        }
+        if text.starts_with("<?xml") {
+            let mime_type = if text.contains("<rss") {
+                "application/rss+xml"
+            } else if text.contains("<feed") {
+                "application/atom+xml"
+            } else if text.contains("<html") {
+                "application/xhtml+xml"
+            } else {
+                "application/xml"
+            };
+            return Some(mime_type);
+        }
+    }
+    let html_tags = [
+        "<head", "<script", "<iframe", "<h1", "<div", "<font", "<table", "<a", "<style", "<title",
+        "<b", "<body", "<br", "<p", "<!--",
+    ];
+    if html_tags.iter().any(|tag| starts_with_tag(tag)) {
+        return Some("text/html");
+    }
+    if text.starts_with("%!ps-adobe-") {
+        return Some("application/postscript");
+    }
+    None
+}
+
+/// Other names used for a type
+static ALIASES: phf::Map<&'static str, &str> = phf_map! {
+    "application/javascript" => "text/javascript",
+    "application/x-javascript" => "text/javascript",
+    "application/ecmascript" => "text/javascript",
+    "text/ecmascript" => "text/javascript",
+    "text/x-javascript" => "text/javascript",
+    "text/xml" => "application/xml",
+    "application/x-json" => "application/json",
+    "text/json" => "application/json",
+    "image/jpg" => "image/jpeg",
+    "image/pjpeg" => "image/jpeg",
+    "image/x-png" => "image/png",
+    "image/vnd.microsoft.icon" => "image/x-icon",
+    "image/ico" => "image/x-icon",
+    "image/x-ms-bmp" => "image/bmp",
+    "audio/mp3" => "audio/mpeg",
+    "audio/x-mp3" => "audio/mpeg",
+    "audio/x-wav" => "audio/wav",
+    "audio/wave" => "audio/wav",
+    "audio/x-flac" => "audio/flac",
+    "audio/x-m4a" => "audio/mp4",
+    "application/font-woff" => "font/woff",
+    "application/x-font-woff" => "font/woff",
+    "font/x-woff" => "font/woff",
+    "application/font-woff2" => "font/woff2",
+    "application/x-font-ttf" => "font/ttf",
+    "application/x-font-truetype" => "font/ttf",
+    "font/sfnt" => "font/ttf",
+    "application/x-font-otf" => "font/otf",
+    "application/x-font-opentype" => "font/otf",
+    "application/x-gzip" => "application/gzip",
+    "application/x-zip-compressed" => "application/zip",
+    "application/x-pdf" => "application/pdf",
+    "application/x-rar-compressed" => "application/vnd.rar",
+    "text/x-markdown" => "text/markdown",
+    "application/x-yaml" => "application/yaml",
+    "text/yaml" => "application/yaml",
+    "text/x-yaml" => "application/yaml",
+    "application/x-wasm" => "application/wasm",
+};
+
+/// Extension of each type, with the dot
+static MIME_TYPES: phf::Map<&'static str, &str> = phf_map! {
+    "text/html" => ".html",
+    "application/xhtml+xml" => ".xhtml",
+    "text/css" => ".css",
+    "text/javascript" => ".js",
+    "application/json" => ".json",
+    "application/ld+json" => ".jsonld",
+    "application/manifest+json" => ".webmanifest",
+    "application/xml" => ".xml",
+    "application/rss+xml" => ".rss",
+    "application/atom+xml" => ".atom",
+    "application/yaml" => ".yaml",
+    "text/plain" => ".txt",
+    "text/csv" => ".csv",
+    "text/markdown" => ".md",
+    "text/calendar" => ".ics",
+    "text/vtt" => ".vtt",
+    "application/wasm" => ".wasm",
+    "application/pdf" => ".pdf",
+    "application/postscript" => ".ps",
+    "application/rtf" => ".rtf",
+    "application/epub+zip" => ".epub",
+    "application/msword" => ".doc",
+    "application/vnd.ms-excel" => ".xls",
+    "application/vnd.ms-powerpoint" => ".ppt",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => ".docx",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => ".xlsx",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation" => ".pptx",
+    "application/vnd.oasis.opendocument.text" => ".odt",
+    "application/vnd.oasis.opendocument.spreadsheet" => ".ods",
+    "application/vnd.oasis.opendocument.presentation" => ".odp",
+    "application/zip" => ".zip",
+    "application/gzip" => ".gz",
+    "application/x-tar" => ".tar",
+    "application/x-bzip2" => ".bz2",
+    "application/x-xz" => ".xz",
+    "application/x-7z-compressed" => ".7z",
+    "application/vnd.rar" => ".rar",
+    "application/java-archive" => ".jar",
+    "application/vnd.android.package-archive" => ".apk",
+    "application/x-apple-diskimage" => ".dmg",
+    "application/x-msdownload" => ".exe",
+    "application/x-shockwave-flash" => ".swf",
+    "application/vnd.ms-fontobject" => ".eot",
+    "font/woff" => ".woff",
+    "font/woff2" => ".woff2",
+    "font/ttf" => ".ttf",
+    "font/otf" => ".otf",
+    "font/collection" => ".ttc",
+    "image/png" => ".png",
+    "image/jpeg" => ".jpg",
+    "image/gif" => ".gif",
+    "image/webp" => ".webp",
+    "image/avif" => ".avif",
+    "image/heic" => ".heic",
+    "image/svg+xml" => ".svg",
+    "image/x-icon" => ".ico",
+    "image/bmp" => ".bmp",
+    "image/tiff" => ".tiff",
+    "image/apng" => ".apng",
+    "image/jxl" => ".jxl",
+    "audio/mpeg" => ".mp3",
+    "audio/ogg" => ".oga",
+    "audio/opus" => ".opus",
+    "audio/wav" => ".wav",
+    "audio/webm" => ".weba",
+    "audio/aac" => ".aac",
+    "audio/flac" => ".flac",
+    "audio/mp4" => ".m4a",
+    "audio/midi" => ".mid",
+    "video/mp4" => ".mp4",
+    "video/mpeg" => ".mpeg",
+    "video/ogg" => ".ogv",
+    "video/webm" => ".webm",
+    "video/quicktime" => ".mov",
+    "video/x-msvideo" => ".avi",
+    "video/x-matroska" => ".mkv",
+    "video/mp2t" => ".ts",
+    "video/3gpp" => ".3gp",
+    "application/ogg" => ".ogx",
+};
+
+/// Type of each extension, without the dot. Several extensions can have
+/// the same type, E.g `jpg` and `jpeg`.
+static EXTENSIONS: phf::Map<&'static str, &str> = phf_map! {
+    "html" => "text/html",
+    "htm" => "text/html",
+    "shtml" => "text/html",
+    "xhtml" => "application/xhtml+xml",
+    "xht" => "application/xhtml+xml",
+    "css" => "text/css",
+    "js" => "text/javascript",
+    "mjs" => "text/javascript",
+    "cjs" => "text/javascript",
+    "json" => "application/json",
+    "map" => "application/json",
+    "jsonld" => "application/ld+json",
+    "webmanifest" => "application/manifest+json",
+    "xml" => "application/xml",
+    "xsl" => "application/xml",
+    "rss" => "application/rss+xml",
+    "atom" => "application/atom+xml",
+    "yaml" => "application/yaml",
+    "yml" => "application/yaml",
+    "txt" => "text/plain",
+    "text" => "text/plain",
+    "csv" => "text/csv",
+    "md" => "text/markdown",
+    "markdown" => "text/markdown",
+    "ics" => "text/calendar",
+    "vtt" => "text/vtt",
+    "wasm" => "application/wasm",
+    "pdf" => "application/pdf",
+    "ps" => "application/postscript",
+    "eps" => "application/postscript",
+    "rtf" => "application/rtf",
+    "epub" => "application/epub+zip",
+    "doc" => "application/msword",
+    "xls" => "application/vnd.ms-excel",
+    "ppt" => "application/vnd.ms-powerpoint",
+    "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "odt" => "application/vnd.oasis.opendocument.text",
+    "ods" => "application/vnd.oasis.opendocument.spreadsheet",
+    "odp" => "application/vnd.oasis.opendocument.presentation",
+    "zip" => "application/zip",
+    "gz" => "application/gzip",
+    "tgz" => "application/gzip",
+    "tar" => "application/x-tar",
+    "bz2" => "application/x-bzip2",
+    "xz" => "application/x-xz",
+    "7z" => "application/x-7z-compressed",
+    "rar" => "application/vnd.rar",
+    "jar" => "application/java-archive",
+    "apk" => "application/vnd.android.package-archive",
+    "dmg" => "application/x-apple-diskimage",
+    "exe" => "application/x-msdownload",
+    "msi" => "application/x-msdownload",
+    "swf" => "application/x-shockwave-flash",
+    "eot" => "application/vnd.ms-fontobject",
+    "woff" => "font/woff",
+    "woff2" => "font/woff2",
+    "ttf" => "font/ttf",
+    "otf" => "font/otf",
+    "ttc" => "font/collection",
+    "png" => "image/png",
+    "jpg" => "image/jpeg",
+    "jpeg" => "image/jpeg",
+    "jpe" => "image/jpeg",
+    "jfif" => "image/jpeg",
+    "gif" => "image/gif",
+    "webp" => "image/webp",
+    "avif" => "image/avif",
+    "heic" => "image/heic",
+    "heif" => "image/heic",
+    "svg" => "image/svg+xml",
+    "svgz" => "image/svg+xml",
+    "ico" => "image/x-icon",
+    "cur" => "image/x-icon",
+    "bmp" => "image/bmp",
+    "tif" => "image/tiff",
+    "tiff" => "image/tiff",
+    "apng" => "image/apng",
+    "jxl" => "image/jxl",
+    "mp3" => "audio/mpeg",
+    "oga" => "audio/ogg",
+    "ogg" => "audio/ogg",
+    "opus" => "audio/opus",
+    "wav" => "audio/wav",
+    "weba" => "audio/webm",
+    "aac" => "audio/aac",
+    "flac" => "audio/flac",
+    "m4a" => "audio/mp4",
+    "mid" => "audio/midi",
+    "midi" => "audio/midi",
+    "mp4" => "video/mp4",
+    "m4v" => "video/mp4",
+    "mpeg" => "video/mpeg",
+    "mpg" => "video/mpeg",
+    "ogv" => "video/ogg",
+    "webm" => "video/webm",
+    "mov" => "video/quicktime",
+    "avi" => "video/x-msvideo",
+    "mkv" => "video/x-matroska",
+    "3gp" => "video/3gpp",
+    "ogx" => "application/ogg",
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    fn resolve(content_type: Option<&str>, url: &str, first_chunk: &[u8]) -> Option<String> {
+        super::resolve(content_type, &Url::parse(url).unwrap(), first_chunk)
+    }
+
+    #[test]
+    fn binary_signatures_win() {
+        let page = "https://a.org/logo.html";
+        assert_eq!(
+            resolve(Some("text/html"), page, PNG).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            resolve(None, "https://a.org/f", b"RIFF\0\0\0\0WEBPVP8 ").as_deref(),
+            Some("image/webp")
+        );
+        assert_eq!(
+            resolve(None, "https://a.org/f", b"\0\0\0\x1cftypavif").as_deref(),
+            Some("image/avif")
+        );
+        assert_eq!(
+            resolve(None, "https://a.org/f", b"%PDF-1.7").as_deref(),
+            Some("application/pdf")
+        );
+    }
+
+    #[test]
+    fn specific_headers_win_over_extensions() {
+        assert_eq!(
+            resolve(
+                Some("application/x-javascript; charset=utf-8"),
+                "https://a.org/app",
+                b"var a;"
+            )
+            .as_deref(),
+            Some("text/javascript")
+        );
+        assert_eq!(
+            resolve(Some("text/css"), "https://a.org/style.php", b"p {}").as_deref(),
+            Some("text/css")
+        );
+        // A page served as HTML whose content doesn't look like it
+        assert_eq!(
+            resolve(Some("text/html"), "https://a.org/data.json", b"{}").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            resolve(
+                Some("text/html"),
+                "https://a.org/data.json",
+                b"<!DOCTYPE html>"
+            )
+            .as_deref(),
+            Some("text/html")
+        );
+    }
+
+    #[test]
+    fn generic_headers_come_last() {
+        let octets = Some("application/octet-stream");
+        assert_eq!(
+            resolve(octets, "https://a.org/a.css", b"p {}").as_deref(),
+            Some("text/css")
+        );
+        assert_eq!(
+            resolve(octets, "https://a.org/a", b"\xef\xbb\xbf  <HTML lang=en>").as_deref(),
+            Some("text/html")
+        );
+        assert_eq!(
+            resolve(Some("text/plain"), "https://a.org/a", b"PK\x03\x04").as_deref(),
+            Some("application/zip")
+        );
+        assert_eq!(
+            resolve(octets, "https://a.org/a", b"plain text").as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(resolve(None, "https://a.org/a", b"plain text"), None);
+        assert_eq!(
+            resolve(None, "https://a.org/a", b"\0\x01\x02").as_deref(),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn xml_documents_are_told_apart() {
+        let sniff = |text: &str| sniff_text(text.as_bytes());
+        assert_eq!(
+            sniff("<?xml version=\"1.0\"?><rss>"),
+            Some("application/rss+xml")
+        );
+        assert_eq!(
+            sniff("<?xml version=\"1.0\"?><feed>"),
+            Some("application/atom+xml")
+        );
+        assert_eq!(sniff("<?xml version=\"1.0\"?><svg>"), Some("image/svg+xml"));
+        assert_eq!(sniff("<!-- logo --><svg>"), Some("image/svg+xml"));
+        assert_eq!(
+            sniff("<?xml version=\"1.0\"?><note>"),
+            Some("application/xml")
+        );
+        // A tag must end for the name to match, `<bdi` isn't `<b`
+        assert_eq!(sniff("<bdi>"), None);
+        assert_eq!(sniff("<b>bold</b>"), Some("text/html"));
+    }
+
+    #[test]
+    fn extensions_match_their_types() {
+        assert!(has_extension_of("photo.JPEG", "image/jpeg"));
+        assert!(has_extension_of("photo.jpg", "image/pjpeg"));
+        assert!(!has_extension_of("photo.png", "image/jpeg"));
+        assert!(!has_extension_of(".jpg", "image/jpeg"));
+        assert_eq!(extension("image/svg+xml"), Some(".svg"));
+        assert_eq!(
+            url_extension(&Url::parse("https://a.org/a.b/Photo.JPG?x=1.png").unwrap()),
+            Some("jpg".to_string())
+        );
+        assert_eq!(
+            url_extension(&Url::parse("https://a.org/.htaccess").unwrap()),
+            None
+        );
+        assert!(is_html(None));
+        assert!(is_html(Some("application/xhtml+xml")));
+        assert!(!is_html(Some("text/plain")));
+    }
+}
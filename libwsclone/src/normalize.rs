@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use url::Url;
+
+/// Query parameters added by analytics and ad platforms, removed by [`Normalization::clean_queries`].
+const TRACKING_PARAMS: [&str; 11] = [
+    "utm_*", "fbclid", "gclid", "dclid", "msclkid", "yclid", "igshid", "mc_cid", "mc_eid", "_ga",
+    "_gl",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    Keep,
+    /// Add a slash to paths whose last segment has no extension, `/docs` => `/docs/`
+    Add,
+    /// Remove the slash at the end of any path but `/`, `/docs/` => `/docs`
+    Remove,
+}
+
+impl FromStr for TrailingSlash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(TrailingSlash::Keep),
+            "add" => Ok(TrailingSlash::Add),
+            "remove" => Ok(TrailingSlash::Remove),
+            _ => Err(format!(
+                "unknown trailing slash handling \"{s}\", expected one of keep, add, remove"
+            )),
+        }
+    }
+}
+
+/// Steps applied to every url found in a page before it is deduplicated and
+/// requested, so the variants of a url map to a single local file.
+///
+/// Fragments are always removed. Default ports and the case of the scheme and
+/// host are always normalized when urls are parsed. The other steps are off by
+/// default, as some sites serve different pages for them.
+/// [`Normalization::clean_queries`] removes tracking parameters and sorts queries.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Normalization {
+    /// Query parameters to remove, case insensitive. An entry ending with `*`
+    /// matches parameters starting with it, E.g `utm_*`
+    pub strip_params: Vec<String>,
+    /// Sort query parameters by name, `?b=1&a=2` => `?a=2&b=1`
+    pub sort_query: bool,
+    pub trailing_slash: TrailingSlash,
+    /// Lower case the path, for servers with case insensitive paths.
+    pub lowercase_path: bool,
+    /// Treat pages declaring an already downloaded page as their
+    /// `<link rel="canonical">` as duplicates of it.
+    pub follow_canonical: bool,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization::none()
+    }
+}
+
+impl Normalization {
+    /// Normalization that only removes fragments.
+    pub fn none() -> Self {
+        Normalization {
+            strip_params: Vec::new(),
+            sort_query: false,
+            trailing_slash: TrailingSlash::Keep,
+            lowercase_path: false,
+            follow_canonical: false,
+        }
+    }
+
+    /// Removes the query parameters of analytics and ad platforms, E.g `utm_source`,
+    /// and sorts the others. `?b=1&utm_source=x&a=2` => `?a=2&b=1`
+    pub fn clean_queries() -> Self {
+        Normalization {
+            strip_params: TRACKING_PARAMS.iter().map(|p| p.to_string()).collect(),
+            sort_query: true,
+            ..Normalization::none()
+        }
+    }
+
+    pub fn normalize(&self, url: &Url) -> Url {
+        let mut url = url.clone();
+        url.set_fragment(None);
+        if url.cannot_be_a_base() {
+            return url;
+        }
+
+        if self.lowercase_path {
+            let path = url.path().to_lowercase();
+            url.set_path(&path);
+        }
+        match self.trailing_slash {
+            TrailingSlash::Keep => {}
+            TrailingSlash::Add => {
+                let last = url.path().rsplit('/').next().unwrap_or_default();
+                if !last.is_empty() && !last.contains('.') {
+                    let path = format!("{}/", url.path());
+                    url.set_path(&path);
+                }
+            }
+            TrailingSlash::Remove => {
+                if url.path().len() > 1 && url.path().ends_with('/') {
+                    let path = url.path().trim_end_matches('/').to_string();
+                    url.set_path(if path.is_empty() { "/" } else { &path });
+                }
+            }
+        }
+
+        // Without these steps the query is left as written, empty parameters included
+        let rewrites_query = self.sort_query || !self.strip_params.is_empty();
+        if let Some(query) = url.query().filter(|_| rewrites_query) {
+            // Parameters are kept as they are written, decoding and encoding
+            // them again could change what the server receives.
+            let mut params: Vec<&str> = query
+                .split('&')
+                .filter(|param| !param.is_empty() && !self.is_stripped(param))
+                .collect();
+            if self.sort_query {
+                params.sort_by(|a, b| param_name(a).cmp(param_name(b)).then(a.cmp(b)));
+            }
+            let query = params.join("&");
+            url.set_query(if query.is_empty() { None } else { Some(&query) });
+        }
+        url
+    }
+
+    fn is_stripped(&self, param: &str) -> bool {
+        let name = param_name(param).to_lowercase();
+        self.strip_params.iter().any(|pattern| {
+            let pattern = pattern.to_lowercase();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        })
+    }
+}
+
+fn param_name(param: &str) -> &str {
+    param.split('=').next().unwrap_or(param)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(normalization: &Normalization, url: &str) -> String {
+        normalization
+            .normalize(&Url::parse(url).unwrap())
+            .to_string()
+    }
+
+    #[test]
+    fn queries_are_kept_without_query_steps() {
+        let none = Normalization::none();
+        assert_eq!(
+            normalize(&none, "http://a.org/p?b=2&&a=1#top"),
+            "http://a.org/p?b=2&&a=1"
+        );
+        assert_eq!(normalize(&none, "http://a.org/p?"), "http://a.org/p?");
+    }
+
+    #[test]
+    fn tracking_params_are_stripped_and_queries_sorted() {
+        let clean = Normalization::clean_queries();
+        assert_eq!(
+            normalize(&clean, "http://a.org/?b=1&UTM_Source=x&a=2&fbclid=y"),
+            "http://a.org/?a=2&b=1"
+        );
+        assert_eq!(
+            normalize(&clean, "http://a.org/?utm_medium=x"),
+            "http://a.org/"
+        );
+        // Parameters with the same name keep a stable order, by value
+        assert_eq!(
+            normalize(&clean, "http://a.org/?b=2&a&b=1"),
+            "http://a.org/?a&b=1&b=2"
+        );
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        let add = Normalization {
+            trailing_slash: TrailingSlash::Add,
+            lowercase_path: true,
+            ..Normalization::none()
+        };
+        assert_eq!(normalize(&add, "http://a.org/Docs"), "http://a.org/docs/");
+        assert_eq!(
+            normalize(&add, "http://a.org/A.HTML"),
+            "http://a.org/a.html"
+        );
+        let remove = Normalization {
+            trailing_slash: TrailingSlash::Remove,
+            ..Normalization::none()
+        };
+        assert_eq!(
+            normalize(&remove, "http://a.org/docs//"),
+            "http://a.org/docs"
+        );
+        assert_eq!(normalize(&remove, "http://a.org/"), "http://a.org/");
+    }
+}
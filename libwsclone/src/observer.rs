@@ -0,0 +1,35 @@
+use crate::event::Event;
+use serde::{Deserialize, Serialize};
+
+/// Receives the events of a session as they happen, see [`crate::Downloader::run_with_observer`].
+///
+/// Events are delivered on the download tasks, so implementations should
+/// return quickly and hand off any slow work.
+pub trait Observer: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+/// What to do when a channel or stream consumer doesn't keep up with the events of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Downloads wait until there's room for every event.
+    Block,
+    /// Progress events are dropped when there's no room, downloads wait for every other event.
+    #[default]
+    DropProgress,
+    /// Downloads don't wait. Events that don't fit are held back and sent as
+    /// soon as there's room, with held back progress events replaced by the
+    /// newest one of the same resource. Past 1000 held back events, downloads
+    /// wait as with `Block` until they are sent.
+    Coalesce,
+}
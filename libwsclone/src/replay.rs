@@ -0,0 +1,438 @@
+use crate::errors::WscError;
+use crate::fetch::{FetchResponse, Fetcher};
+use crate::mime;
+use bytes::Bytes;
+use flate2::read::MultiGzDecoder;
+use futures::future::BoxFuture;
+use futures::{stream, StreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{header, StatusCode};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use url::Url;
+
+/// Suffix of the optional file holding the status line and headers for a fixture file.
+const HEADERS_SUFFIX: &str = ".headers";
+
+/// A [`Fetcher`] that never touches the network. Responses are served from a
+/// fixture directory or from a WARC archive, so crawls can run hermetically.
+///
+/// # Fixture directory layout
+///
+/// `https://example.com:8080/docs/page.html?v=1` is served from
+/// `<dir>/example.com:8080/docs/page.html?v=1`. Paths ending in `/` are served
+/// from `index.html` inside the matching directory. When a file named like the
+/// fixture plus `.headers` exists, it holds the status line and headers of the
+/// response (E.g `HTTP/1.1 301 Moved Permanently` followed by `Location: /new`),
+/// otherwise a `200 OK` with a content type guessed from the extension is used.
+/// Urls without a fixture get a `404 Not Found`.
+#[derive(Debug)]
+pub struct ReplayFetcher {
+    source: ReplaySource,
+}
+
+#[derive(Debug)]
+enum ReplaySource {
+    Directory(PathBuf),
+    Archive(HashMap<String, RecordedResponse>),
+}
+
+#[derive(Debug, Clone)]
+struct RecordedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl RecordedResponse {
+    fn not_found() -> Self {
+        RecordedResponse {
+            status: StatusCode::NOT_FOUND,
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+}
+
+impl ReplayFetcher {
+    /// Serve responses from a fixture directory.
+    pub fn from_dir(dir: impl Into<PathBuf>) -> Result<Self, WscError> {
+        let dir = dir.into();
+        if !dir.is_dir() {
+            return Err(WscError::FileOperationError {
+                file_name: dir.to_string_lossy().to_string(),
+                message: "replay directory does not exist".into(),
+            });
+        }
+        Ok(ReplayFetcher {
+            source: ReplaySource::Directory(dir),
+        })
+    }
+
+    /// Serve responses recorded in a WARC file. Files ending in `.gz` are
+    /// decompressed first.
+    pub fn from_warc(path: impl AsRef<Path>) -> Result<Self, WscError> {
+        let path = path.as_ref();
+        let file_name = path.to_string_lossy().to_string();
+        let raw = std::fs::read(path).map_err(|e| WscError::FileOperationError {
+            file_name: file_name.clone(),
+            message: format!("{} | {}", e, e.kind()),
+        })?;
+        let data = if path.extension().is_some_and(|ext| ext == "gz") {
+            let mut data = Vec::new();
+            if let Err(e) = MultiGzDecoder::new(raw.as_slice()).read_to_end(&mut data) {
+                return Err(WscError::FileOperationError {
+                    file_name,
+                    message: format!("{} | {}", e, e.kind()),
+                });
+            }
+            data
+        } else {
+            raw
+        };
+        let records = parse_warc(&data).map_err(|message| WscError::FileOperationError {
+            file_name: file_name.clone(),
+            message,
+        })?;
+        tracing::debug!("Loaded {} responses from {}", records.len(), file_name);
+        Ok(ReplayFetcher {
+            source: ReplaySource::Archive(records),
+        })
+    }
+
+    async fn lookup(&self, url: &Url) -> Result<RecordedResponse, WscError> {
+        match &self.source {
+            ReplaySource::Archive(records) => Ok(records
+                .get(url.as_str())
+                .cloned()
+                .unwrap_or_else(RecordedResponse::not_found)),
+            ReplaySource::Directory(dir) => lookup_fixture(dir, url).await,
+        }
+    }
+}
+
+impl Fetcher for ReplayFetcher {
+    fn fetch<'a>(&'a self, url: &'a Url) -> BoxFuture<'a, Result<FetchResponse, WscError>> {
+        Box::pin(async move {
+            let recorded = self.lookup(url).await?;
+            let body = recorded.body;
+            Ok(FetchResponse {
+                url: url.clone(),
+                status: recorded.status,
+                headers: recorded.headers,
+                body: stream::once(async move { Ok(body) }).boxed(),
+            })
+        })
+    }
+}
+
+fn fixture_path(dir: &Path, url: &Url) -> PathBuf {
+    let mut path = dir.to_path_buf();
+    let host = url.host_str().unwrap_or_default();
+    path.push(match url.port() {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    });
+    for segment in url.path().split('/').filter(|s| !s.is_empty()) {
+        path.push(segment);
+    }
+    if url.path().ends_with('/') || path.is_dir() {
+        path.push("index.html");
+    }
+    if let Some(query) = url.query() {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!("?{query}"));
+        path.set_file_name(file_name);
+    }
+    path
+}
+
+async fn lookup_fixture(dir: &Path, url: &Url) -> Result<RecordedResponse, WscError> {
+    let path = fixture_path(dir, url);
+    let mut headers_path = path.clone().into_os_string();
+    headers_path.push(HEADERS_SUFFIX);
+
+    let (status, mut headers) = match fs::read_to_string(&headers_path).await {
+        Ok(head) => parse_head(&head).map_err(|message| WscError::FileOperationError {
+            file_name: PathBuf::from(&headers_path).to_string_lossy().to_string(),
+            message,
+        })?,
+        Err(_) if path.is_file() => {
+            let mut headers = HeaderMap::new();
+            // The query of the url is part of the file name, not of it's extension
+            if let Some(mime) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('?').next())
+                .and_then(|name| name.rsplit_once('.'))
+                .and_then(|(_, ext)| mime::guess_mime_type(&ext.to_lowercase()))
+            {
+                headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(mime));
+            }
+            (StatusCode::OK, headers)
+        }
+        Err(_) => {
+            tracing::debug!("No fixture for {} at {}", url, path.to_string_lossy());
+            return Ok(RecordedResponse::not_found());
+        }
+    };
+
+    let body = match fs::read(&path).await {
+        Ok(b) => Bytes::from(b),
+        Err(_) => Bytes::new(),
+    };
+    if !headers.contains_key(header::CONTENT_LENGTH) {
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Ok(RecordedResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Parses an HTTP status line followed by header lines.
+fn parse_head(head: &str) -> Result<(StatusCode, HeaderMap), String> {
+    let mut lines = head.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .ok_or_else(|| format!("invalid status line \"{status_line}\""))?;
+    let mut headers = HeaderMap::new();
+    for line in lines.take_while(|l| !l.trim().is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("invalid header line \"{line}\""))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| e.to_string())?;
+        let value = HeaderValue::from_str(value.trim()).map_err(|e| e.to_string())?;
+        headers.append(name, value);
+    }
+    Ok((status, headers))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Collects the `response` records of a WARC file, keyed by target uri.
+fn parse_warc(data: &[u8]) -> Result<HashMap<String, RecordedResponse>, String> {
+    let mut records = HashMap::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        while pos < data.len() && (data[pos] == b'\r' || data[pos] == b'\n') {
+            pos += 1;
+        }
+        if pos >= data.len() {
+            break;
+        }
+        let rest = &data[pos..];
+        let head_end = find(rest, b"\r\n\r\n").ok_or("truncated WARC record header")?;
+        let head = String::from_utf8_lossy(&rest[..head_end]);
+        if !head.starts_with("WARC/") {
+            return Err(format!("invalid WARC record at byte {pos}"));
+        }
+        let mut warc_type = None;
+        let mut target_uri = None;
+        let mut content_length = None;
+        for line in head.lines().skip(1) {
+            if let Some((name, value)) = line.split_once(':') {
+                let value = value.trim();
+                match name.trim().to_lowercase().as_str() {
+                    "warc-type" => warc_type = Some(value.to_string()),
+                    "warc-target-uri" => {
+                        target_uri = Some(value.trim_matches(|c| c == '<' || c == '>').to_string())
+                    }
+                    "content-length" => content_length = value.parse::<usize>().ok(),
+                    _ => {}
+                }
+            }
+        }
+        let content_length = content_length.ok_or("WARC record without Content-Length")?;
+        let block_start = head_end + 4;
+        let block = rest
+            .get(block_start..block_start + content_length)
+            .ok_or("truncated WARC record block")?;
+        pos += block_start + content_length;
+
+        if let (Some("response"), Some(uri)) = (warc_type.as_deref(), target_uri) {
+            match parse_http_response(block) {
+                Ok(response) => {
+                    let key = Url::parse(&uri).map(String::from).unwrap_or(uri);
+                    records.insert(key, response);
+                }
+                Err(e) => tracing::warn!("Skipping WARC record for {}. {}", uri, e),
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn parse_http_response(block: &[u8]) -> Result<RecordedResponse, String> {
+    let (head_end, separator_len) = match find(block, b"\r\n\r\n") {
+        Some(i) => (i, 4),
+        None => (
+            find(block, b"\n\n").ok_or("missing end of http headers")?,
+            2,
+        ),
+    };
+    let (status, mut headers) = parse_head(&String::from_utf8_lossy(&block[..head_end]))?;
+    let mut body = block[head_end + separator_len..].to_vec();
+    let chunked = headers
+        .get(header::TRANSFER_ENCODING)
+        .and_then(|te| te.to_str().ok())
+        .is_some_and(|te| te.to_lowercase().contains("chunked"));
+    if chunked {
+        body = dechunk(&body)?;
+        headers.remove(header::TRANSFER_ENCODING);
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+    }
+    Ok(RecordedResponse {
+        status,
+        headers,
+        body: Bytes::from(body),
+    })
+}
+
+fn dechunk(mut data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = find(data, b"\r\n").ok_or("truncated chunk size")?;
+        let size_str = String::from_utf8_lossy(&data[..line_end]);
+        let size_str = size_str.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .map_err(|_| format!("invalid chunk size \"{size_str}\""))?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Ok(body);
+        }
+        body.extend_from_slice(data.get(..size).ok_or("truncated chunk")?);
+        data = data.get(size + 2..).unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("wsclone-replay-{}-{name}", std::process::id()))
+    }
+
+    async fn fetch(fetcher: &ReplayFetcher, url: &str) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let response = fetcher.fetch(&Url::parse(url).unwrap()).await.unwrap();
+        let mut body = Vec::new();
+        let mut stream = response.body;
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk.unwrap());
+        }
+        (response.status, response.headers, body)
+    }
+
+    fn warc_record(warc_type: &str, uri: &str, block: &[u8]) -> Vec<u8> {
+        let mut record = format!(
+            "WARC/1.0\r\nWARC-Type: {warc_type}\r\nWARC-Target-URI: <{uri}>\r\n\
+             Content-Length: {}\r\n\r\n",
+            block.len()
+        )
+        .into_bytes();
+        record.extend_from_slice(block);
+        record.extend_from_slice(b"\r\n\r\n");
+        record
+    }
+
+    #[tokio::test]
+    async fn fixture_directory_is_replayed() {
+        let dir = temp_path("dir");
+        std::fs::create_dir_all(dir.join("example.com:8080/docs")).unwrap();
+        std::fs::write(dir.join("example.com:8080/docs/index.html"), "<p>docs</p>").unwrap();
+        std::fs::write(dir.join("example.com:8080/style.css?v=1"), "p {}").unwrap();
+        std::fs::write(dir.join("example.com:8080/old"), "").unwrap();
+        std::fs::write(
+            dir.join("example.com:8080/old.headers"),
+            "HTTP/1.1 301 Moved Permanently\nLocation: /docs/\n",
+        )
+        .unwrap();
+        let fetcher = ReplayFetcher::from_dir(&dir).unwrap();
+
+        let (status, headers, body) = fetch(&fetcher, "http://example.com:8080/docs/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(headers[header::CONTENT_LENGTH], "11");
+        assert_eq!(body, b"<p>docs</p>");
+        // A directory is served from it's index.html, with or without a slash
+        let (_, _, body) = fetch(&fetcher, "http://example.com:8080/docs").await;
+        assert_eq!(body, b"<p>docs</p>");
+
+        let (_, headers, body) = fetch(&fetcher, "http://example.com:8080/style.css?v=1").await;
+        assert_eq!(headers[header::CONTENT_TYPE], "text/css");
+        assert_eq!(body, b"p {}");
+
+        let (status, headers, _) = fetch(&fetcher, "http://example.com:8080/old").await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers[header::LOCATION], "/docs/");
+
+        let (status, _, body) = fetch(&fetcher, "http://example.com:8080/missing").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(ReplayFetcher::from_dir(&dir).is_err());
+    }
+
+    #[tokio::test]
+    async fn warc_responses_are_replayed() {
+        let mut warc = warc_record(
+            "request",
+            "https://example.com/",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        );
+        warc.extend(warc_record(
+            "response",
+            "https://example.com/",
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<p>home</p>",
+        ));
+        warc.extend(warc_record(
+            "response",
+            "https://example.com/chunked.txt",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+              4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n",
+        ));
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped.write_all(&warc).unwrap();
+        let plain_path = temp_path("site.warc");
+        let gz_path = temp_path("site.warc.gz");
+        std::fs::write(&plain_path, &warc).unwrap();
+        std::fs::write(&gz_path, gzipped.finish().unwrap()).unwrap();
+
+        for path in [&plain_path, &gz_path] {
+            let fetcher = ReplayFetcher::from_warc(path).unwrap();
+            let (status, headers, body) = fetch(&fetcher, "https://example.com/").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+            assert_eq!(body, b"<p>home</p>");
+
+            let (_, headers, body) = fetch(&fetcher, "https://example.com/chunked.txt").await;
+            assert_eq!(body, b"Wikipedia");
+            assert_eq!(headers[header::CONTENT_LENGTH], "9");
+            assert!(!headers.contains_key(header::TRANSFER_ENCODING));
+
+            let (status, _, _) = fetch(&fetcher, "https://example.com/missing").await;
+            assert_eq!(status, StatusCode::NOT_FOUND);
+        }
+
+        std::fs::write(&plain_path, b"not a warc\r\n\r\n").unwrap();
+        assert!(ReplayFetcher::from_warc(&plain_path).is_err());
+        std::fs::remove_file(&plain_path).unwrap();
+        std::fs::remove_file(&gz_path).unwrap();
+    }
+}
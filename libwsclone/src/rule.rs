@@ -0,0 +1,270 @@
+use crate::budget::Budget;
+use crate::errors::RuleError;
+use crate::filter::{FilterSet, UrlFilter};
+use crate::http::HttpOptions;
+use crate::normalize::Normalization;
+use crate::scope::{HostScope, Scope};
+use crate::sitemap::SitemapSeeding;
+use serde::{Deserialize, Serialize};
+
+/// Rules applied to every resource downloaded in a session.
+///
+/// Use [`DownloadRule::builder`] to get a validated rule with sensible defaults.
+/// Rules are also (de)serializable, missing fields take their default value.
+///
+/// # Initial page
+///
+/// The initial page is what every other page and resource hangs off, so it is
+/// downloaded with a copy of the rule where `abort_on_download_error` and
+/// `download_static_resource_with_unknown_size` are both forced to `true`
+/// (see [`DownloadRule::for_initial_page`]). Any failure on it aborts the session
+/// and it is downloaded even when the server does not report its size. Static
+/// resources of the initial page use the same overrides. Every other setting,
+/// and all pages from level 1 on, use the rule as given. The initial page is
+/// always in scope, `page_scope` applies to the pages linked from it.
+///
+/// Seeds added with [`crate::Downloader::seed`] are downloaded like the
+/// initial page, and urls in scope of any seed are in scope of the session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DownloadRule {
+    /// Maximum size for static files to download, once decoded. A compressed
+    /// body, or one without a `Content-Length`, is given up as soon as it's
+    /// larger.
+    pub max_static_file_size: u64,
+    pub download_static_resource_with_unknown_size: bool,
+    /// Progress update interval in millisecond
+    pub progress_update_interval: u64,
+    /// Max levels of pages to download. Minimum is 0, which means download
+    /// only the initial page and it's resources.
+    pub max_level: u8,
+    pub black_list_urls: Vec<String>,
+    /// Abort download if any resource other than the first page encounters an error.
+    pub abort_on_download_error: bool,
+    /// Number of times a request is retried after a network error or a 5xx response.
+    pub max_retries: u8,
+    /// Delay before the first retry in millisecond, multiplied by the attempt number for later ones.
+    pub retry_delay: u64,
+    /// Write pages as UTF-8, with their charset declaration updated. Pages
+    /// are written back in the encoding they were served in otherwise.
+    pub convert_to_utf8: bool,
+    /// Pages to follow. Defaults to the host of the initial page.
+    pub page_scope: Scope,
+    /// Static resources to download, E.g images and stylesheets. Defaults to any host.
+    pub resource_scope: Scope,
+    /// Include and exclude rules, checked in order before a page (other than
+    /// the initial page) or resource is requested. The first matching filter
+    /// decides, urls matching none are included. End the list with an exclude
+    /// glob `*` to only download what an include filter matched.
+    pub filters: Vec<UrlFilter>,
+    /// How urls are normalized before they are deduplicated and named.
+    pub normalization: Normalization,
+    /// Pages to add to the crawl from the sitemaps of the site.
+    pub sitemap: SitemapSeeding,
+    /// Session wide limits on pages, resources, bytes and duration. None by default.
+    pub budget: Budget,
+    /// User agent, headers and cookies of the requests.
+    pub http: HttpOptions,
+}
+
+impl Default for DownloadRule {
+    fn default() -> Self {
+        DownloadRule {
+            max_static_file_size: 10_000_000,
+            download_static_resource_with_unknown_size: true,
+            progress_update_interval: 1000,
+            max_level: 0,
+            black_list_urls: Vec::new(),
+            abort_on_download_error: false,
+            max_retries: 2,
+            retry_delay: 1000,
+            convert_to_utf8: false,
+            page_scope: Scope::new(HostScope::SameHost),
+            resource_scope: Scope::new(HostScope::AnyHost),
+            filters: Vec::new(),
+            normalization: Normalization::default(),
+            sitemap: SitemapSeeding::default(),
+            budget: Budget::default(),
+            http: HttpOptions::default(),
+        }
+    }
+}
+
+impl DownloadRule {
+    pub fn builder() -> DownloadRuleBuilder {
+        DownloadRuleBuilder::default()
+    }
+
+    /// Checks for combinations that can't work, before any request is made.
+    pub fn validate(&self) -> Result<(), RuleError> {
+        if self.max_static_file_size == 0 {
+            return Err(RuleError::ZeroMaxStaticFileSize);
+        }
+        if self.progress_update_interval == 0 {
+            return Err(RuleError::ZeroProgressUpdateInterval);
+        }
+        if let Some(idx) = self
+            .black_list_urls
+            .iter()
+            .position(|url| url.trim().is_empty())
+        {
+            // An empty entry is contained in every url, it would blacklist everything.
+            return Err(RuleError::EmptyBlackListEntry(idx));
+        }
+        for scope in [&self.page_scope, &self.resource_scope] {
+            if let Some(prefix) = &scope.path_prefix {
+                if !prefix.starts_with('/') {
+                    return Err(RuleError::InvalidPathPrefix(prefix.clone()));
+                }
+            }
+            if scope
+                .allowed_hosts
+                .iter()
+                .any(|host| host.trim().is_empty())
+            {
+                return Err(RuleError::EmptyAllowedHost);
+            }
+        }
+        FilterSet::new(&self.filters)?;
+        if let Some(idx) = self
+            .normalization
+            .strip_params
+            .iter()
+            .position(|param| param.trim().is_empty())
+        {
+            return Err(RuleError::EmptyStripParam(idx));
+        }
+        if let Some(url) = self
+            .sitemap
+            .sitemap_urls
+            .iter()
+            .find(|url| url::Url::parse(url).is_err())
+        {
+            return Err(RuleError::InvalidSitemapUrl(url.clone()));
+        }
+        if let Some((kind, _)) = self
+            .budget
+            .limits()
+            .into_iter()
+            .find(|(_, limit)| *limit == Some(0))
+        {
+            return Err(RuleError::ZeroBudget(kind));
+        }
+        self.http.validate()?;
+        Ok(())
+    }
+
+    /// The rule used for the initial page and it's static resources.
+    pub fn for_initial_page(&self) -> DownloadRule {
+        DownloadRule {
+            abort_on_download_error: true,
+            download_static_resource_with_unknown_size: true,
+            ..self.clone()
+        }
+    }
+}
+
+/// Builder for [`DownloadRule`], starting from [`DownloadRule::default`].
+#[derive(Debug, Clone, Default)]
+pub struct DownloadRuleBuilder {
+    rule: DownloadRule,
+}
+
+impl DownloadRuleBuilder {
+    pub fn max_static_file_size(mut self, size: u64) -> Self {
+        self.rule.max_static_file_size = size;
+        self
+    }
+
+    pub fn download_static_resource_with_unknown_size(mut self, download: bool) -> Self {
+        self.rule.download_static_resource_with_unknown_size = download;
+        self
+    }
+
+    pub fn progress_update_interval(mut self, interval_ms: u64) -> Self {
+        self.rule.progress_update_interval = interval_ms;
+        self
+    }
+
+    pub fn max_level(mut self, level: u8) -> Self {
+        self.rule.max_level = level;
+        self
+    }
+
+    pub fn black_list_url(mut self, url: impl Into<String>) -> Self {
+        self.rule.black_list_urls.push(url.into());
+        self
+    }
+
+    pub fn black_list_urls<I, S>(mut self, urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.rule
+            .black_list_urls
+            .extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn abort_on_download_error(mut self, abort: bool) -> Self {
+        self.rule.abort_on_download_error = abort;
+        self
+    }
+
+    pub fn max_retries(mut self, retries: u8) -> Self {
+        self.rule.max_retries = retries;
+        self
+    }
+
+    pub fn retry_delay(mut self, delay_ms: u64) -> Self {
+        self.rule.retry_delay = delay_ms;
+        self
+    }
+
+    pub fn page_scope(mut self, scope: Scope) -> Self {
+        self.rule.page_scope = scope;
+        self
+    }
+
+    pub fn resource_scope(mut self, scope: Scope) -> Self {
+        self.rule.resource_scope = scope;
+        self
+    }
+
+    /// Appends a filter, after the ones already added.
+    pub fn filter(mut self, filter: UrlFilter) -> Self {
+        self.rule.filters.push(filter);
+        self
+    }
+
+    pub fn normalization(mut self, normalization: Normalization) -> Self {
+        self.rule.normalization = normalization;
+        self
+    }
+
+    pub fn sitemap(mut self, sitemap: SitemapSeeding) -> Self {
+        self.rule.sitemap = sitemap;
+        self
+    }
+
+    pub fn budget(mut self, budget: Budget) -> Self {
+        self.rule.budget = budget;
+        self
+    }
+
+    pub fn convert_to_utf8(mut self, convert: bool) -> Self {
+        self.rule.convert_to_utf8 = convert;
+        self
+    }
+
+    pub fn http(mut self, http: HttpOptions) -> Self {
+        self.rule.http = http;
+        self
+    }
+
+    pub fn build(self) -> Result<DownloadRule, RuleError> {
+        self.rule.validate()?;
+        Ok(self.rule)
+    }
+}
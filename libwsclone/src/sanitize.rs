@@ -0,0 +1,110 @@
+/// Longest file name, in bytes, on most file systems
+const MAX_FILE_NAME_BYTES: usize = 255;
+/// Longest extension kept when a name is truncated, with the dot
+const MAX_EXTENSION_BYTES: usize = 16;
+/// Names Windows gives to devices, with or without an extension
+const RESERVED_NAMES: [&str; 22] = [
+    "con", "prn", "aux", "nul", "com1", "com2", "com3", "com4", "com5", "com6", "com7", "com8",
+    "com9", "lpt1", "lpt2", "lpt3", "lpt4", "lpt5", "lpt6", "lpt7", "lpt8", "lpt9",
+];
+/// Name of a file whose name has nothing left once sanitized
+const EMPTY_NAME_REPLACEMENT: &str = "file";
+
+/// A name from a decoded url segment or a header, made safe to create in the
+/// destination directory on Linux, macOS and Windows. It isn't decoded again.
+///
+/// Every path separator, character Windows or macOS refuse and control
+/// character is replaced with `_`. So are `%` and `#`, which would break the
+/// links to the file in rewritten pages. A name that is only dots, e.g. `..`,
+/// can't point out of the directory. Names of Windows devices get a leading
+/// `_`, e.g. `_con.txt`. A name longer than 255 bytes is cut, keeping its
+/// extension.
+///
+/// A name that was changed gets a hash of the original name before its
+/// extension, so `a?b` and `a_b`, or names sharing a long prefix, are kept
+/// apart instead of overwriting each other.
+pub(crate) fn sanitize_file_name(name: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' | '%' | '#' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // Windows drops trailing dots and spaces, `..` would be the parent directory
+    let mut sanitized = replaced
+        .trim_end_matches(['.', ' '])
+        .trim_start()
+        .to_string();
+    if sanitized.is_empty() {
+        sanitized = EMPTY_NAME_REPLACEMENT.to_string();
+    }
+    let stem = sanitized.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.contains(&stem.to_lowercase().as_str()) {
+        sanitized.insert(0, '_');
+    }
+    if sanitized != name || sanitized.len() > MAX_FILE_NAME_BYTES {
+        sanitized = with_hash(&sanitized, fnv1a(name.as_bytes()));
+    }
+    sanitized
+}
+
+/// `name` with `hash` before its extension, cut to `MAX_FILE_NAME_BYTES`.
+fn with_hash(name: &str, hash: u64) -> String {
+    let extension = match name.rfind('.') {
+        Some(idx) if idx > 0 && name.len() - idx <= MAX_EXTENSION_BYTES => &name[idx..],
+        _ => "",
+    };
+    let suffix = format!("-{hash:016x}{extension}");
+    let mut end = (MAX_FILE_NAME_BYTES - suffix.len()).min(name.len() - extension.len());
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{suffix}", &name[..end])
+}
+
+/// FNV-1a hash, which unlike the hasher of the standard library is the same
+/// from one build to the next. So a name is changed the same way every session.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_names_are_kept() {
+        for name in ["index.html", "my report.pdf", "app.min.js", "ünïcode.css"] {
+            assert_eq!(sanitize_file_name(name), name);
+        }
+    }
+
+    #[test]
+    fn changed_names_get_a_hash_of_the_original() {
+        let replaced = sanitize_file_name("a?b.pdf");
+        assert!(replaced.starts_with("a_b-"), "{replaced}");
+        assert!(replaced.ends_with(".pdf"), "{replaced}");
+        assert_ne!(replaced, sanitize_file_name("a_b.pdf"));
+        assert_ne!(replaced, sanitize_file_name("a*b.pdf"));
+        assert_eq!(replaced, sanitize_file_name("a?b.pdf"));
+
+        assert!(sanitize_file_name("..").starts_with("file-"));
+        assert!(sanitize_file_name("con.js").starts_with("_con-"));
+        assert!(!sanitize_file_name("../../evil.txt").contains('/'));
+    }
+
+    #[test]
+    fn long_names_are_cut_keeping_their_extension() {
+        let first = sanitize_file_name(&format!("{}1.html", "a".repeat(300)));
+        let second = sanitize_file_name(&format!("{}2.html", "a".repeat(300)));
+        assert_eq!(first.len(), MAX_FILE_NAME_BYTES);
+        assert!(first.ends_with(".html"));
+        assert_ne!(first, second);
+        let multi_byte = sanitize_file_name(&"é".repeat(200));
+        assert!(multi_byte.len() <= MAX_FILE_NAME_BYTES);
+    }
+}
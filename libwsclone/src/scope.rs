@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use url::Url;
+
+/// Which hosts are in scope, relative to the host of the initial page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostScope {
+    /// Only the exact host of the initial page. `www.example.com` and
+    /// `example.com` are different hosts.
+    SameHost,
+    /// Any host under the registrable domain of the initial page. E.g
+    /// `docs.example.com` and `www.example.com` for `example.com`.
+    SameDomain,
+    AnyHost,
+}
+
+impl FromStr for HostScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "same-host" | "same_host" => Ok(HostScope::SameHost),
+            "same-domain" | "same_domain" => Ok(HostScope::SameDomain),
+            "any-host" | "any_host" => Ok(HostScope::AnyHost),
+            _ => Err(format!(
+                "unknown host scope \"{s}\", expected one of same-host, same-domain, any-host"
+            )),
+        }
+    }
+}
+
+/// Limits the urls a session downloads. Pages and static resources each have
+/// their own scope in [`crate::DownloadRule`].
+///
+/// A url is in scope when it's host matches `hosts` or `allowed_hosts`, and
+/// it's path starts with `path_prefix` when one is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scope {
+    pub hosts: HostScope,
+    /// Hosts allowed in addition to `hosts`. An entry starting with a `.`
+    /// matches the domain and all it's subdomains, E.g `.example.org`
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+    /// E.g `/docs/v2/`. Compared with the path of the url, case sensitive.
+    #[serde(default)]
+    pub path_prefix: Option<String>,
+}
+
+impl Scope {
+    pub fn new(hosts: HostScope) -> Self {
+        Scope {
+            hosts,
+            allowed_hosts: Vec::new(),
+            path_prefix: None,
+        }
+    }
+
+    /// Whether `url` is in scope of a session that started at `initial_url`.
+    pub fn contains(&self, initial_url: &Url, url: &Url) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !url.path().starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        let host = match url.host_str() {
+            Some(host) => host,
+            // Urls without hosts (data:, file:) can't be told apart by host
+            None => return self.hosts == HostScope::AnyHost,
+        };
+        let in_scope = match self.hosts {
+            HostScope::AnyHost => true,
+            HostScope::SameHost => initial_url.host_str() == Some(host),
+            HostScope::SameDomain => match initial_url.host_str() {
+                Some(initial_host) => same_registrable_domain(initial_host, host),
+                None => false,
+            },
+        };
+        in_scope
+            || self
+                .allowed_hosts
+                .iter()
+                .any(|allowed| host_matches(allowed, host))
+    }
+}
+
+/// Compares the registrable domains (public suffix plus one label) of two hosts.
+/// IP addresses and hosts without a known suffix only match themselves.
+fn same_registrable_domain(a: &str, b: &str) -> bool {
+    if a.eq_ignore_ascii_case(b) {
+        return true;
+    }
+    match (psl::domain_str(a), psl::domain_str(b)) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+        _ => false,
+    }
+}
+
+pub(crate) fn host_matches(allowed: &str, host: &str) -> bool {
+    match allowed.strip_prefix('.') {
+        Some(domain) => {
+            host.eq_ignore_ascii_case(domain)
+                || (host.len() > domain.len()
+                    && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+        }
+        None => host.eq_ignore_ascii_case(allowed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(scope: &Scope, url: &str) -> bool {
+        let initial_url = Url::parse("https://www.example.co.uk/docs/").unwrap();
+        scope.contains(&initial_url, &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn allowed_hosts_match_exactly_or_by_domain() {
+        assert!(host_matches("cdn.example.org", "CDN.example.org"));
+        assert!(!host_matches("cdn.example.org", "img.cdn.example.org"));
+        assert!(host_matches(".example.org", "example.org"));
+        assert!(host_matches(".example.org", "img.cdn.Example.org"));
+        assert!(!host_matches(".example.org", "badexample.org"));
+        assert!(!host_matches(".example.org", "example.org.evil.com"));
+    }
+
+    #[test]
+    fn hosts_are_compared_by_scope() {
+        let same_host = Scope::new(HostScope::SameHost);
+        assert!(contains(&same_host, "https://www.example.co.uk/a"));
+        assert!(!contains(&same_host, "https://example.co.uk/a"));
+
+        let same_domain = Scope::new(HostScope::SameDomain);
+        assert!(contains(&same_domain, "https://static.example.co.uk/a.css"));
+        assert!(contains(&same_domain, "https://example.co.uk/"));
+        // co.uk is a public suffix, not a domain of the site
+        assert!(!contains(&same_domain, "https://other.co.uk/"));
+        assert!(!contains(&same_domain, "data:text/plain,hi"));
+
+        let any_host = Scope::new(HostScope::AnyHost);
+        assert!(contains(&any_host, "https://other.org/"));
+        assert!(contains(&any_host, "data:text/plain,hi"));
+    }
+
+    #[test]
+    fn ip_addresses_only_match_themselves() {
+        assert!(same_registrable_domain("127.0.0.1", "127.0.0.1"));
+        assert!(!same_registrable_domain("127.0.0.1", "127.0.0.2"));
+        assert!(!same_registrable_domain("localhost", "other.localhost"));
+    }
+
+    #[test]
+    fn allowed_hosts_and_path_prefix_apply_together() {
+        let scope = Scope {
+            hosts: HostScope::SameHost,
+            allowed_hosts: vec![".cdn.org".to_string()],
+            path_prefix: Some("/docs/".to_string()),
+        };
+        assert!(contains(&scope, "https://www.example.co.uk/docs/a"));
+        assert!(contains(&scope, "https://img.cdn.org/docs/a.png"));
+        assert!(!contains(&scope, "https://img.cdn.org/a.png"));
+        assert!(!contains(&scope, "https://www.example.co.uk/Docs/a"));
+        assert!(!contains(&scope, "https://other.org/docs/a"));
+    }
+}
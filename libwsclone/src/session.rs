@@ -1,4 +1,10 @@
-use std::collections::HashMap;
+use crate::budget::BudgetKind;
+use crate::download::DownloadResult;
+use crate::errors::WscError;
+use crate::event::{SessionStats, SkipReason};
+use encoding_rs::Encoding;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use url::Url;
 
 #[derive(Debug)]
@@ -9,14 +15,338 @@ pub struct LinkInfo {
     pub file_path: String,
     /// The element attribute that provided the relative link. E.g href, src, etc
     pub(crate) element_attribute: String,
+    /// Level of the page the link was found on
+    pub depth: u8,
+}
+
+/// What happened to a single url during a session.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Downloaded {
+        file_path: String,
+        bytes: u64,
+    },
+    /// The file was already on disk from a previous session
+    Cached {
+        file_path: String,
+    },
+    Skipped {
+        reason: SkipReason,
+    },
+    Failed {
+        error: WscError,
+    },
+    /// The server redirected the url, the outcome of the final target is
+    /// recorded under it's own url
+    Redirected {
+        to: String,
+    },
+}
+
+impl Outcome {
+    /// Path to the local copy, if there is one.
+    pub fn file_path(&self) -> Option<&str> {
+        match self {
+            Outcome::Downloaded { file_path, .. } | Outcome::Cached { file_path } => {
+                Some(file_path)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UrlOutcome {
+    /// Level of the page the url is, or was found on
+    pub depth: u8,
+    pub is_page: bool,
+    #[serde(flatten)]
+    pub outcome: Outcome,
 }
 
 #[derive(Debug)]
 pub struct Session {
+    /// Url of the initial page, after redirects
     pub initial_url: Url,
+    /// Urls of the pages the crawl started from, after redirects. The first
+    /// one is the initial page.
+    pub seed_urls: Vec<Url>,
     pub session_id: String,
     /// A url string to file destination map of all processed pages
     pub processed_pages: HashMap<String, LinkInfo>,
     /// A url string to file destination map of all processed static resources
     pub processed_static_files: HashMap<String, LinkInfo>,
+    /// A url string to outcome map of every url the session came across
+    pub outcomes: HashMap<String, UrlOutcome>,
+    /// Urls whose local copy is the file of another url. Links to them are
+    /// rewritten to that file, which isn't rewritten again for them.
+    pub aliases: HashMap<String, LinkInfo>,
+    /// A url string to final url map of every redirect, including the
+    /// intermediate urls of a chain
+    pub redirects: HashMap<String, String>,
+    /// A canonical url to url map of the processed pages declaring them
+    pub canonical_urls: HashMap<String, String>,
+    /// Every relative link found for a url. Links that only differ by what
+    /// normalization removes are all rewritten to the same file.
+    pub link_variants: HashMap<String, HashSet<String>>,
+    /// Pages requested so far, counted against the pages budget
+    pub pages_requested: u64,
+    /// Static resources requested so far, counted against the resources budget
+    pub resources_requested: u64,
+    /// The budget that ran out, if one did
+    pub budget_exhausted: Option<BudgetKind>,
+    /// A file path to encoding map of the downloaded pages
+    pub page_encodings: HashMap<String, &'static Encoding>,
+    /// Logout links found so far. Each is skipped once, not for every page
+    /// linking to it.
+    pub logout_links: HashSet<String>,
+}
+
+impl Session {
+    /// `seed_urls` can't be empty, the first one is the initial page.
+    pub(crate) fn new(seed_urls: Vec<Url>, session_id: &str) -> Self {
+        Session {
+            initial_url: seed_urls[0].clone(),
+            seed_urls,
+            session_id: session_id.to_string(),
+            processed_pages: Default::default(),
+            processed_static_files: Default::default(),
+            outcomes: Default::default(),
+            aliases: Default::default(),
+            canonical_urls: Default::default(),
+            redirects: Default::default(),
+            link_variants: Default::default(),
+            pages_requested: 0,
+            resources_requested: 0,
+            budget_exhausted: None,
+            page_encodings: Default::default(),
+            logout_links: Default::default(),
+        }
+    }
+
+    /// Replaces the seed `requested` by the url it redirected to.
+    pub(crate) fn redirect_seed(&mut self, requested: &Url, final_url: &Url) {
+        if &self.initial_url == requested {
+            self.initial_url = final_url.clone();
+        }
+        for seed_url in self.seed_urls.iter_mut().filter(|url| *url == requested) {
+            *seed_url = final_url.clone();
+        }
+    }
+
+    /// The processed page or static resource whose file is the local copy of
+    /// `url`, following redirects and aliases. Links to files that aren't
+    /// HTML are processed as static resources, once downloaded.
+    pub(crate) fn local_copy(&self, url: &str, is_page: bool) -> Option<&LinkInfo> {
+        let url = self.final_url(url);
+        if is_page {
+            self.processed_pages
+                .get(url)
+                .or_else(|| self.processed_static_files.get(url))
+        } else {
+            self.processed_static_files.get(url)
+        }
+        .or_else(|| self.aliases.get(url))
+    }
+
+    /// The url `url` finally redirects to, or `url` when it wasn't redirected.
+    pub(crate) fn final_url<'a>(&'a self, url: &'a str) -> &'a str {
+        self.redirects.get(url).map(String::as_str).unwrap_or(url)
+    }
+
+    pub(crate) fn record_link(&mut self, url: &Url, relative_link: &str) {
+        self.link_variants
+            .entry(url.to_string())
+            .or_default()
+            .insert(relative_link.to_string());
+    }
+
+    /// The processed page for `url`, either downloaded from it or declaring
+    /// it as canonical url. Returns the url of the page and it's link info.
+    pub(crate) fn canonical_page(&self, url: &str) -> Option<(&String, &LinkInfo)> {
+        let url = self
+            .canonical_urls
+            .get(url)
+            .map(String::as_str)
+            .unwrap_or(url);
+        self.processed_pages.get_key_value(url)
+    }
+
+    pub(crate) fn record_outcome(&mut self, url: &Url, depth: u8, is_page: bool, outcome: Outcome) {
+        self.outcomes.insert(
+            url.to_string(),
+            UrlOutcome {
+                depth,
+                is_page,
+                outcome,
+            },
+        );
+    }
+
+    /// Records the outcome of a download, and the redirects leading to it if
+    /// any. Returns the path to the local copy, if there is one.
+    pub(crate) fn record_download(
+        &mut self,
+        depth: u8,
+        is_page: bool,
+        result: DownloadResult,
+    ) -> Option<String> {
+        let final_url = result.final_url.to_string();
+        for url in &result.redirects {
+            self.record_outcome(
+                url,
+                depth,
+                is_page,
+                Outcome::Redirected {
+                    to: final_url.clone(),
+                },
+            );
+            self.redirects.insert(url.to_string(), final_url.clone());
+        }
+        match result.outcome {
+            Some(outcome) => {
+                let file_path = outcome.file_path().map(str::to_string);
+                self.record_outcome(&result.final_url, depth, is_page, outcome);
+                file_path
+            }
+            None => self
+                .local_copy(&final_url, is_page)
+                .map(|info| info.file_path.clone()),
+        }
+    }
+
+    /// The `attribute="relative link"` texts found in pages, with the
+    /// `attribute="file path"` texts replacing them. Covers every relative
+    /// link of processed urls, their aliases and the urls redirected to them.
+    pub(crate) fn link_rewrites(&self) -> (Vec<String>, Vec<String>) {
+        let redirected = self.redirects.iter().filter_map(|(url, final_url)| {
+            self.local_copy(final_url, true)
+                .or_else(|| self.local_copy(final_url, false))
+                .map(|info| (url, info))
+        });
+        let mut seen = HashSet::new();
+        let mut raw_links = Vec::new();
+        let mut file_links = Vec::new();
+        for (url, info) in self
+            .processed_static_files
+            .iter()
+            .chain(self.processed_pages.iter())
+            .chain(self.aliases.iter())
+            .chain(redirected)
+        {
+            let variants = self.link_variants.get(url).into_iter().flatten();
+            for relative_link in std::iter::once(&info.relative_link).chain(variants) {
+                let raw_link = format!(r#"{}="{relative_link}""#, info.element_attribute);
+                if seen.insert(raw_link.clone()) {
+                    raw_links.push(raw_link);
+                    file_links.push(format!(
+                        r#"{}="{}""#,
+                        info.element_attribute, info.file_path
+                    ));
+                }
+            }
+        }
+        (raw_links, file_links)
+    }
+
+    pub(crate) fn report(&self, destination_dir: &str, stats: SessionStats) -> SessionReport {
+        let mut urls: Vec<UrlReport> = self
+            .outcomes
+            .iter()
+            .map(|(url, outcome)| UrlReport {
+                url: url.clone(),
+                outcome: outcome.clone(),
+            })
+            .collect();
+        urls.sort_by(|a, b| a.url.cmp(&b.url));
+        SessionReport {
+            session_id: self.session_id.clone(),
+            initial_url: self.initial_url.to_string(),
+            seed_urls: self.seed_urls.iter().map(Url::to_string).collect(),
+            destination_dir: destination_dir.to_string(),
+            budget_exhausted: self.budget_exhausted,
+            stats,
+            urls,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UrlReport {
+    pub url: String,
+    #[serde(flatten)]
+    pub outcome: UrlOutcome,
+}
+
+/// Summary of a completed session, with the outcome of every url sorted by url.
+/// The `Display` implementation gives a human readable summary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SessionReport {
+    pub session_id: String,
+    pub initial_url: String,
+    /// Urls of the pages the crawl started from, the initial page first
+    pub seed_urls: Vec<String>,
+    pub destination_dir: String,
+    /// The budget that stopped the crawl, if one did
+    pub budget_exhausted: Option<BudgetKind>,
+    pub stats: SessionStats,
+    pub urls: Vec<UrlReport>,
+}
+
+impl SessionReport {
+    /// Number of urls with an outcome matching `filter`.
+    pub fn count(&self, filter: impl Fn(&UrlOutcome) -> bool) -> usize {
+        self.urls.iter().filter(|u| filter(&u.outcome)).count()
+    }
+}
+
+impl std::fmt::Display for SessionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = |is_page: bool, matches: fn(&Outcome) -> bool| {
+            self.count(|o| o.is_page == is_page && matches(&o.outcome))
+        };
+        let downloaded = |o: &Outcome| matches!(o, Outcome::Downloaded { .. });
+        let cached = |o: &Outcome| matches!(o, Outcome::Cached { .. });
+        let skipped = |o: &Outcome| matches!(o, Outcome::Skipped { .. });
+        let failed = |o: &Outcome| matches!(o, Outcome::Failed { .. });
+        let redirected = |o: &Outcome| matches!(o, Outcome::Redirected { .. });
+
+        writeln!(
+            f,
+            "Session {} : {} => {}",
+            self.session_id, self.initial_url, self.destination_dir
+        )?;
+        if self.seed_urls.len() > 1 {
+            writeln!(f, "  {:<10} : {}", "Seeds", self.seed_urls.len())?;
+        }
+        for (label, is_page) in [("Pages", true), ("Resources", false)] {
+            writeln!(
+                f,
+                "  {label:<10} : {} downloaded, {} cached, {} skipped, {} failed, {} redirected",
+                count(is_page, downloaded),
+                count(is_page, cached),
+                count(is_page, skipped),
+                count(is_page, failed),
+                count(is_page, redirected),
+            )?;
+        }
+        writeln!(f, "  {:<10} : {}", "Bytes", self.stats.bytes_written)?;
+        writeln!(f, "  {:<10} : {}", "Retries", self.stats.retries)?;
+        writeln!(
+            f,
+            "  {:<10} : {:.1}s",
+            "Duration",
+            self.stats.elapsed_ms as f64 / 1000.0
+        )?;
+        if let Some(budget) = self.budget_exhausted {
+            writeln!(f, "  {:<10} : {budget} budget exhausted", "Stopped")?;
+        }
+        for url in &self.urls {
+            if let Outcome::Failed { error } = &url.outcome.outcome {
+                writeln!(f, "  [FAILED] {} : {error}", url.url)?;
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,397 @@
+use crate::compression::BodyDecoder;
+use crate::download::MAX_REDIRECTS;
+use crate::errors::WscError;
+use crate::event::{EventKind, EventSink};
+use crate::fetch::Fetcher;
+use chrono::NaiveDate;
+use flate2::read::MultiGzDecoder;
+use futures::StreamExt;
+use quick_xml::events::Event as XmlEvent;
+use quick_xml::Reader;
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use std::sync::Arc;
+use url::Url;
+
+/// Largest sitemap read, uncompressed. The limit set by the sitemap protocol.
+const MAX_SITEMAP_SIZE: u64 = 50 * 1024 * 1024;
+/// Most sitemaps read in a session, nested ones included.
+const MAX_SITEMAPS: usize = 1000;
+
+/// Seeds the crawl with the pages listed in the sitemaps of the sites crawled, so
+/// pages no other page links to are downloaded too.
+///
+/// Sitemap urls join the first level of pages. They go through the page
+/// scope and filters like any linked page. With a `max_level` of 0 they are
+/// downloaded without following their links.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SitemapSeeding {
+    pub enabled: bool,
+    /// Sitemaps to read. When empty, the sitemaps listed in robots.txt are
+    /// read, or `/sitemap.xml` if it lists none. Sitemap indexes and gzipped
+    /// sitemaps are supported.
+    pub sitemap_urls: Vec<String>,
+    /// Only seed urls modified on or after this date, according to their
+    /// `lastmod`. Urls without one are always seeded.
+    pub modified_since: Option<NaiveDate>,
+}
+
+/// Reads the sitemaps of the sites of `seed_urls`. Sitemaps that can't be
+/// read are reported as error messages and skipped.
+pub(crate) async fn sitemap_urls(
+    seed_urls: &[Url],
+    seeding: &SitemapSeeding,
+    fetcher: &Arc<dyn Fetcher>,
+    events: &EventSink,
+) -> Vec<Url> {
+    let mut sitemaps: VecDeque<Url> = seeding
+        .sitemap_urls
+        .iter()
+        .filter_map(|url| Url::parse(url).ok())
+        .collect();
+    if sitemaps.is_empty() {
+        let mut sites = HashSet::new();
+        for seed_url in seed_urls {
+            if !sites.insert(seed_url.origin()) {
+                continue;
+            }
+            let listed = robots_sitemaps(seed_url, fetcher).await;
+            if listed.is_empty() {
+                sitemaps.extend(seed_url.join("/sitemap.xml").ok());
+            } else {
+                sitemaps.extend(listed);
+            }
+        }
+    }
+
+    let mut read = HashSet::new();
+    let mut urls = Vec::new();
+    while let Some(sitemap) = sitemaps.pop_front() {
+        if read.len() >= MAX_SITEMAPS {
+            tracing::warn!("More than {MAX_SITEMAPS} sitemaps, ignoring the rest");
+            break;
+        }
+        if !read.insert(sitemap.clone()) {
+            continue;
+        }
+        let entries = match fetch_text(&sitemap, fetcher).await.and_then(|xml| {
+            parse_sitemap(&xml).map_err(|e| WscError::UnknownError(format!("{e} : {sitemap}")))
+        }) {
+            Ok(entries) => entries,
+            Err(e) => {
+                events
+                    .emit(
+                        Some(&sitemap),
+                        0,
+                        EventKind::Message {
+                            content: format!("Error reading sitemap. {e}"),
+                            is_error: true,
+                        },
+                    )
+                    .await;
+                continue;
+            }
+        };
+        for entry in entries {
+            let modified = match (seeding.modified_since, &entry.lastmod) {
+                (Some(since), Some(lastmod)) => parse_lastmod(lastmod).is_none_or(|d| d >= since),
+                _ => true,
+            };
+            let url = match sitemap.join(entry.loc.trim()) {
+                Ok(url) if modified => url,
+                _ => continue,
+            };
+            match entry.kind {
+                EntryKind::Sitemap => sitemaps.push_back(url),
+                EntryKind::Page => urls.push(url),
+            }
+        }
+    }
+    tracing::debug!("{} urls found in {} sitemaps", urls.len(), read.len());
+    urls
+}
+
+/// Sitemaps listed in the robots.txt of the site of `site_url`, if it has one.
+async fn robots_sitemaps(site_url: &Url, fetcher: &Arc<dyn Fetcher>) -> Vec<Url> {
+    let robots_url = match site_url.join("/robots.txt") {
+        Ok(url) => url,
+        Err(_) => return Vec::new(),
+    };
+    let robots = match fetch_text(&robots_url, fetcher).await {
+        Ok(robots) => robots,
+        Err(e) => {
+            tracing::debug!("No robots.txt. {e}");
+            return Vec::new();
+        }
+    };
+    robots
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if !key.trim().eq_ignore_ascii_case("sitemap") {
+                return None;
+            }
+            robots_url.join(value.trim()).ok()
+        })
+        .collect()
+}
+
+/// Fetches `url` following redirects, and decompresses gzipped content.
+async fn fetch_text(url: &Url, fetcher: &Arc<dyn Fetcher>) -> Result<String, WscError> {
+    let mut current = url.clone();
+    let mut redirects = 0;
+    let response = loop {
+        let response = fetcher.fetch(&current).await?;
+        let location = response
+            .headers
+            .get(header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| current.join(l).ok());
+        match location {
+            Some(next) if response.status.is_redirection() => {
+                redirects += 1;
+                if redirects > MAX_REDIRECTS {
+                    return Err(WscError::TooManyRedirects(url.to_string()));
+                }
+                current = next;
+            }
+            _ => break response,
+        }
+    };
+    if !response.status.is_success() {
+        return Err(WscError::ErrorStatusCode {
+            status_code: response.status.to_string(),
+            url: current.to_string(),
+        });
+    }
+
+    let decoding_error = |message: String| WscError::ContentDecodingError {
+        url: current.to_string(),
+        message,
+    };
+    let mut decoder = BodyDecoder::new(&response.headers).map_err(decoding_error)?;
+    let mut body = Vec::new();
+    let mut chunks = response.body;
+    while let Some(chunk) = chunks.next().await {
+        body.extend(decoder.decode(&chunk?).map_err(decoding_error)?);
+        if body.len() as u64 > MAX_SITEMAP_SIZE {
+            return Err(WscError::UnknownError(format!(
+                "larger than {MAX_SITEMAP_SIZE} bytes : {current}"
+            )));
+        }
+    }
+    body.extend(decoder.finish().map_err(decoding_error)?);
+    // Gzip magic bytes, sitemap.xml.gz files are served as is
+    if body.starts_with(&[0x1f, 0x8b]) {
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(body.as_slice())
+            .take(MAX_SITEMAP_SIZE + 1)
+            .read_to_end(&mut decoded)
+            .map_err(|e| WscError::UnknownError(format!("{e} : {current}")))?;
+        if decoded.len() as u64 > MAX_SITEMAP_SIZE {
+            return Err(WscError::UnknownError(format!(
+                "larger than {MAX_SITEMAP_SIZE} bytes : {current}"
+            )));
+        }
+        body = decoded;
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum EntryKind {
+    /// `<url>` of a `<urlset>`
+    Page,
+    /// `<sitemap>` of a `<sitemapindex>`
+    Sitemap,
+}
+
+#[derive(Debug)]
+struct SitemapEntry {
+    kind: EntryKind,
+    loc: String,
+    lastmod: Option<String>,
+}
+
+/// Entries of a sitemap or a sitemap index. Elements are matched by local
+/// name, whatever the namespace.
+fn parse_sitemap(xml: &str) -> Result<Vec<SitemapEntry>, quick_xml::Error> {
+    let mut reader = Reader::from_reader(xml.as_bytes());
+    reader.trim_text(true);
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    let mut entry: Option<SitemapEntry> = None;
+    // Name of the open element of the current entry
+    let mut field: Option<Vec<u8>> = None;
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            XmlEvent::Start(e) => {
+                let name = e.local_name().as_ref().to_vec();
+                match name.as_slice() {
+                    b"url" | b"sitemap" => {
+                        entry = Some(SitemapEntry {
+                            kind: if name == b"url" {
+                                EntryKind::Page
+                            } else {
+                                EntryKind::Sitemap
+                            },
+                            loc: String::new(),
+                            lastmod: None,
+                        });
+                    }
+                    _ => field = Some(name),
+                }
+            }
+            XmlEvent::Text(text) => {
+                if let (Some(entry), Some(field)) = (entry.as_mut(), field.as_deref()) {
+                    let text = text.unescape()?.into_owned();
+                    match field {
+                        b"loc" => entry.loc.push_str(&text),
+                        b"lastmod" => entry.lastmod = Some(text),
+                        _ => {}
+                    }
+                }
+            }
+            XmlEvent::CData(text) => {
+                if let (Some(entry), Some(b"loc")) = (entry.as_mut(), field.as_deref()) {
+                    entry.loc.push_str(&String::from_utf8_lossy(&text));
+                }
+            }
+            XmlEvent::End(e) => match e.local_name().as_ref() {
+                b"url" | b"sitemap" => {
+                    if let Some(entry) = entry.take().filter(|e| !e.loc.trim().is_empty()) {
+                        entries.push(entry);
+                    }
+                }
+                _ => field = None,
+            },
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+/// Date of a W3C datetime `lastmod`, E.g `2023-01-31` or `2023-01-31T10:00:00+00:00`
+fn parse_lastmod(lastmod: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(lastmod.trim().get(..10)?, "%Y-%m-%d").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{Event, EventTarget};
+    use crate::observer::SlowConsumerPolicy;
+    use crate::ReplayFetcher;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    #[test]
+    fn sitemaps_and_indexes_are_parsed() {
+        let entries = parse_sitemap(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <s:urlset xmlns:s="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <s:url><s:loc>https://a.org/?p=1&amp;q=2</s:loc><s:lastmod>2023-01-31</s:lastmod></s:url>
+                <s:url><s:loc><![CDATA[https://a.org/cdata]]></s:loc></s:url>
+                <s:url><s:lastmod>2023-01-31</s:lastmod></s:url>
+            </s:urlset>"#,
+        )
+        .unwrap();
+        let locs: Vec<&str> = entries.iter().map(|e| e.loc.as_str()).collect();
+        assert_eq!(locs, ["https://a.org/?p=1&q=2", "https://a.org/cdata"]);
+        assert!(entries.iter().all(|e| e.kind == EntryKind::Page));
+        assert_eq!(entries[0].lastmod.as_deref(), Some("2023-01-31"));
+
+        let entries =
+            parse_sitemap("<sitemapindex><sitemap><loc>/pages.xml</loc></sitemap></sitemapindex>")
+                .unwrap();
+        assert_eq!(entries[0].kind, EntryKind::Sitemap);
+        assert_eq!(entries[0].loc, "/pages.xml");
+
+        assert!(parse_sitemap("<urlset><url><loc>a</url></urlset>").is_err());
+    }
+
+    #[test]
+    fn lastmod_dates_are_read() {
+        let date = NaiveDate::from_ymd_opt(2023, 1, 31);
+        assert_eq!(parse_lastmod("2023-01-31"), date);
+        assert_eq!(parse_lastmod(" 2023-01-31T10:00:00+02:00"), date);
+        assert_eq!(parse_lastmod("31/01/2023"), None);
+    }
+
+    /// robots.txt lists an index, which lists a gzipped sitemap and a plain one.
+    #[tokio::test]
+    async fn sitemaps_are_read_from_robots_and_indexes() {
+        let dir = std::env::temp_dir().join(format!("wsclone-sitemap-{}", std::process::id()));
+        let site = dir.join("example.com");
+        std::fs::create_dir_all(&site).unwrap();
+        std::fs::write(
+            site.join("robots.txt"),
+            "User-agent: *\nDisallow: /private\nSitemap: /index.xml\n",
+        )
+        .unwrap();
+        std::fs::write(
+            site.join("index.xml"),
+            "<sitemapindex>\
+                <sitemap><loc>https://example.com/news.xml.gz</loc></sitemap>\
+                <sitemap><loc>https://example.com/pages.xml</loc></sitemap>\
+                <sitemap><loc>https://example.com/missing.xml</loc></sitemap>\
+            </sitemapindex>",
+        )
+        .unwrap();
+        let mut gzipped = GzEncoder::new(Vec::new(), Compression::default());
+        gzipped
+            .write_all(
+                b"<urlset><url><loc>https://example.com/news/1</loc>\
+                  <lastmod>2022-12-01</lastmod></url></urlset>",
+            )
+            .unwrap();
+        std::fs::write(site.join("news.xml.gz"), gzipped.finish().unwrap()).unwrap();
+        std::fs::write(
+            site.join("pages.xml"),
+            "<urlset><url><loc>/about</loc><lastmod>2023-02-01</lastmod></url></urlset>",
+        )
+        .unwrap();
+
+        let fetcher: Arc<dyn Fetcher> = Arc::new(ReplayFetcher::from_dir(&dir).unwrap());
+        let errors = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let observed = errors.clone();
+        let events = EventSink::new(
+            "sitemap",
+            EventTarget::Observer(Arc::new(move |event: &Event| {
+                if let EventKind::Message { is_error: true, .. } = event.kind {
+                    observed.lock().unwrap().push(event.url.clone().unwrap());
+                }
+            })),
+            SlowConsumerPolicy::default(),
+        );
+        let seeds = [Url::parse("https://example.com/docs/").unwrap()];
+
+        let mut seeding = SitemapSeeding {
+            enabled: true,
+            ..SitemapSeeding::default()
+        };
+        let urls = sitemap_urls(&seeds, &seeding, &fetcher, &events).await;
+        let urls: Vec<&str> = urls.iter().map(Url::as_str).collect();
+        assert_eq!(
+            urls,
+            ["https://example.com/news/1", "https://example.com/about"]
+        );
+        assert_eq!(*errors.lock().unwrap(), ["https://example.com/missing.xml"]);
+
+        seeding.modified_since = NaiveDate::from_ymd_opt(2023, 1, 1);
+        seeding.sitemap_urls = vec!["https://example.com/pages.xml".to_string()];
+        let urls = sitemap_urls(&seeds, &seeding, &fetcher, &events).await;
+        assert_eq!(urls, [Url::parse("https://example.com/about").unwrap()]);
+        seeding.modified_since = NaiveDate::from_ymd_opt(2024, 1, 1);
+        assert!(sitemap_urls(&seeds, &seeding, &fetcher, &events)
+            .await
+            .is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,120 @@
+use crate::errors::{RuleError, WscError};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use serde::{Deserialize, Serialize};
+
+const PEM_CERTIFICATE_START: &str = "-----BEGIN CERTIFICATE-----";
+
+/// Certificates of the default fetcher's TLS connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsOptions {
+    /// PEM files of root certificates trusted on top of the system ones,
+    /// E.g the CA of internal sites. A file can hold several certificates.
+    pub ca_certificates: Vec<String>,
+    /// PEM file of the client certificate for mutual TLS, with `client_key`
+    pub client_certificate: Option<String>,
+    /// PEM file of the PKCS#8 private key of `client_certificate`
+    pub client_key: Option<String>,
+    /// PKCS#12 file of the client certificate and it's key, instead of
+    /// `client_certificate` and `client_key`
+    pub client_pkcs12: Option<String>,
+    /// Environment variable holding the password of `client_pkcs12`
+    pub client_pkcs12_password_env: Option<String>,
+    /// Hosts whose certificate isn't verified, E.g a staging site with a self
+    /// signed one. A leading `.` also matches the subdomains, and `*` matches
+    /// every host. Anyone between wsclone and these hosts can read and change
+    /// the traffic, a [`crate::EventKind::TlsVerificationDisabled`] event is sent
+    /// when the session starts.
+    pub insecure_hosts: Vec<String>,
+}
+
+impl TlsOptions {
+    pub(crate) fn validate(&self) -> Result<(), RuleError> {
+        let invalid = |message: &str| RuleError::InvalidTls(message.to_string());
+        match (&self.client_certificate, &self.client_key) {
+            (Some(_), None) => return Err(invalid("client certificate needs a client key")),
+            (None, Some(_)) => return Err(invalid("client key needs a client certificate")),
+            (Some(_), Some(_)) if self.client_pkcs12.is_some() => {
+                return Err(invalid(
+                    "client certificate and PKCS#12 file can't both be set",
+                ))
+            }
+            _ => {}
+        }
+        if self.client_pkcs12_password_env.is_some() && self.client_pkcs12.is_none() {
+            return Err(invalid("PKCS#12 password needs a PKCS#12 file"));
+        }
+        if self
+            .insecure_hosts
+            .iter()
+            .any(|host| host.trim().is_empty())
+        {
+            return Err(invalid("insecure hosts can't be empty"));
+        }
+        Ok(())
+    }
+
+    /// Adds the root certificates and the client identity to `builder`.
+    pub(crate) fn configure(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, WscError> {
+        for path in &self.ca_certificates {
+            for certificate in read_certificates(path)? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(identity) = self.identity()? {
+            builder = builder.identity(identity);
+        }
+        Ok(builder)
+    }
+
+    fn identity(&self) -> Result<Option<Identity>, WscError> {
+        if let (Some(certificate), Some(key)) = (&self.client_certificate, &self.client_key) {
+            let identity = Identity::from_pkcs8_pem(&read(certificate)?, &read(key)?)
+                .map_err(|e| file_error(certificate, &e.to_string()))?;
+            return Ok(Some(identity));
+        }
+        let path = match &self.client_pkcs12 {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let password = match &self.client_pkcs12_password_env {
+            Some(var) => std::env::var(var).map_err(|_| {
+                RuleError::InvalidTls(format!("environment variable {var} isn't set"))
+            })?,
+            None => String::new(),
+        };
+        Identity::from_pkcs12_der(&read(path)?, &password)
+            .map(Some)
+            .map_err(|e| file_error(path, &e.to_string()))
+    }
+}
+
+/// The certificates of a PEM file, which can be a bundle of several.
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, WscError> {
+    let pem = String::from_utf8(read(path)?).map_err(|e| file_error(path, &e.to_string()))?;
+    let certificates = pem
+        .match_indices(PEM_CERTIFICATE_START)
+        .map(|(start, _)| {
+            let end = pem[start + 1..]
+                .find(PEM_CERTIFICATE_START)
+                .map_or(pem.len(), |end| start + 1 + end);
+            Certificate::from_pem(&pem.as_bytes()[start..end])
+                .map_err(|e| file_error(path, &e.to_string()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if certificates.is_empty() {
+        return Err(file_error(path, "no PEM certificate found"));
+    }
+    Ok(certificates)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, WscError> {
+    std::fs::read(path).map_err(|e| file_error(path, &e.to_string()))
+}
+
+fn file_error(path: &str, message: &str) -> WscError {
+    WscError::FileOperationError {
+        file_name: path.to_string(),
+        message: message.to_string(),
+    }
+}